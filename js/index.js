@@ -34,6 +34,21 @@ function convertIndexToLineColumn(text, index) {
   return CodeMirror.Pos(line, column);
 }
 
+// Prefer the line/column `location` computed by the library: `start` and `end` are UTF-8 byte
+// offsets, which differ from JavaScript string indices on non-ASCII text.
+function convertSpan(text, { start, end, location }) {
+  if (location) {
+    return {
+      from: CodeMirror.Pos(location.start.line, location.start.utf16_column),
+      to: CodeMirror.Pos(location.end.line, location.end.utf16_column),
+    };
+  }
+  return {
+    from: convertIndexToLineColumn(text, start),
+    to: convertIndexToLineColumn(text, end),
+  };
+}
+
 function parseJSON(str) {
   try {
     return JSON.parse(str);
@@ -78,6 +93,8 @@ function Substructural() {
   let log_error = console.log;
   let editor = null;
   let examples_code = [];
+  // suggested fixes of the last type error, applied with Ctrl-.
+  let quick_fixes = [];
 
   function init_me() {
     lib.init().then(() => {
@@ -99,6 +116,7 @@ function Substructural() {
       let do_nothing = console.log;
 
       let do_check_typing = () => {
+        quick_fixes = [];
         lib.typing(
           text,
           (res) => {
@@ -107,12 +125,18 @@ function Substructural() {
           (err_) => {
             let err = parseJSON(err_);
             if (err.TypeError) {
-              let { start, end, message } = err.TypeError;
+              let { code, message, suggestions } = err.TypeError;
+              quick_fixes = (suggestions || []).map((suggestion) => ({
+                text,
+                suggestion,
+              }));
+              let help = quick_fixes
+                .map(({ suggestion }) => `\nhelp: ${suggestion.message} (Ctrl-.)`)
+                .join("");
               hints.push({
-                from: convertIndexToLineColumn(text, start),
-                to: convertIndexToLineColumn(text, end),
-                message,
-                severity: "warning",
+                ...convertSpan(text, err.TypeError),
+                message: `[${code}] ${message}${help}`,
+                severity: "error",
               });
               this.typing_output = "Not well-typed: " + message;
             } else {
@@ -122,16 +146,25 @@ function Substructural() {
         );
       };
 
-      lib.term_lint(text, do_check_typing, (err_) => {
+      lib.term_lint(text, this.lint_config, do_check_typing, (err_) => {
         let err = parseJSON(err_);
         if (err.ParseError) {
-          let { start, end, message } = err.ParseError;
+          let { code, message } = err.ParseError;
           hints.push({
-            from: convertIndexToLineColumn(text, start),
-            to: convertIndexToLineColumn(text, end),
-            message,
+            ...convertSpan(text, err.ParseError),
+            message: `[${code}] ${message}`,
             severity: "error",
           });
+        } else if (err.LintError) {
+          let { code, message, severity, suggestions } = err.LintError;
+          let help = (suggestions || [])
+            .map((suggestion) => `\nhelp: ${suggestion.message}`)
+            .join("");
+          hints.push({
+            ...convertSpan(text, err.LintError),
+            message: `[${code}] ${message}${help}`,
+            severity,
+          });
         } else {
           console.log(err);
         }
@@ -147,6 +180,9 @@ function Substructural() {
       gutters: ["CodeMirror-lint-markers"],
       lint: true,
       theme: "darcula",
+      extraKeys: {
+        "Ctrl-.": applyQuickFix,
+      },
     });
 
     editor.on("change", (_self, _obj) => {
@@ -162,6 +198,21 @@ function Substructural() {
     this.onInputChanged();
   }
 
+  function applyQuickFix(cm) {
+    let fix = quick_fixes[0];
+    if (!fix || fix.text !== cm.getValue()) {
+      return;
+    }
+    // apply from the back so that earlier offsets stay valid
+    let edits = [...fix.suggestion.edits].sort((a, b) => b.start - a.start);
+    cm.operation(() => {
+      for (const edit of edits) {
+        let { from, to } = convertSpan(fix.text, edit);
+        cm.replaceRange(edit.replacement, from, to);
+      }
+    });
+  }
+
   function init_examples() {
     fetch('examples/manifest.json')
     .then(response => response.text())
@@ -197,29 +248,50 @@ function Substructural() {
     return result;
   }
 
-  function oneStep(arg) {
-    lib.one_step_eval(
-      arg,
-      (res) => {
-        eval_term1 = parseJSON(res);
+  // the evaluation being stepped through, as a `lib.EvalSession`
+  let session = null;
 
-        let term1 = eval_term1.term;
-        let context1 = eval_term1.store.bindings;
-        this.eval0 = this.eval1;
-        this.ctx0 = this.ctx1;
-        this.eval1 = prettify(term1, 38);
-        let ctx1 = [];
-        for (const [key, value] of Object.entries(context1).sort((a, b) =>
-          b[0].localeCompare(a[0])
-        )) {
-          ctx1.push([key, prettify(value, 60)]);
-        }
-        this.ctx1 = ctx1;
-      },
-      console.error
+  // The watched bindings of a snapshot, with a star on the ones the last step changed.
+  function watchLines(snapshot) {
+    return snapshot.watches.map(
+      ({ name, value, changed }) => `${name} = ${value ?? "(none)"}${changed ? "  *" : ""}`
     );
   }
 
+  // Show the state that the session is at, next to the one shown before.
+  function showSnapshot(res) {
+    let snapshot = parseJSON(res);
+    eval_term1 = snapshot.state;
+    this.eval_step = snapshot.step;
+    // the rule that fired, and what it did to the store
+    let { reduction } = snapshot;
+    if (reduction) {
+      let { contraction, derivation, diff } = reduction;
+      // the congruence rules down to the rule that fired, e.g. "E-Let1 / E-Alloc"
+      let rules = [];
+      for (let d = derivation; d; d = d.premises[0]) {
+        rules.push(d.rule);
+      }
+      let changes = [
+        ...diff.added.map((x) => `+${x}`),
+        ...diff.removed.map((x) => `-${x}`),
+        ...diff.consumed.map((x) => `consumed ${x}`),
+      ];
+      this.eval_reduction =
+        `step ${snapshot.step}: ${rules.join(" / ")}\n` +
+        `${contraction.redex} \u27f6 ${contraction.contractum}  ${changes.join(", ")}`;
+    } else {
+      this.eval_reduction = "";
+    }
+    this.eval_watches = watchLines(snapshot);
+    this.eval0 = this.eval1;
+    this.ctx0 = this.ctx1;
+    this.eval1 = prettify(eval_term1.term, 38);
+    this.ctx1 = Object.entries(eval_term1.store.bindings)
+      .sort((a, b) => b[0].localeCompare(a[0]))
+      .map(([key, value]) => [key, prettify(value, 60)]);
+  }
+
   function onInputChanged() {
     let { input_code } = this;
     lib.prettify(
@@ -229,7 +301,22 @@ function Substructural() {
         this.eval1 = result;
         this.ctx1 = [];
 
-        oneStep.call(this, result);
+        if (session) {
+          session.free();
+        }
+        try {
+          session = new lib.EvalSession(result, this.eval_strategy);
+        } catch (err) {
+          session = null;
+          console.error(err);
+          return;
+        }
+        // spans are in the old program; the watched bindings are looked up again
+        this.eval_breakpoints = [];
+        for (let name of this.eval_watched) {
+          session.watch(name);
+        }
+        session.step(showSnapshot.bind(this), console.error);
       },
       log_error,
       38 // TODO: refactor the calls to `prettify()`
@@ -237,7 +324,105 @@ function Substructural() {
   }
 
   function onOneStepEval() {
-    oneStep.call(this, JSON.stringify(eval_term1));
+    session && session.step(showSnapshot.bind(this), console.error);
+  }
+
+  function onStepBack() {
+    session && session.step_back(showSnapshot.bind(this), console.error);
+  }
+
+  // Go to the step that created a binding of the store, e.g. `%x3`.
+  function onGotoBinding(name) {
+    session && session.goto_binding(name, showSnapshot.bind(this), console.error);
+  }
+
+  // Step until a breakpoint is hit, or the term is a value.
+  function onRun() {
+    session &&
+      session.run(
+        this.eval_limits,
+        (res) => {
+          showSnapshot.call(this, res);
+          let { stop } = parseJSON(res);
+          if (stop !== "Value") {
+            let [kind, arg] = Object.entries(stop)[0];
+            this.eval_reduction =
+              (kind === "Breakpoint"
+                ? `breakpoint ${this.eval_breakpoints[arg]}`
+                : `out of fuel (${arg})`) +
+              "\n" +
+              this.eval_reduction;
+          }
+        },
+        console.error
+      );
+  }
+
+  // Break at the steps inside the first occurrence of `eval_break` in the program, or at
+  // the steps that touch the store binding it names, e.g. "%x3".
+  function onAddBreakpoint() {
+    let text = this.eval_break.trim();
+    if (!session || !text) {
+      return;
+    }
+    let breakpoint;
+    if (text.startsWith("%")) {
+      breakpoint = { Binding: text };
+    } else {
+      // the session evaluates the prettified program; spans are in bytes
+      let source = this.output_syntax;
+      let i = source.indexOf(text);
+      if (i < 0) {
+        console.error(`"${text}" is not in the program`);
+        return;
+      }
+      let encoder = new TextEncoder();
+      let start = encoder.encode(source.slice(0, i)).length;
+      let end = start + encoder.encode(text).length;
+      breakpoint = { Span: { start, end } };
+    }
+    session.add_breakpoint(JSON.stringify(breakpoint));
+    this.eval_breakpoints.push(text);
+    this.eval_break = "";
+  }
+
+  function onRemoveBreakpoint(index) {
+    session && session.remove_breakpoint(index);
+    this.eval_breakpoints.splice(index, 1);
+  }
+
+  // Watch a store binding, or stop watching it if it is watched already.
+  function onWatch(name) {
+    if (!session || !name) {
+      return;
+    }
+    if (this.eval_watched.includes(name)) {
+      session.unwatch(name);
+      this.eval_watched = this.eval_watched.filter((x) => x !== name);
+    } else {
+      session.watch(name);
+      this.eval_watched.push(name);
+    }
+    // show the watches in the current state
+    session.goto(
+      this.eval_step,
+      (res) => {
+        this.eval_watches = watchLines(parseJSON(res));
+      },
+      console.error
+    );
+  }
+
+  // Free the store bindings that the term no longer refers to.
+  function onCollect() {
+    session &&
+      session.collect_garbage((res) => {
+        showSnapshot.call(this, res);
+        let { freed, live, unreachable_linear } = eval_term1.store.gc;
+        console.log(
+          `gc: ${freed} freed in total, ${live} live, unreachable linear: [${unreachable_linear}]`
+        );
+      }, console.error);
   }
 
   function onReset() {
@@ -249,13 +434,18 @@ function Substructural() {
     console.log(json);
     let result = json
       .map((span) => {
-        let { ty, s } = span;
+        let { ty, live, s } = span;
         s = s.replace(/</g, "&lt;").replace(/>/g, "&gt;");
 
         if (s === "\n") {
           s = "<br/>";
         } else if (ty) {
-          s = `<span class="typing-tip" data-text="${ty}">${s}</span>`;
+          let tip = ty;
+          // show the linear variables used up by the term
+          if (live && (live.before.length || live.after.length)) {
+            tip += ` | linear: {${live.before.join(", ")}} \u2192 {${live.after.join(", ")}}`;
+          }
+          s = `<span class="typing-tip" data-text="${tip}">${s}</span>`;
         }
         return s;
       })
@@ -264,9 +454,64 @@ function Substructural() {
     this.typing_output = result;
   }
 
+  // The derivation of the program in the editor, as JSON or as `mathpartir` source.
+  function onShowDerivation(format) {
+    lib.typing_derivation(
+      editor.getValue(),
+      format,
+      (res) => {
+        this.derivation_output =
+          format === "json" ? JSON.stringify(JSON.parse(res), null, 2) : res;
+      },
+      (err_) => {
+        let err = parseJSON(err_);
+        let { message } = Object.values(err)[0];
+        this.derivation_output = "No derivation: " + message;
+      }
+    );
+  }
+
   function onFormatCode() {}
 
-  function onEvalution() {}
+  // Run the program in the editor to the end, or until it runs out of fuel.
+  function onEvalution() {
+    lib.eval_run(
+      this.input_code,
+      this.eval_limits,
+      this.eval_monitor,
+      this.eval_strategy,
+      (res) => {
+        let outcome = parseJSON(res);
+        let [kind, { result, error, errors, limit, steps }] = Object.entries(outcome)[0];
+        this.eval0 = this.eval1;
+        this.ctx0 = this.ctx1;
+        if (kind === "Stuck") {
+          let { code, message } = Object.values(error)[0];
+          this.eval1 = `stuck after ${steps} steps: [${code}] ${message}`;
+          this.ctx1 = [];
+          return;
+        }
+        eval_term1 = result;
+        this.eval1 = prettify(result.term, 38);
+        if (kind === "OutOfFuel") {
+          this.eval1 = `out of fuel (${limit}) after ${steps} steps:\n` + this.eval1;
+        } else if (kind === "Leaked") {
+          let leaks = errors
+            .map((error) => {
+              let { code, message, location } = Object.values(error)[0];
+              let at = location ? ` at Ln ${location.start.line + 1}` : "";
+              return `[${code}] ${message}${at}`;
+            })
+            .join("\n");
+          this.eval1 = leaks + "\n" + this.eval1;
+        }
+        this.ctx1 = Object.entries(result.store.bindings)
+          .sort((a, b) => b[0].localeCompare(a[0]))
+          .map(([key, value]) => [key, prettify(value, 60)]);
+      },
+      console.error
+    );
+  }
 
   function onLoadExample(i) {
     let code = examples_code[i];
@@ -279,20 +524,46 @@ function Substructural() {
     input_code: "",
     output_syntax: "",
     typing_output: "",
+    // house rules for the linter, as the JSON of a `lint::LintConfig`, e.g.
+    // '{"rules": {"deep-let-nesting": "error"}, "max_let_depth": 2}'
+    lint_config: null,
+    // the JSON of an `eval::Limits`, e.g. '{"max_steps": 1000}'; null for the defaults
+    eval_limits: null,
+    // report linear values used twice or never when running to the end
+    eval_monitor: true,
+    // how arguments are passed: "call-by-value", "call-by-name" or "call-by-need"
+    eval_strategy: "call-by-value",
 
     onInputChanged: debounce(onInputChanged, 500),
 
     onFormatCode,
+    onShowDerivation,
     onEvalution,
     onOneStepEval,
+    onStepBack,
+    onGotoBinding,
+    onRun,
+    onAddBreakpoint,
+    onRemoveBreakpoint,
+    onWatch,
+    onCollect,
     onReset,
     onLoadExample,
 
     eval0: "",
     eval1: "",
+    eval_step: 0,
+    eval_reduction: "",
+    // the breakpoints of the session, as typed: source text or a store binding
+    eval_break: "",
+    eval_breakpoints: [],
+    // the names of the watched store bindings, and their values in the current state
+    eval_watched: [],
+    eval_watches: [],
     ctx0: [],
     ctx1: [],
 
     examples: [],
+    derivation_output: "",
   };
 }
@@ -1,3 +1,93 @@
-fn main() {
-    println!("Hello, world!");
+use std::io::IsTerminal;
+use std::process::ExitCode;
+use substructural::error::Severity;
+use substructural::eval::MachineOutcome;
+use substructural::render::Renderer;
+use substructural::source_map::SourceMap;
+use substructural::{eval, lint, syntax, typing, vm};
+
+/// Check the programs given on the command line and print their diagnostics.
+///
+/// Usage: `main [--color | --plain] [--run] [--max-steps N] [--max-millis N] FILE...`. Colors
+/// are used by default when stderr is a terminal. With `--run`, the programs without errors are
+/// compiled to bytecode and run, and their answers are printed. Runs are not limited, unless
+/// `--max-steps` or `--max-millis` says so.
+fn main() -> ExitCode {
+    let mut color = std::io::stderr().is_terminal();
+    let mut run = false;
+    let mut limits = eval::Limits {
+        max_steps: None,
+        max_store: None,
+        max_millis: None,
+        ..Default::default()
+    };
+    let mut paths = vec![];
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--color" => color = true,
+            "--plain" => color = false,
+            "--run" => run = true,
+            "--max-steps" | "--max-millis" => {
+                let Some(n) = args.next().and_then(|n| n.parse().ok()) else {
+                    eprintln!("{} expects a number", arg);
+                    return ExitCode::FAILURE;
+                };
+                if arg == "--max-steps" {
+                    limits.max_steps = Some(n as usize);
+                } else {
+                    limits.max_millis = Some(n);
+                }
+            }
+            _ => paths.push(arg),
+        }
+    }
+    let renderer = Renderer::new(color);
+    let mut failed = false;
+    for path in paths {
+        let source = match std::fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(error) => {
+                eprintln!("{}: {}", path, error);
+                failed = true;
+                continue;
+            }
+        };
+        let map = SourceMap::new(&source);
+        let (term, mut errors) = syntax::parse_program_recovering(&source);
+        if errors.is_empty() {
+            if let Err(error) = typing::type_check(&term) {
+                errors.push(error);
+            }
+        }
+        errors.extend(lint::lint(&term));
+        for error in &errors {
+            eprintln!("{}", renderer.render(error, &map, &path));
+        }
+        if errors.iter().any(|e| e.severity() == Severity::Error) {
+            failed = true;
+        } else if run {
+            let start = std::time::Instant::now();
+            let outcome = vm::compile(&term).run(&limits, true);
+            let millis = start.elapsed().as_millis();
+            match outcome {
+                MachineOutcome::Value { answer, steps } => {
+                    println!("{}: {} ({} steps, {} ms)", path, answer, steps, millis)
+                }
+                MachineOutcome::Stuck { error, .. } => {
+                    eprintln!("{}", renderer.render(&error, &map, &path));
+                    failed = true;
+                }
+                MachineOutcome::OutOfFuel { limit, steps } => {
+                    eprintln!("{}: out of fuel ({:?}) after {} steps", path, limit, steps);
+                    failed = true;
+                }
+            }
+        }
+    }
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
 }
@@ -0,0 +1,170 @@
+use crate::syntax::{Context, NodeId};
+use serde::{Deserialize, Serialize};
+
+/// A variable and its type in a typing context.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Assumption {
+    pub name: String,
+    pub ty: String,
+}
+
+/// A typing derivation, as built by `typing::type_derivation`. Each node concludes with the
+/// judgement `input |- term : ty ; output`: checking `term` in the context `input` leaves the
+/// context `output`, where the linear variables used by `term` are gone.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Derivation {
+    /// The name of the rule, e.g. `T-Abs`.
+    pub rule: String,
+    pub id: NodeId,
+    pub span: Context,
+    pub term: String,
+    pub input: Vec<Assumption>,
+    pub output: Vec<Assumption>,
+    pub ty: String,
+    pub premises: Vec<Derivation>,
+}
+
+impl Derivation {
+    /// The derivation as nested `\inferrule*` of the LaTeX package `mathpartir`, to be placed
+    /// in a `mathpar` environment or in math mode.
+    pub fn to_latex(&self) -> String {
+        let mut out = String::new();
+        self.write_latex(&mut out, 0);
+        out
+    }
+
+    fn write_latex(&self, out: &mut String, depth: usize) {
+        let conclusion = format!(
+            "{} \\vdash {} : {} ; {}",
+            latex_context(&self.input),
+            latex_code(&self.term),
+            latex_code(&self.ty),
+            latex_context(&self.output)
+        );
+        write_inferrule(
+            out,
+            depth,
+            &self.rule,
+            &self.premises,
+            &conclusion,
+            Self::write_latex,
+        );
+    }
+}
+
+/// A derivation of an evaluation step, as recorded by `eval::one_step_eval_traced`. Each node
+/// concludes with `term --> result`, leaving out the store. The topmost node is the rule that
+/// contracted the redex; the ones below it are the congruence rules, e.g. `E-App1`, that find
+/// the redex in the term.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StepDerivation {
+    pub rule: String,
+    pub id: NodeId,
+    pub span: Context,
+    pub term: String,
+    pub result: String,
+    /// The step of the subterm that holds the redex; none for the redex itself.
+    pub premises: Vec<StepDerivation>,
+}
+
+impl StepDerivation {
+    /// The derivation in `mathpartir`, like `Derivation::to_latex`.
+    pub fn to_latex(&self) -> String {
+        let mut out = String::new();
+        self.write_latex(&mut out, 0);
+        out
+    }
+
+    fn write_latex(&self, out: &mut String, depth: usize) {
+        let conclusion = format!(
+            "{} \\longrightarrow {}",
+            latex_code(&self.term),
+            latex_code(&self.result)
+        );
+        write_inferrule(
+            out,
+            depth,
+            &self.rule,
+            &self.premises,
+            &conclusion,
+            Self::write_latex,
+        );
+    }
+}
+
+fn write_inferrule<T>(
+    out: &mut String,
+    depth: usize,
+    rule: &str,
+    premises: &[T],
+    conclusion: &str,
+    write_premise: fn(&T, &mut String, usize),
+) {
+    let indent = "  ".repeat(depth);
+    out.push_str(&format!("{}\\inferrule*[right={}]\n", indent, rule));
+    out.push_str(&format!("{}  {{", indent));
+    for (i, premise) in premises.iter().enumerate() {
+        out.push_str(if i == 0 { "\n" } else { " \\\\\n" });
+        write_premise(premise, out, depth + 2);
+    }
+    if !premises.is_empty() {
+        out.push_str(&format!("\n{}  ", indent));
+    }
+    out.push_str("}\n");
+    out.push_str(&format!("{}  {{{}}}", indent, conclusion));
+}
+
+fn latex_context(assumptions: &[Assumption]) -> String {
+    if assumptions.is_empty() {
+        return "\\cdot".to_owned();
+    }
+    let assumptions: Vec<String> = assumptions
+        .iter()
+        .map(|a| format!("{}{{:}}{}", latex_code(&a.name), latex_code(&a.ty)))
+        .collect();
+    assumptions.join(", ")
+}
+
+/// Typeset source code in math mode. Line breaks and indentation become single spaces.
+fn latex_code(code: &str) -> String {
+    let mut out = String::new();
+    for (i, word) in code.split_whitespace().enumerate() {
+        if i > 0 {
+            out.push_str("\\ ");
+        }
+        for c in word.chars() {
+            match c {
+                '$' | '_' | '{' | '}' | '&' | '#' | '%' => {
+                    out.push('\\');
+                    out.push(c);
+                }
+                '\\' => out.push_str("\\backslash{}"),
+                '^' => out.push_str("\\hat{}"),
+                '~' => out.push_str("\\sim{}"),
+                _ => out.push(c),
+            }
+        }
+    }
+    format!("\\mathtt{{{}}}", out)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::syntax::parse_program;
+    use crate::typing::type_derivation;
+
+    #[test]
+    fn test_latex() {
+        let term = parse_program("let x = $1 in iszero(x)").unwrap();
+        let latex = type_derivation(&term).unwrap().to_latex();
+        println!("{}", latex);
+        assert!(latex.starts_with("\\inferrule*[right=T-Let]\n"));
+        assert!(latex.contains("\\inferrule*[right=T-Var]"));
+        assert!(latex.contains(
+            "{\\mathtt{x}{:}\\mathtt{\\$int} \\vdash \\mathtt{x} : \\mathtt{\\$int} ; \\cdot}"
+        ));
+        assert!(latex.ends_with("{\\cdot \\vdash \\mathtt{let\\ x\\ =\\ \\$1\\ in\\ iszero(x)} : \\mathtt{bool} ; \\cdot}"));
+        // premises are separated by `\\`
+        assert_eq!(latex.matches(" \\\\\n").count(), 1);
+    }
+}
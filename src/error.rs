@@ -1,52 +1,492 @@
+use crate::source_map::{Location, SourceMap};
+use crate::syntax::NodeId;
 use pest::error::Error as PestError;
 use pest::RuleType;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::fmt::{Display, Formatter};
 
-// hint: generate `source` from `Span::as_str()`
-// hint: Get the position with `Span::start() -> usize` and `Span::end() -> usize`
+/// A secondary span attached to a diagnostic, e.g. where a variable was bound.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Label {
+    pub message: String,
+    pub start: usize,
+    pub end: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<Location>,
+}
+
+/// Replace `start..end` of the source with `replacement`. An empty range is an insertion.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Edit {
+    pub start: usize,
+    pub end: usize,
+    pub replacement: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<Location>,
+}
+
+/// A machine-applicable repair of a diagnostic: applying all of its edits fixes the error.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Suggestion {
+    pub message: String,
+    pub edits: Vec<Edit>,
+}
+
+impl Suggestion {
+    pub fn insert(message: String, at: usize, text: String) -> Self {
+        Suggestion {
+            message,
+            edits: vec![Edit {
+                start: at,
+                end: at,
+                replacement: text,
+                location: None,
+            }],
+        }
+    }
+
+    /// Apply the edits to the source the diagnostic was reported on.
+    pub fn apply(&self, source: &str) -> String {
+        let mut edits: Vec<&Edit> = self.edits.iter().collect();
+        edits.sort_by_key(|e| (e.start, e.end));
+        let mut result = String::new();
+        let mut last = 0;
+        for edit in edits {
+            result.push_str(&source[last..edit.start]);
+            result.push_str(&edit.replacement);
+            last = edit.end;
+        }
+        result.push_str(&source[last..]);
+        result
+    }
+}
+
+/// A stable code for each kind of diagnostic, serialized as e.g. `"E0003"`.
+///
+/// Codes never change meaning once published; `explain::explain` has the long-form
+/// explanation of each. `E00xx` are reported before evaluation, `E01xx` by the evaluator,
+/// `E02xx` by the linter and `E09xx` are internal errors. Rules added with
+/// `lint::LintRunner::register` bring their own codes as `Custom`, e.g. `L0001`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    SyntaxError,
+    UndefinedVariable,
+    LinearNotConsumed,
+    LinearConsumedTwice,
+    BranchConsumption,
+    LinearCapturedByFunction,
+    LinearCapturedByPair,
+    ArgumentMismatch,
+    NotAFunction,
+    BranchTypeMismatch,
+    ConditionNotBoolean,
+    InvalidRecursion,
+    LinearRecursion,
+    NotAPair,
+    DuplicatePatternVariable,
+    NotAnInteger,
+    LinearResult,
+    IncompatibleQualifiers,
+    MissingAnnotation,
+    AnnotationMismatch,
+    UnboundStoreVariable,
+    StuckTerm,
+    MalformedTerm,
+    UnfilledHole,
+    LinearUsedAgain,
+    LinearLeaked,
+    UnknownBinding,
+    UnusedVariable,
+    ShadowedLinear,
+    RedundantQualifier,
+    DeepLetNesting,
+    DiscardedLinearResult,
+    LargeClosureCapture,
+    TypedHole,
+    Internal,
+    /// The code of a lint rule that is not built in. It should not start with `E`.
+    Custom(&'static str),
+}
+
+impl ErrorCode {
+    /// The built-in codes.
+    pub const ALL: [ErrorCode; 35] = [
+        ErrorCode::SyntaxError,
+        ErrorCode::UndefinedVariable,
+        ErrorCode::LinearNotConsumed,
+        ErrorCode::LinearConsumedTwice,
+        ErrorCode::BranchConsumption,
+        ErrorCode::LinearCapturedByFunction,
+        ErrorCode::LinearCapturedByPair,
+        ErrorCode::ArgumentMismatch,
+        ErrorCode::NotAFunction,
+        ErrorCode::BranchTypeMismatch,
+        ErrorCode::ConditionNotBoolean,
+        ErrorCode::InvalidRecursion,
+        ErrorCode::LinearRecursion,
+        ErrorCode::NotAPair,
+        ErrorCode::DuplicatePatternVariable,
+        ErrorCode::NotAnInteger,
+        ErrorCode::LinearResult,
+        ErrorCode::IncompatibleQualifiers,
+        ErrorCode::MissingAnnotation,
+        ErrorCode::AnnotationMismatch,
+        ErrorCode::UnboundStoreVariable,
+        ErrorCode::StuckTerm,
+        ErrorCode::MalformedTerm,
+        ErrorCode::UnfilledHole,
+        ErrorCode::LinearUsedAgain,
+        ErrorCode::LinearLeaked,
+        ErrorCode::UnknownBinding,
+        ErrorCode::UnusedVariable,
+        ErrorCode::ShadowedLinear,
+        ErrorCode::RedundantQualifier,
+        ErrorCode::DeepLetNesting,
+        ErrorCode::DiscardedLinearResult,
+        ErrorCode::LargeClosureCapture,
+        ErrorCode::TypedHole,
+        ErrorCode::Internal,
+    ];
+
+    /// The code as written in diagnostics, e.g. `E0003`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::SyntaxError => "E0001",
+            ErrorCode::UndefinedVariable => "E0002",
+            ErrorCode::LinearNotConsumed => "E0003",
+            ErrorCode::LinearConsumedTwice => "E0004",
+            ErrorCode::BranchConsumption => "E0005",
+            ErrorCode::LinearCapturedByFunction => "E0006",
+            ErrorCode::LinearCapturedByPair => "E0007",
+            ErrorCode::ArgumentMismatch => "E0008",
+            ErrorCode::NotAFunction => "E0009",
+            ErrorCode::BranchTypeMismatch => "E0010",
+            ErrorCode::ConditionNotBoolean => "E0011",
+            ErrorCode::InvalidRecursion => "E0012",
+            ErrorCode::LinearRecursion => "E0013",
+            ErrorCode::NotAPair => "E0014",
+            ErrorCode::DuplicatePatternVariable => "E0015",
+            ErrorCode::NotAnInteger => "E0016",
+            ErrorCode::LinearResult => "E0017",
+            ErrorCode::IncompatibleQualifiers => "E0018",
+            ErrorCode::MissingAnnotation => "E0019",
+            ErrorCode::AnnotationMismatch => "E0020",
+            ErrorCode::UnboundStoreVariable => "E0101",
+            ErrorCode::StuckTerm => "E0102",
+            ErrorCode::MalformedTerm => "E0103",
+            ErrorCode::UnfilledHole => "E0104",
+            ErrorCode::LinearUsedAgain => "E0105",
+            ErrorCode::LinearLeaked => "E0106",
+            ErrorCode::UnknownBinding => "E0107",
+            ErrorCode::UnusedVariable => "E0201",
+            ErrorCode::ShadowedLinear => "E0202",
+            ErrorCode::RedundantQualifier => "E0203",
+            ErrorCode::DeepLetNesting => "E0204",
+            ErrorCode::DiscardedLinearResult => "E0205",
+            ErrorCode::LargeClosureCapture => "E0206",
+            ErrorCode::TypedHole => "E0207",
+            ErrorCode::Internal => "E0901",
+            ErrorCode::Custom(code) => code,
+        }
+    }
+
+    /// Look up a built-in code.
+    pub fn from_code(code: &str) -> Option<ErrorCode> {
+        ErrorCode::ALL.iter().copied().find(|c| c.as_str() == code)
+    }
+}
+
+impl Serialize for ErrorCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+/// Only built-in codes can be read back: a custom code belongs to a rule of the program that
+/// registered it.
+impl<'de> Deserialize<'de> for ErrorCode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let code = String::deserialize(deserializer)?;
+        ErrorCode::from_code(&code)
+            .ok_or_else(|| serde::de::Error::custom(format!("unknown error code {}", code)))
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// How seriously a diagnostic is taken. Only lints can be warnings, and their severity is
+/// configurable with `lint::LintConfig`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A diagnostic. `start` and `end` are byte offsets into the source; `location` has the same
+/// span in lines and columns once `Error::with_source_map` has been called.
+///
+/// The diagnostics with a span are boxed, labels and suggestions and all, so that a
+/// `Result<_, Error>` stays small.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Error {
-    ParseError {
-        message: String,
-        start: usize,
-        end: usize,
-    },
-    EvaluateError {
-        message: String,
-        source: String,
-    },
-    TypeError {
-        message: String,
-        start: usize,
-        end: usize,
-    },
+    ParseError(Box<ParseError>),
+    EvaluateError(Box<EvaluateError>),
+    TypeError(Box<TypeError>),
+    /// A finding of the linter: the program is well-formed, but probably not what was meant.
+    LintError(Box<LintError>),
     InternalError {
+        code: ErrorCode,
         message: String,
     },
 }
 
-// TODO: JSON format
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ParseError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub start: usize,
+    pub end: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<Location>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EvaluateError {
+    pub code: ErrorCode,
+    pub message: String,
+    /// The span of the term that could not be evaluated.
+    pub start: usize,
+    pub end: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<Location>,
+    /// The term that could not be evaluated.
+    #[serde(default)]
+    pub node: NodeId,
+    /// Related places in the source, e.g. where a linear value was created and consumed.
+    #[serde(default)]
+    pub labels: Vec<Label>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TypeError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub start: usize,
+    pub end: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<Location>,
+    /// The type that was expected, rendered as source syntax (`_` stands for any type).
+    pub expected: Option<String>,
+    /// The type that was found instead, rendered as source syntax.
+    pub found: Option<String>,
+    /// Related places in the source, e.g. where a variable was bound and first consumed.
+    #[serde(default)]
+    pub labels: Vec<Label>,
+    /// Edits that fix the error, in order of preference.
+    #[serde(default)]
+    pub suggestions: Vec<Suggestion>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LintError {
+    pub code: ErrorCode,
+    pub severity: Severity,
+    pub message: String,
+    pub start: usize,
+    pub end: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<Location>,
+    #[serde(default)]
+    pub labels: Vec<Label>,
+    #[serde(default)]
+    pub suggestions: Vec<Suggestion>,
+}
+
+impl From<ParseError> for Error {
+    fn from(error: ParseError) -> Self {
+        Error::ParseError(Box::new(error))
+    }
+}
+
+impl From<EvaluateError> for Error {
+    fn from(error: EvaluateError) -> Self {
+        Error::EvaluateError(Box::new(error))
+    }
+}
+
+impl From<TypeError> for Error {
+    fn from(error: TypeError) -> Self {
+        Error::TypeError(Box::new(error))
+    }
+}
+
+impl From<LintError> for Error {
+    fn from(error: LintError) -> Self {
+        Error::LintError(Box::new(error))
+    }
+}
+
+/// `[Ln 3, Col 5]` if the location is known, otherwise the byte offsets as `[12:20]`.
+fn write_position(
+    f: &mut Formatter,
+    location: &Option<Location>,
+    start: usize,
+    end: usize,
+) -> Result<(), std::fmt::Error> {
+    match location {
+        Some(Location { start, .. }) => {
+            write!(f, "[Ln {}, Col {}]", start.line + 1, start.column + 1)
+        }
+        None => write!(f, "[{}:{}]", start, end),
+    }
+}
+
+fn write_labels(f: &mut Formatter, labels: &[Label]) -> Result<(), std::fmt::Error> {
+    for label in labels {
+        write!(f, "    ")?;
+        write_position(f, &label.location, label.start, label.end)?;
+        writeln!(f, " {}", label.message)?;
+    }
+    Ok(())
+}
+
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
         match self {
-            Error::ParseError { message, .. } => {
-                write!(f, "Parse error: {}\n", message)
+            Error::ParseError(e) => {
+                write!(f, "Parse error [{}] in ", e.code)?;
+                write_position(f, &e.location, e.start, e.end)?;
+                writeln!(f, ": {}", e.message)
+            }
+            Error::EvaluateError(e) => {
+                write!(f, "Evaluate error [{}] in ", e.code)?;
+                write_position(f, &e.location, e.start, e.end)?;
+                writeln!(f, ": {}", e.message)?;
+                write_labels(f, &e.labels)
+            }
+            Error::TypeError(e) => {
+                write!(f, "Type error [{}] in ", e.code)?;
+                write_position(f, &e.location, e.start, e.end)?;
+                writeln!(f, ": {}", e.message)?;
+                write_labels(f, &e.labels)
+            }
+            Error::LintError(e) => {
+                write!(f, "Lint {} [{}] in ", e.severity, e.code)?;
+                write_position(f, &e.location, e.start, e.end)?;
+                writeln!(f, ": {}", e.message)?;
+                write_labels(f, &e.labels)
+            }
+            Error::InternalError { code, message } => {
+                write!(f, "Internal error [{}]: {}", code, message)
+            }
+        }
+    }
+}
+
+impl Error {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::ParseError(e) => e.code,
+            Error::EvaluateError(e) => e.code,
+            Error::TypeError(e) => e.code,
+            Error::LintError(e) => e.code,
+            Error::InternalError { code, .. } => *code,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            Error::ParseError(e) => &e.message,
+            Error::EvaluateError(e) => &e.message,
+            Error::TypeError(e) => &e.message,
+            Error::LintError(e) => &e.message,
+            Error::InternalError { message, .. } => message,
+        }
+    }
+
+    pub fn severity(&self) -> Severity {
+        match self {
+            Error::LintError(e) => e.severity,
+            _ => Severity::Error,
+        }
+    }
+
+    /// The span of the error in the source, if it has one.
+    pub fn span(&self) -> Option<(usize, usize)> {
+        match self {
+            Error::ParseError(e) => Some((e.start, e.end)),
+            Error::EvaluateError(e) => Some((e.start, e.end)),
+            Error::TypeError(e) => Some((e.start, e.end)),
+            Error::LintError(e) => Some((e.start, e.end)),
+            Error::InternalError { .. } => None,
+        }
+    }
+
+    /// Attach secondary labels to a type, evaluation or lint error. Other errors are returned
+    /// unchanged.
+    pub fn with_labels(mut self, new_labels: Vec<Label>) -> Self {
+        match &mut self {
+            Error::EvaluateError(e) => e.labels.extend(new_labels),
+            Error::TypeError(e) => e.labels.extend(new_labels),
+            Error::LintError(e) => e.labels.extend(new_labels),
+            Error::ParseError(_) | Error::InternalError { .. } => {}
+        }
+        self
+    }
+
+    /// Fill in the line/column `location` of the error and of its labels and suggested edits,
+    /// given the source it was reported on.
+    pub fn with_source_map(mut self, map: &SourceMap) -> Self {
+        let (labels, suggestions): (&mut [Label], &mut [Suggestion]) = match &mut self {
+            Error::ParseError(e) => {
+                e.location = Some(map.location(e.start, e.end));
+                (&mut [], &mut [])
             }
-            Error::EvaluateError { message, source } => {
-                write!(f, "Evaluate error: {}\n{}", message, source)
+            Error::EvaluateError(e) => {
+                e.location = Some(map.location(e.start, e.end));
+                (&mut e.labels, &mut [])
             }
-            Error::TypeError {
-                message,
-                start,
-                end,
-            } => {
-                write!(f, "Type error in [Ln {}, Col {}]: {}\n", start, end, message)
+            Error::TypeError(e) => {
+                e.location = Some(map.location(e.start, e.end));
+                (&mut e.labels, &mut e.suggestions)
             }
-            Error::InternalError {
-                message,
-            } => write!(f, "Internal error: {}", message),
+            Error::LintError(e) => {
+                e.location = Some(map.location(e.start, e.end));
+                (&mut e.labels, &mut e.suggestions)
+            }
+            Error::InternalError { .. } => (&mut [], &mut []),
+        };
+        for label in labels {
+            label.location = Some(map.location(label.start, label.end));
         }
+        for edit in suggestions.iter_mut().flat_map(|s| s.edits.iter_mut()) {
+            edit.location = Some(map.location(edit.start, edit.end));
+        }
+        self
+    }
+
+    /// Attach a suggested fix to a type error or lint. Other errors are returned unchanged.
+    pub fn with_suggestion(mut self, suggestion: Option<Suggestion>) -> Self {
+        match (&mut self, suggestion) {
+            (Error::TypeError(e), Some(suggestion)) => e.suggestions.push(suggestion),
+            (Error::LintError(e), Some(suggestion)) => e.suggestions.push(suggestion),
+            _ => {}
+        }
+        self
     }
 }
 
@@ -61,10 +501,12 @@ impl<R: RuleType> From<PestError<R>> for Error {
             pest::error::InputLocation::Pos(_) => start + 1,
             pest::error::InputLocation::Span((_, j)) => *j,
         };
-        Error::ParseError {
+        Error::from(ParseError {
+            code: ErrorCode::SyntaxError,
             message: format!("{}", error),
             start,
             end,
-        }
+            location: None,
+        })
     }
 }
@@ -1,12 +1,80 @@
-use crate::error::Error;
-use crate::syntax::{ArithOp, Qualifier, Term, TermCtx};
+use crate::derivation::StepDerivation;
+use crate::error::{Error, ErrorCode, EvaluateError, Label};
+use crate::formatter;
+use crate::syntax::{ArithOp, Context, NodeId, Qualifier, Term, TermCtx};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Store {
     bindings: HashMap<String, TermCtx>,
     counter: u128,
+    /// The tags of the bindings, if the store is monitored. A consumed linear binding keeps its
+    /// tag, so that a second use can be told apart from an unbound variable.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tags: Option<HashMap<String, Tag>>,
+    #[serde(default)]
+    gc: GcStats,
+    #[serde(default)]
+    strategy: Strategy,
+    /// The arguments bound without being evaluated, under a lazy strategy.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    thunks: HashMap<String, Thunk>,
+}
+
+/// How arguments are passed to functions, and values to `let` bindings.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    /// An argument is evaluated before it is passed. Terms are evaluated left to right.
+    #[default]
+    CallByValue,
+    /// An argument is passed as a thunk, and evaluated again at each use.
+    CallByName,
+    /// An argument is passed as a thunk, and evaluated at its first use only.
+    CallByNeed,
+}
+
+/// An argument passed by a lazy strategy, bound to a name starting with `%t`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Thunk {
+    /// The argument, not evaluated yet. Under call-by-name, a thunk whose value is unrestricted
+    /// stays delayed after it is forced.
+    Delayed(TermCtx),
+    /// The argument was forced to the value bound to this name. A linear value is always
+    /// memoized, so that it is forced once: a second use finds it consumed.
+    Memo(String),
+}
+
+fn is_thunk(name: &str) -> bool {
+    name.starts_with("%t")
+}
+
+/// What the garbage collector has done to a store so far.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GcStats {
+    /// The number of collections.
+    pub collections: usize,
+    /// The number of bindings freed, over all collections.
+    pub freed: usize,
+    /// The number of bindings left by the last collection.
+    pub live: usize,
+    /// The linear bindings that the last collection found unreachable. They are not freed:
+    /// they are leaks.
+    pub unreachable_linear: Vec<String>,
+}
+
+/// What the linearity monitor knows about a binding of the store.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tag {
+    pub qualifier: Qualifier,
+    /// The span of the value that the binding was created from.
+    pub origin: Context,
+    #[serde(default)]
+    pub node: NodeId,
+    /// Where a linear binding was consumed, once it is.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub consumed: Option<Context>,
 }
 
 impl Store {
@@ -14,34 +82,373 @@ impl Store {
         Store {
             bindings: HashMap::new(),
             counter: 0,
+            tags: None,
+            gc: GcStats::default(),
+            strategy: Strategy::default(),
+            thunks: HashMap::new(),
+        }
+    }
+
+    /// The store, evaluating with `strategy`.
+    pub fn with_strategy(self, strategy: Strategy) -> Store {
+        Store { strategy, ..self }
+    }
+
+    pub fn strategy(&self) -> Strategy {
+        self.strategy
+    }
+
+    /// An empty store that tags its bindings, to report linear values used twice or never.
+    pub fn new_monitored() -> Store {
+        Store {
+            tags: Some(HashMap::new()),
+            ..Store::new_empty()
         }
     }
 
     fn push(&mut self, name: String, value: TermCtx) {
-        assert!(get_qualifier(&value).is_some());
+        let qualifier = get_qualifier(&value).unwrap();
+        if let Some(tags) = &mut self.tags {
+            let tag = Tag {
+                qualifier,
+                origin: value.0,
+                node: value.2,
+                consumed: None,
+            };
+            tags.insert(name.clone(), tag);
+        }
         self.bindings.insert(name, value);
     }
 
-    fn extract(&mut self, name: &str) -> Option<TermCtx> {
+    /// Look up a binding used at `at`. A linear binding is removed.
+    fn extract(&mut self, name: &str, at: Context) -> Option<TermCtx> {
         let value = self.bindings.get(name)?;
         return match get_qualifier(value) {
-            Some(Qualifier::Linear) => self.bindings.remove(name),
+            Some(Qualifier::Linear) => {
+                if let Some(tag) = self.tags.as_mut().and_then(|tags| tags.get_mut(name)) {
+                    tag.consumed = Some(at);
+                }
+                self.bindings.remove(name)
+            }
             _ => self.bindings.get(name).map(|x| x.clone()),
         };
     }
 
+    /// Bind `term` to a new thunk, and give the variable to use in its place.
+    ///
+    /// A monitored store tags the thunk like a binding, linear if it is seen to make a linear
+    /// value, so that a thunk that is never forced can be reported as a leak.
+    fn delay(&mut self, term: TermCtx) -> TermCtx {
+        let name = self.fresh_variable("%t");
+        let var = TermCtx(term.0, Term::Variable(name.clone()), term.2, None);
+        if let Some(tags) = &mut self.tags {
+            let qualifier = if makes_linear(&term) {
+                Qualifier::Linear
+            } else {
+                Qualifier::Nop
+            };
+            let tag = Tag {
+                qualifier,
+                origin: term.0,
+                node: term.2,
+                consumed: None,
+            };
+            tags.insert(name.clone(), tag);
+        }
+        self.thunks.insert(name, Thunk::Delayed(term));
+        var
+    }
+
+    /// If `term_ctx` needs the value of a variable bound to a thunk, replace the variable with
+    /// what forces the thunk, and give the span and node of the variable.
+    ///
+    /// A delayed thunk `%t` is forced by `let %t = term in %t`; its `E-Memo` step is where the
+    /// value gets memoized. A memoized thunk is replaced by the variable of its value.
+    fn force(&self, term_ctx: &mut TermCtx) -> Option<(Context, NodeId)> {
+        if self.thunks.is_empty() {
+            return None;
+        }
+        let slots: Vec<&mut TermCtx> = match &mut term_ctx.1 {
+            Term::Variable(_) => vec![term_ctx],
+            Term::Conditional(t, ..)
+            | Term::Application(t, _)
+            | Term::Letc(_, _, t, _)
+            | Term::Arith1(_, _, t) => vec![t],
+            Term::Arith2(_, _, t1, t2) if is_var(t1) && is_var(t2) => vec![t1, t2],
+            _ => vec![],
+        };
+        for slot in slots {
+            let TermCtx(ctx, Term::Variable(x), id, key) = slot else {
+                continue;
+            };
+            let (ctx, id) = (*ctx, *id);
+            let forced = match self.thunks.get(x) {
+                Some(Thunk::Memo(y)) => Term::Variable(y.clone()),
+                Some(Thunk::Delayed(term)) => {
+                    let var = TermCtx(ctx, Term::Variable(x.clone()), id, None);
+                    Term::Let(x.clone(), Box::new(term.clone()), Box::new(var))
+                }
+                None => continue,
+            };
+            *slot = TermCtx(ctx, forced, id, key.take());
+            return Some((ctx, id));
+        }
+        None
+    }
+
+    /// The thunk `name` was forced to the value bound to `value`.
+    ///
+    /// Under call-by-name, the thunk stays delayed, to be evaluated again at its next use,
+    /// unless that would use linear values again: when the value is linear, or when the term
+    /// of the thunk refers to linear values, which the forcing may have consumed. A thunk that
+    /// was taken out of the store to be forced, see `run_eval`, is memoized too.
+    fn memoize(&mut self, name: &str, value: &str) {
+        let again = match self.thunks.get(name) {
+            Some(Thunk::Delayed(term)) if self.strategy == Strategy::CallByName => {
+                let linear = (self.bindings.get(value))
+                    .is_some_and(|v| get_qualifier(v) == Some(Qualifier::Linear));
+                !linear && !self.uses_linear(term)
+            }
+            _ => false,
+        };
+        if !again {
+            self.thunks
+                .insert(name.to_owned(), Thunk::Memo(value.to_owned()));
+        }
+    }
+
+    /// Whether `term` refers to linear values, directly or through thunks. A name bound to
+    /// nothing is taken to be a linear value that was consumed.
+    fn uses_linear(&self, term: &TermCtx) -> bool {
+        let free = term.free_variables();
+        free.iter()
+            .any(|x| match (self.bindings.get(x), self.thunks.get(x)) {
+                (Some(value), _) => get_qualifier(value) == Some(Qualifier::Linear),
+                (None, Some(Thunk::Memo(y))) => (self.bindings.get(y))
+                    .is_none_or(|v| get_qualifier(v) == Some(Qualifier::Linear)),
+                (None, Some(Thunk::Delayed(term))) => self.uses_linear(term),
+                (None, None) => true,
+            })
+    }
+
+    /// A thunk reachable from `term` that is not forced yet.
+    fn unforced(&self, term: &TermCtx) -> Option<String> {
+        (self.reachable(term).into_iter())
+            .find(|name| matches!(self.thunks.get(name), Some(Thunk::Delayed(_))))
+    }
+
+    /// The value bound to `name`, through a memoized thunk.
+    pub fn lookup(&self, name: &str) -> Option<&TermCtx> {
+        match self.thunks.get(name) {
+            Some(Thunk::Memo(value)) => self.bindings.get(value),
+            _ => self.bindings.get(name),
+        }
+    }
+
+    /// The tag of a binding, if the store is monitored.
+    pub fn tag(&self, name: &str) -> Option<&Tag> {
+        self.tags.as_ref()?.get(name)
+    }
+
+    /// The bindings that `term` refers to, directly or through other bindings.
+    fn reachable(&self, term: &TermCtx) -> BTreeSet<String> {
+        let mut reachable = term.free_variables();
+        let mut todo: Vec<String> = reachable.iter().cloned().collect();
+        while let Some(name) = todo.pop() {
+            let mut free = BTreeSet::new();
+            if let Some(value) = self.bindings.get(&name) {
+                free.extend(value.free_variables());
+            }
+            match self.thunks.get(&name) {
+                Some(Thunk::Delayed(term)) => free.extend(term.free_variables()),
+                Some(Thunk::Memo(value)) => {
+                    free.insert(value.clone());
+                }
+                None => (),
+            }
+            for x in free {
+                if reachable.insert(x.clone()) {
+                    todo.push(x);
+                }
+            }
+        }
+        reachable
+    }
+
+    /// The linear bindings of a monitored store that are neither consumed nor reachable from
+    /// `term`, ordered by where they were created.
+    pub fn leaks(&self, term: &TermCtx) -> Vec<(&str, &Tag)> {
+        let Some(tags) = &self.tags else {
+            return vec![];
+        };
+        let reachable = self.reachable(term);
+        let unforced = (self.thunks.iter())
+            .filter(|(_, thunk)| matches!(thunk, Thunk::Delayed(_)))
+            .map(|(name, _)| name);
+        let mut leaks: Vec<_> = (self.bindings.keys().chain(unforced))
+            .filter(|name| !reachable.contains(*name))
+            .filter_map(|name| Some((name.as_str(), tags.get(name)?)))
+            .filter(|(_, tag)| tag.qualifier == Qualifier::Linear)
+            .collect();
+        leaks.sort_by_key(|(name, tag)| (tag.origin.start, *name));
+        leaks
+    }
+
+    /// Free the unrestricted bindings that `term` cannot reach. Unreachable linear bindings
+    /// are kept, and listed in the stats, like the thunks that are not forced and would make
+    /// a linear value.
+    pub fn collect(&mut self, term: &TermCtx) -> &GcStats {
+        let reachable = self.reachable(term);
+        let mut unreachable_linear = vec![];
+        let before = self.bindings.len();
+        self.bindings.retain(|name, value| {
+            if reachable.contains(name) {
+                true
+            } else if get_qualifier(value) == Some(Qualifier::Linear) {
+                unreachable_linear.push(name.clone());
+                true
+            } else {
+                false
+            }
+        });
+        if let Some(tags) = &mut self.tags {
+            // the tags of consumed linear bindings are still needed to report a second use
+            tags.retain(|name, tag| {
+                tag.qualifier == Qualifier::Linear || self.bindings.contains_key(name)
+            });
+        }
+        self.thunks.retain(|name, thunk| match thunk {
+            _ if reachable.contains(name) => true,
+            Thunk::Delayed(term) if makes_linear(term) => {
+                unreachable_linear.push(name.clone());
+                true
+            }
+            _ => false,
+        });
+        unreachable_linear.sort();
+        self.gc.collections += 1;
+        self.gc.freed += before - self.bindings.len();
+        self.gc.live = self.bindings.len();
+        self.gc.unreachable_linear = unreachable_linear;
+        &self.gc
+    }
+
+    pub fn gc_stats(&self) -> &GcStats {
+        &self.gc
+    }
+
+    /// The number of bindings.
+    pub fn len(&self) -> usize {
+        self.bindings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bindings.is_empty()
+    }
+
+    /// Whether the store has a binding `name`.
+    pub fn contains(&self, name: &str) -> bool {
+        self.bindings.contains_key(name)
+    }
+
+    /// The value bound to `name`, read back like `TermEval::answer`.
+    pub fn answer_of(&self, name: &str) -> Option<Answer> {
+        self.answer(self.lookup(name)?)
+    }
+
+    fn answer(&self, value: &TermCtx) -> Option<Answer> {
+        let answer = match &value.1 {
+            Term::Boolean(q, v) => Answer::Boolean(*q, *v),
+            Term::Integer(q, v) => Answer::Integer(*q, *v),
+            Term::Abstraction(q, ..) => Answer::Function(*q),
+            // a recursive function, bound by the unrolling of `fix`
+            Term::Fix(_) => Answer::Function(Qualifier::Nop),
+            Term::Compound(q, t1, t2) => match (&t1.1, &t2.1) {
+                (Term::Variable(x1), Term::Variable(x2)) => {
+                    let a1 = self.answer(self.lookup(x1)?)?;
+                    let a2 = self.answer(self.lookup(x2)?)?;
+                    Answer::Pair(*q, Box::new(a1), Box::new(a2))
+                }
+                _ => return None,
+            },
+            _ => return None,
+        };
+        Some(answer)
+    }
+
     fn fresh_variable(&mut self, prefix: &str) -> String {
         self.counter += 1;
         format!("{}{}", prefix, self.counter)
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TermEval {
     store: Store,
     term: TermCtx,
 }
 
+/// A value with the values in the store that it refers to read back into it, to compare the
+/// results of evaluators that lay out their stores differently.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Answer {
+    Boolean(Qualifier, bool),
+    Integer(Qualifier, i64),
+    Function(Qualifier),
+    Pair(Qualifier, Box<Answer>, Box<Answer>),
+}
+
+/// Written in the syntax of the language, e.g. `$<1, true>`; functions are shown as `<fn>`.
+impl std::fmt::Display for Answer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let q = match self {
+            Answer::Boolean(q, _) | Answer::Integer(q, _) | Answer::Function(q) => q,
+            Answer::Pair(q, ..) => q,
+        };
+        if *q == Qualifier::Linear {
+            write!(f, "$")?;
+        }
+        match self {
+            Answer::Boolean(_, v) => write!(f, "{}", v),
+            Answer::Integer(_, v) => write!(f, "{}", v),
+            Answer::Function(_) => write!(f, "<fn>"),
+            Answer::Pair(_, a1, a2) => write!(f, "<{}, {}>", a1, a2),
+        }
+    }
+}
+
+impl TermEval {
+    pub fn new(term: TermCtx, store: Store) -> TermEval {
+        TermEval { store, term }
+    }
+
+    /// The value of the term, if it is one.
+    pub fn answer(&self) -> Option<Answer> {
+        self.store.answer(&self.term)
+    }
+
+    pub fn is_value(&self) -> bool {
+        is_value(&self.term)
+    }
+
+    pub fn term(&self) -> &TermCtx {
+        &self.term
+    }
+
+    pub fn store(&self) -> &Store {
+        &self.store
+    }
+
+    /// Free the unrestricted bindings of the store that the term cannot reach.
+    pub fn collect_garbage(&mut self) -> &GcStats {
+        self.store.collect(&self.term)
+    }
+
+    pub fn gc_stats(&self) -> &GcStats {
+        self.store.gc_stats()
+    }
+}
+
 impl From<TermCtx> for TermEval {
     fn from(term: TermCtx) -> TermEval {
         TermEval {
@@ -53,13 +460,13 @@ impl From<TermCtx> for TermEval {
 
 fn is_var(term: &TermCtx) -> bool {
     match term {
-        TermCtx(_, Term::Variable(..)) => true,
+        TermCtx(_, Term::Variable(..), ..) => true,
         _ => false,
     }
 }
 
 fn is_value(term: &TermCtx) -> bool {
-    let TermCtx(_, term) = term;
+    let TermCtx(_, term, ..) = term;
     match term {
         Term::Boolean(..) => true,
         Term::Integer(..) => true,
@@ -69,8 +476,20 @@ fn is_value(term: &TermCtx) -> bool {
     }
 }
 
+/// Whether evaluating `term` is seen to make a linear value, without evaluating it. Only
+/// literals, abstractions, pairs and arithmetic with a qualifier are seen through, with the
+/// terms that step to them, e.g. the body of a `let`.
+fn makes_linear(term: &TermCtx) -> bool {
+    match &term.1 {
+        Term::Let(_, _, t) | Term::Letc(_, _, _, t) | Term::Ascription(t, _) => makes_linear(t),
+        Term::Conditional(_, t2, t3) => makes_linear(t2) || makes_linear(t3),
+        Term::Arith1(q, ..) | Term::Arith2(q, ..) => *q == Qualifier::Linear,
+        _ => get_qualifier(term) == Some(Qualifier::Linear),
+    }
+}
+
 fn get_qualifier(term: &TermCtx) -> Option<Qualifier> {
-    let TermCtx(_, term) = term;
+    let TermCtx(_, term, ..) = term;
     let q = match term {
         Term::Boolean(q, ..) => q,
         Term::Integer(q, ..) => q,
@@ -82,7 +501,7 @@ fn get_qualifier(term: &TermCtx) -> Option<Qualifier> {
 }
 
 fn subst_var(term_ctx: Box<TermCtx>, x: &str, x2: &str) -> Box<TermCtx> {
-    let TermCtx(ctx, term) = *term_ctx;
+    let TermCtx(ctx, term, id, key) = *term_ctx;
     let term = match term {
         Term::Variable(y) if y == x => Term::Variable(x2.to_owned()),
         Term::Application(t1, t2) => Term::Application(subst_var(t1, x, x2), subst_var(t2, x, x2)),
@@ -118,135 +537,417 @@ fn subst_var(term_ctx: Box<TermCtx>, x: &str, x2: &str) -> Box<TermCtx> {
         Term::Arith2(q, op, t1, t2) => {
             Term::Arith2(q, op, subst_var(t1, x, x2), subst_var(t2, x, x2))
         }
+        Term::Ascription(t, ty) => Term::Ascription(subst_var(t, x, x2), ty),
         _ => term,
     };
-    Box::new(TermCtx(ctx, term))
+    Box::new(TermCtx(ctx, term, id, key))
 }
 
-fn one_step_eval_aux(store: &mut Store, term_ctx: TermCtx) -> Result<TermCtx, Error> {
-    let err = |msg| Error::EvaluateError {
-        message: msg,
-        source: term_ctx.0.to_string(),
+/// Step `term_ctx`, and record the derivation of the step if `trace` asks for it.
+fn one_step_eval_aux(
+    store: &mut Store,
+    trace: &mut Trace,
+    term_ctx: TermCtx,
+) -> Result<TermCtx, Error> {
+    if !trace.record {
+        return one_step_eval_node(store, trace, term_ctx);
+    }
+    let (span, id) = (term_ctx.0, term_ctx.2);
+    let term = formatter::format_termctx(&term_ctx);
+    let result = one_step_eval_node(store, trace, term_ctx)?;
+    // a congruence rule if the redex is in a subterm, otherwise the rule that fired here
+    let rule = match (trace.congruence.take(), trace.fired) {
+        (Some(rule), _) => rule,
+        (None, Some((rule, ..))) => rule.name(),
+        (None, None) => "?",
     };
+    trace.derivation = Some(StepDerivation {
+        rule: rule.to_owned(),
+        id,
+        span,
+        term,
+        result: formatter::format_termctx(&result),
+        premises: trace.derivation.take().into_iter().collect(),
+    });
+    Ok(result)
+}
+
+/// Step the subterm `term_ctx` of a term, by the congruence rule `rule`.
+fn congruence(
+    store: &mut Store,
+    trace: &mut Trace,
+    rule: &'static str,
+    term_ctx: TermCtx,
+) -> Result<Box<TermCtx>, Error> {
+    let result = one_step_eval_aux(store, trace, term_ctx)?;
+    trace.congruence = Some(rule);
+    Ok(Box::new(result))
+}
+
+fn one_step_eval_node(
+    store: &mut Store,
+    trace: &mut Trace,
+    mut term_ctx: TermCtx,
+) -> Result<TermCtx, Error> {
+    if let Some((redex, node)) = store.force(&mut term_ctx) {
+        trace.fire(Rule::Force, redex, node);
+        return Ok(term_ctx);
+    }
+    let lazy = store.strategy != Strategy::CallByValue;
+    let err = |code, msg| {
+        Error::from(EvaluateError {
+            code,
+            message: msg,
+            start: term_ctx.0.start,
+            end: term_ctx.0.end,
+            location: None,
+            node: term_ctx.2,
+            labels: vec![],
+        })
+    };
+    let at = term_ctx.0;
     let mut extract = |x: &str| -> Result<TermCtx, Error> {
-        store
-            .extract(x)
-            .ok_or_else(|| err(format!("Variable {} not found", x)))
+        if let Some(value) = store.extract(x, at) {
+            return Ok(value);
+        }
+        // a monitored store remembers the linear values that are gone
+        match store.tag(x) {
+            Some(Tag {
+                origin,
+                consumed: Some(consumed),
+                ..
+            }) => {
+                let message = format!("Linear value {} is used again", x);
+                let label = |message: &str, span: &Context| Label {
+                    message: message.to_string(),
+                    start: span.start,
+                    end: span.end,
+                    location: None,
+                };
+                Err(err(ErrorCode::LinearUsedAgain, message).with_labels(vec![
+                    label("the value is created here", origin),
+                    label("and consumed here", consumed),
+                ]))
+            }
+            _ => Err(err(
+                ErrorCode::UnboundStoreVariable,
+                format!("Variable {} not found", x),
+            )),
+        }
     };
-    let TermCtx(ctx, term) = term_ctx;
+    let TermCtx(ctx, term, id, key) = term_ctx;
     let dup_term = term.clone();
     let term = match term {
-        Term::Variable(x) => extract(&x)?.1,
+        Term::Variable(x) => {
+            trace.fire(Rule::Var, ctx, id);
+            extract(&x)?.1
+        }
         Term::Boolean(..) | Term::Integer(..) | Term::Abstraction(..) => {
+            trace.fire(Rule::Alloc, ctx, id);
             let var = store.fresh_variable("%x");
-            store.push(var.clone(), TermCtx(ctx, term));
+            store.push(var.clone(), TermCtx(ctx, term, id, key));
             Term::Variable(var)
             // term
         }
         Term::Compound(q, t1, t2) => match (&*t1, &*t2) {
-            (TermCtx(_, Term::Variable(..)), TermCtx(_, Term::Variable(..))) => {
+            (TermCtx(_, Term::Variable(..), ..), TermCtx(_, Term::Variable(..), ..)) => {
+                trace.fire(Rule::Pair, ctx, id);
                 let var = store.fresh_variable("%x");
-                store.push(var.clone(), TermCtx(ctx, dup_term));
+                store.push(var.clone(), TermCtx(ctx, dup_term, id, key));
                 Term::Variable(var)
             }
-            (TermCtx(_, Term::Variable(..)), _) => {
-                Term::Compound(q, t1, Box::new(one_step_eval_aux(store, *t2)?))
+            (TermCtx(_, Term::Variable(..), ..), _) => {
+                Term::Compound(q, t1, congruence(store, trace, "E-Pair2", *t2)?)
             }
-            _ => Term::Compound(q, Box::new(one_step_eval_aux(store, *t1)?), t2),
+            _ => Term::Compound(q, congruence(store, trace, "E-Pair1", *t1)?, t2),
         },
         Term::Conditional(t1, t2, t3) => match *t1 {
-            TermCtx(_, Term::Variable(x)) => match extract(&x)? {
-                TermCtx(_, Term::Boolean(_, v)) => return Ok(if v { *t2 } else { *t3 }),
-                t1_ @ TermCtx(_, Term::Fix(..)) => Term::Conditional(Box::new(t1_), t2, t3),
-                _ => return Err(err(format!("Conditional term must be boolean"))),
+            TermCtx(_, Term::Variable(x), ..) => match extract(&x)? {
+                TermCtx(_, Term::Boolean(_, v), ..) => {
+                    trace.fire(if v { Rule::IfTrue } else { Rule::IfFalse }, ctx, id);
+                    return Ok(if v { *t2 } else { *t3 });
+                }
+                t1_ @ TermCtx(_, Term::Fix(..), ..) => {
+                    trace.fire(Rule::Unfold, ctx, id);
+                    Term::Conditional(Box::new(t1_), t2, t3)
+                }
+                _ => {
+                    return Err(err(
+                        ErrorCode::StuckTerm,
+                        format!("Conditional term must be boolean"),
+                    ))
+                }
             },
             _ => {
-                let t1 = one_step_eval_aux(store, *t1)?;
-                Term::Conditional(Box::new(t1), t2, t3)
+                let t1 = congruence(store, trace, "E-If1", *t1)?;
+                Term::Conditional(t1, t2, t3)
             }
         },
         Term::Application(t1, t2) => match (&*t1, &*t2) {
-            (TermCtx(_, Term::Variable(x1)), TermCtx(_, Term::Variable(x2))) => match extract(&x1)?
-            {
-                TermCtx(_, Term::Abstraction(_, x, _, body)) => {
-                    return Ok(*subst_var(body, &x, &x2))
+            (TermCtx(_, Term::Variable(x1), ..), TermCtx(_, Term::Variable(x2), ..)) => {
+                match extract(&x1)? {
+                    TermCtx(_, Term::Abstraction(_, x, _, body), ..) => {
+                        trace.fire(Rule::AppAbs, ctx, id);
+                        return Ok(*subst_var(body, &x, &x2));
+                    }
+                    t1_ @ TermCtx(_, Term::Fix(..), ..) => {
+                        trace.fire(Rule::Unfold, ctx, id);
+                        Term::Application(Box::new(t1_), t2)
+                    }
+                    _ => return Err(err(ErrorCode::StuckTerm, format!("Expect abstraction"))),
                 }
-                t1_ @ TermCtx(_, Term::Fix(..)) => Term::Application(Box::new(t1_), t2),
-                _ => return Err(err(format!("Expect abstraction"))),
-            },
-            (TermCtx(_, Term::Variable(_)), _) => {
-                Term::Application(t1, Box::new(one_step_eval_aux(store, *t2)?))
             }
-            _ => Term::Application(Box::new(one_step_eval_aux(store, *t1)?), t2),
+            (TermCtx(_, Term::Variable(_), ..), _) if lazy => {
+                trace.fire(Rule::Delay, t2.0, t2.2);
+                Term::Application(t1, Box::new(store.delay(*t2)))
+            }
+            (TermCtx(_, Term::Variable(_), ..), _) => {
+                Term::Application(t1, congruence(store, trace, "E-App2", *t2)?)
+            }
+            _ => Term::Application(congruence(store, trace, "E-App1", *t1)?, t2),
         },
         Term::Let(x, t1, t2) => match *t1 {
-            TermCtx(_, Term::Variable(y)) => return Ok(*subst_var(t2, &x, &y)),
-            _ => Term::Let(x, Box::new(one_step_eval_aux(store, *t1)?), t2),
+            TermCtx(_, Term::Variable(y), ..) => {
+                if is_thunk(&x) {
+                    trace.fire(Rule::Memo, ctx, id);
+                    store.memoize(&x, &y);
+                } else {
+                    trace.fire(Rule::Let, ctx, id);
+                }
+                return Ok(*subst_var(t2, &x, &y));
+            }
+            // the binding that forces a thunk evaluates it
+            t1 if lazy && !is_thunk(&x) => {
+                trace.fire(Rule::Delay, t1.0, t1.2);
+                Term::Let(x, Box::new(store.delay(t1)), t2)
+            }
+            _ => Term::Let(x, congruence(store, trace, "E-Let1", *t1)?, t2),
         },
         Term::Letc(x1, x2, term, body) => match &*term {
-            TermCtx(_, Term::Variable(x)) => match extract(&x)? {
-                TermCtx(_, Term::Compound(_, y1, y2)) => match (&*y1, &*y2) {
-                    (TermCtx(_, Term::Variable(y1)), TermCtx(_, Term::Variable(y2))) => {
-                        return Ok(*subst_var(subst_var(body, &x1, &y1), &x2, &y2))
+            TermCtx(_, Term::Variable(x), ..) => match extract(&x)? {
+                TermCtx(_, Term::Compound(_, y1, y2), ..) => match (&*y1, &*y2) {
+                    (TermCtx(_, Term::Variable(y1), ..), TermCtx(_, Term::Variable(y2), ..)) => {
+                        trace.fire(Rule::Letc, ctx, id);
+                        return Ok(*subst_var(subst_var(body, &x1, &y1), &x2, &y2));
                     }
-                    _ => return Err(err(format!("..."))),
+                    _ => return Err(err(ErrorCode::StuckTerm, format!("..."))),
                 },
-                _ => return Err(err(format!("Expect compound"))),
+                _ => return Err(err(ErrorCode::StuckTerm, format!("Expect compound"))),
             },
-            _ => Term::Letc(x1, x2, Box::new(one_step_eval_aux(store, *term)?), body),
+            _ => Term::Letc(x1, x2, congruence(store, trace, "E-Letc1", *term)?, body),
         },
         Term::Fix(t) => match *t {
-            TermCtx(ctx1, Term::Abstraction(q, f, ty, body)) => match store.extract(&f) {
-                Some(_) => return Ok(*body),
-                None => {
-                    let new_f = store.fresh_variable("%f");
-                    let body_var = store.fresh_variable("%f");
-                    let body_ctx = body.0;
-                    let new_body = subst_var(body, &f, &new_f);
-                    store.bindings.insert(body_var.clone(), *new_body);
-                    let new_body = TermCtx(body_ctx, Term::Variable(body_var));
-                    let fix_term = TermCtx(
-                        ctx,
-                        Term::Fix(Box::new(TermCtx(
-                            ctx1,
-                            Term::Abstraction(q, new_f.clone(), ty, Box::new(new_body.clone())),
-                        ))),
-                    );
-                    store.bindings.insert(new_f, fix_term);
-                    return Ok(new_body);
+            TermCtx(ctx1, Term::Abstraction(q, f, ty, body), id1, key1) => {
+                match store.extract(&f, ctx) {
+                    Some(_) => {
+                        trace.fire(Rule::FixRec, ctx, id);
+                        return Ok(*body);
+                    }
+                    None => {
+                        trace.fire(Rule::Fix, ctx, id);
+                        let new_f = store.fresh_variable("%f");
+                        let body_var = store.fresh_variable("%f");
+                        let (body_ctx, body_id) = (body.0, body.2);
+                        let new_body = subst_var(body, &f, &new_f);
+                        store.bindings.insert(body_var.clone(), *new_body);
+                        let new_body = TermCtx(body_ctx, Term::Variable(body_var), body_id, None);
+                        let fix_term = TermCtx(
+                            ctx,
+                            Term::Fix(Box::new(TermCtx(
+                                ctx1,
+                                Term::Abstraction(q, new_f.clone(), ty, Box::new(new_body.clone())),
+                                id1,
+                                key1,
+                            ))),
+                            id,
+                            key,
+                        );
+                        store.bindings.insert(new_f, fix_term);
+                        return Ok(new_body);
+                    }
                 }
-            },
-            _ => Term::Fix(Box::new(one_step_eval_aux(store, *t)?)),
+            }
+            _ => Term::Fix(congruence(store, trace, "E-Fix1", *t)?),
         },
         Term::Arith1(q, op, t) => match &*t {
-            TermCtx(_, Term::Variable(x)) => match extract(&x)? {
-                TermCtx(_, Term::Integer(_, v1)) => match op {
-                    ArithOp::IsZero => Term::Boolean(q, v1 == 0),
-                    _ => return Err(err(format!("Unknown op {:?}", op))),
+            TermCtx(_, Term::Variable(x), ..) => match extract(&x)? {
+                TermCtx(_, Term::Integer(_, v1), ..) => match op {
+                    ArithOp::IsZero => {
+                        trace.fire(Rule::IsZero, ctx, id);
+                        Term::Boolean(q, v1 == 0)
+                    }
+                    _ => return Err(err(ErrorCode::StuckTerm, format!("Unknown op {:?}", op))),
                 },
-                _ => return Err(err("Expect an Integer".to_string())),
+                _ => return Err(err(ErrorCode::StuckTerm, "Expect an Integer".to_string())),
             },
-            _ => Term::Arith1(q, op, Box::new(one_step_eval_aux(store, *t)?)),
+            _ => Term::Arith1(q, op, congruence(store, trace, "E-IsZero1", *t)?),
         },
         Term::Arith2(q, op, t1, t2) => match (&*t1, &*t2) {
-            (TermCtx(_, Term::Variable(x1)), TermCtx(_, Term::Variable(x2))) => {
+            (TermCtx(_, Term::Variable(x1), ..), TermCtx(_, Term::Variable(x2), ..)) => {
                 match (extract(&x1)?, extract(&x2)?) {
-                    (TermCtx(_, Term::Integer(_, v1)), TermCtx(_, Term::Integer(_, v2))) => {
-                        match op {
-                            ArithOp::Diff => Term::Integer(q, v1 - v2),
-                            _ => return Err(err(format!("Unknown op {:?}", op))),
+                    (
+                        TermCtx(_, Term::Integer(_, v1), ..),
+                        TermCtx(_, Term::Integer(_, v2), ..),
+                    ) => match op {
+                        ArithOp::Diff => {
+                            trace.fire(Rule::Diff, ctx, id);
+                            Term::Integer(q, v1 - v2)
                         }
-                    }
-                    _ => return Err(err(format!("Expect Integers"))),
+                        _ => return Err(err(ErrorCode::StuckTerm, format!("Unknown op {:?}", op))),
+                    },
+                    _ => return Err(err(ErrorCode::StuckTerm, format!("Expect Integers"))),
                 }
             }
-            (TermCtx(_, Term::Variable(..)), _) => {
-                Term::Arith2(q, op, t1, Box::new(one_step_eval_aux(store, *t2)?))
+            (TermCtx(_, Term::Variable(..), ..), _) => {
+                Term::Arith2(q, op, t1, congruence(store, trace, "E-Diff2", *t2)?)
             }
-            _ => Term::Arith2(q, op, Box::new(one_step_eval_aux(store, *t1)?), t2),
+            _ => Term::Arith2(q, op, congruence(store, trace, "E-Diff1", *t1)?, t2),
         },
+        Term::Error(_) => {
+            let message = "Cannot evaluate a malformed term".to_string();
+            return Err(err(ErrorCode::MalformedTerm, message));
+        }
+        // annotations do not matter at run time
+        Term::Ascription(t, _) => {
+            trace.fire(Rule::Ascribe, ctx, id);
+            return Ok(*t);
+        }
+        Term::Hole(name) => {
+            let message = format!("Cannot evaluate the hole ?{}", name.unwrap_or_default());
+            return Err(err(ErrorCode::UnfilledHole, message));
+        }
     };
-    Ok(TermCtx(ctx, term))
+    Ok(TermCtx(ctx, term, id, key))
+}
+
+/// A rule of the small-step semantics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Rule {
+    /// A variable steps to its value in the store, which a linear value leaves.
+    #[serde(rename = "E-Var")]
+    Var,
+    /// A literal or an abstraction is put in the store.
+    #[serde(rename = "E-Alloc")]
+    Alloc,
+    /// A pair of variables is put in the store.
+    #[serde(rename = "E-Pair")]
+    Pair,
+    #[serde(rename = "E-IfTrue")]
+    IfTrue,
+    #[serde(rename = "E-IfFalse")]
+    IfFalse,
+    #[serde(rename = "E-AppAbs")]
+    AppAbs,
+    /// A variable bound to a recursive function is replaced by its `fix`.
+    #[serde(rename = "E-Unfold")]
+    Unfold,
+    #[serde(rename = "E-Let")]
+    Let,
+    #[serde(rename = "E-Letc")]
+    Letc,
+    /// `fix` of an abstraction binds the recursive function in the store.
+    #[serde(rename = "E-Fix")]
+    Fix,
+    /// `fix` of a function that is already bound steps to its body.
+    #[serde(rename = "E-FixRec")]
+    FixRec,
+    #[serde(rename = "E-IsZero")]
+    IsZero,
+    #[serde(rename = "E-Diff")]
+    Diff,
+    #[serde(rename = "E-Ascribe")]
+    Ascribe,
+    /// Under a lazy strategy, an argument is bound to a thunk instead of being evaluated.
+    #[serde(rename = "E-Delay")]
+    Delay,
+    /// A variable bound to a thunk is replaced by what evaluates it, or by its memoized value.
+    #[serde(rename = "E-Force")]
+    Force,
+    /// A forced thunk gets its value, which is memoized under call-by-need, and for linear
+    /// values under call-by-name too.
+    #[serde(rename = "E-Memo")]
+    Memo,
+}
+
+impl Rule {
+    /// The name of the rule, e.g. `E-AppAbs`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Rule::Var => "E-Var",
+            Rule::Alloc => "E-Alloc",
+            Rule::Pair => "E-Pair",
+            Rule::IfTrue => "E-IfTrue",
+            Rule::IfFalse => "E-IfFalse",
+            Rule::AppAbs => "E-AppAbs",
+            Rule::Unfold => "E-Unfold",
+            Rule::Let => "E-Let",
+            Rule::Letc => "E-Letc",
+            Rule::Fix => "E-Fix",
+            Rule::FixRec => "E-FixRec",
+            Rule::IsZero => "E-IsZero",
+            Rule::Diff => "E-Diff",
+            Rule::Ascribe => "E-Ascribe",
+            Rule::Delay => "E-Delay",
+            Rule::Force => "E-Force",
+            Rule::Memo => "E-Memo",
+        }
+    }
+}
+
+/// How the bindings of the store changed in a step.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoreDiff {
+    pub added: Vec<String>,
+    /// Unrestricted bindings that are gone.
+    pub removed: Vec<String>,
+    /// Linear bindings that were used, and so left the store.
+    pub consumed: Vec<String>,
+}
+
+/// What happened in a step: the rule that fired, on which redex, and what it did to the store.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reduction {
+    pub rule: Rule,
+    /// The span of the redex.
+    pub redex: Context,
+    pub node: NodeId,
+    pub contraction: Contraction,
+    /// How the step of the whole term follows from the contraction, by congruence rules.
+    pub derivation: StepDerivation,
+    pub diff: StoreDiff,
+}
+
+/// A redex and the term it contracts to, formatted as source code.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Contraction {
+    pub redex: String,
+    pub contractum: String,
+}
+
+/// A state with the reduction that led to it, as sent to the playground.
+#[derive(Debug, Serialize)]
+pub struct Stepped {
+    #[serde(flatten)]
+    pub state: TermEval,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reduction: Option<Reduction>,
+}
+
+/// Records the redex while `one_step_eval_aux` looks for it, and with `record` the derivation
+/// of the step.
+#[derive(Default)]
+struct Trace {
+    record: bool,
+    fired: Option<(Rule, Context, NodeId)>,
+    /// The congruence rule of the last subterm stepped, for its parent to pick up.
+    congruence: Option<&'static str>,
+    derivation: Option<StepDerivation>,
+}
+
+impl Trace {
+    fn fire(&mut self, rule: Rule, redex: Context, node: NodeId) {
+        self.fired = Some((rule, redex, node));
+    }
 }
 
 pub(crate) fn one_step_eval(term_eval: TermEval) -> Result<TermEval, Error> {
@@ -254,11 +955,259 @@ pub(crate) fn one_step_eval(term_eval: TermEval) -> Result<TermEval, Error> {
     let term = if is_value(&term) {
         term
     } else {
-        one_step_eval_aux(&mut store, term)?
+        one_step_eval_aux(&mut store, &mut Trace::default(), term)?
     };
     Ok(TermEval { store, term })
 }
 
+/// Take a step, and tell what it did. A value takes no step, and gives no `Reduction`.
+pub(crate) fn one_step_eval_traced(
+    term_eval: TermEval,
+) -> Result<(TermEval, Option<Reduction>), Error> {
+    let TermEval { mut store, term } = term_eval;
+    if is_value(&term) {
+        return Ok((TermEval { store, term }, None));
+    }
+    // the names are enough: a binding never changes once it is made
+    let before: HashMap<String, Option<Qualifier>> = (store.bindings.iter())
+        .map(|(name, value)| (name.clone(), get_qualifier(value)))
+        .collect();
+    let mut trace = Trace {
+        record: true,
+        ..Default::default()
+    };
+    let term = one_step_eval_aux(&mut store, &mut trace, term)?;
+    let mut diff = StoreDiff::default();
+    for (name, qualifier) in &before {
+        if !store.bindings.contains_key(name) {
+            match qualifier {
+                Some(Qualifier::Linear) => diff.consumed.push(name.clone()),
+                _ => diff.removed.push(name.clone()),
+            }
+        }
+    }
+    diff.added = (store.bindings.keys())
+        .filter(|name| !before.contains_key(*name))
+        .cloned()
+        .collect();
+    diff.added.sort();
+    diff.removed.sort();
+    diff.consumed.sort();
+    // every step that does not fail fires a rule, and records its derivation
+    let (Some((rule, redex, node)), Some(derivation)) = (trace.fired, trace.derivation) else {
+        return Err(Error::InternalError {
+            code: ErrorCode::Internal,
+            message: "a step fired no rule".to_owned(),
+        });
+    };
+    let mut leaf = &derivation;
+    while let Some(premise) = leaf.premises.first() {
+        leaf = premise;
+    }
+    let contraction = Contraction {
+        redex: leaf.term.clone(),
+        contractum: leaf.result.clone(),
+    };
+    let reduction = Reduction {
+        rule,
+        redex,
+        node,
+        contraction,
+        derivation,
+        diff,
+    };
+    Ok((TermEval { store, term }, Some(reduction)))
+}
+
+/// When `run` gives up, and how often it collects the garbage in the store. Each limit is
+/// optional; `Limits::default()` keeps a run in the browser short.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Limits {
+    /// The number of steps.
+    pub max_steps: Option<usize>,
+    /// The number of bindings in the store.
+    pub max_store: Option<usize>,
+    /// The wall-clock time, in milliseconds.
+    pub max_millis: Option<u64>,
+    /// Collect the store every this many steps. Without it the store only grows.
+    pub gc_interval: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_steps: Some(100_000),
+            max_store: Some(10_000),
+            max_millis: Some(5_000),
+            gc_interval: Some(1_000),
+        }
+    }
+}
+
+/// The limit that a run ran into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Limit {
+    Steps,
+    Store,
+    Time,
+}
+
+/// How a run ended, after `steps` steps.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Outcome {
+    /// The term reduced to a value. The store holds what it refers to.
+    Value { result: TermEval, steps: usize },
+    /// No rule applies, e.g. at a hole or a malformed term.
+    Stuck { error: Error, steps: usize },
+    /// The term reduced to a value in a monitored run, but linear values were never consumed.
+    /// There is an error for each of them.
+    Leaked {
+        errors: Vec<Error>,
+        result: TermEval,
+        steps: usize,
+    },
+    /// A limit was reached first, e.g. by a `fix` that never returns. `result` is where the run
+    /// stopped.
+    OutOfFuel {
+        limit: Limit,
+        result: TermEval,
+        steps: usize,
+    },
+}
+
+/// How a run of the abstract machine or of the VM ended, after `steps` transitions. Neither
+/// keeps terms around, so a value is read back as an `Answer`.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum MachineOutcome {
+    Value { answer: Answer, steps: usize },
+    Stuck { error: Box<Error>, steps: usize },
+    OutOfFuel { limit: Limit, steps: usize },
+}
+
+/// How many steps go by between two looks at the clock, which costs far more than a step.
+const CLOCK_INTERVAL: usize = 1024;
+
+impl Limits {
+    /// The limit reached after `steps` steps with `store` bindings, in a run that started at
+    /// `start` by `now_millis`. The time is only checked every `CLOCK_INTERVAL` steps.
+    pub(crate) fn reached(&self, steps: usize, store: usize, start: f64) -> Option<Limit> {
+        if self.max_steps.is_some_and(|max| steps >= max) {
+            Some(Limit::Steps)
+        } else if self.max_store.is_some_and(|max| store > max) {
+            Some(Limit::Store)
+        } else if steps.is_multiple_of(CLOCK_INTERVAL)
+            && self
+                .max_millis
+                .is_some_and(|max| now_millis() - start > max as f64)
+        {
+            Some(Limit::Time)
+        } else {
+            None
+        }
+    }
+}
+
+/// Milliseconds since some fixed point in time. `std::time::Instant` is not available in the
+/// browser.
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn now_millis() -> f64 {
+    js_sys::Date::now()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn now_millis() -> f64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.as_secs_f64() * 1000.0
+}
+
+/// Evaluate a term with an empty store until it is a value, or until one of the `limits` is
+/// reached.
+pub fn run(term: TermCtx, limits: &Limits) -> Outcome {
+    run_eval(TermEval::from(term), limits)
+}
+
+/// Like `run`, with a monitored store: a linear value used twice gets the run stuck with
+/// `LinearUsedAgain`, and the linear values left over at the end are reported as leaks.
+pub fn run_monitored(term: TermCtx, limits: &Limits) -> Outcome {
+    let term_eval = TermEval {
+        store: Store::new_monitored(),
+        term,
+    };
+    run_eval(term_eval, limits)
+}
+
+/// Like `run`, from a given state, e.g. with a store made by `Store::with_strategy`.
+pub fn run_eval(mut term_eval: TermEval, limits: &Limits) -> Outcome {
+    let start = now_millis();
+    let mut steps = 0;
+    loop {
+        // the value is read back with the thunks it refers to forced, one at a time
+        let unforced = (is_value(&term_eval.term))
+            .then(|| term_eval.store.unforced(&term_eval.term))
+            .flatten();
+        if let Some(name) = unforced {
+            if let Some(Thunk::Delayed(thunk)) = term_eval.store.thunks.remove(&name) {
+                let TermCtx(ctx, value, id, key) = term_eval.term;
+                let value = TermCtx(ctx, value, id, None);
+                let force = Term::Let(name, Box::new(thunk), Box::new(value));
+                term_eval.term = TermCtx(ctx, force, id, key);
+            }
+        } else if is_value(&term_eval.term) {
+            let errors: Vec<_> = term_eval
+                .store
+                .leaks(&term_eval.term)
+                .into_iter()
+                .map(|(name, tag)| {
+                    Error::from(EvaluateError {
+                        code: ErrorCode::LinearLeaked,
+                        message: format!("Linear value {} is never consumed", name),
+                        start: tag.origin.start,
+                        end: tag.origin.end,
+                        location: None,
+                        node: tag.node,
+                        labels: vec![],
+                    })
+                })
+                .collect();
+            if !errors.is_empty() {
+                return Outcome::Leaked {
+                    errors,
+                    result: term_eval,
+                    steps,
+                };
+            }
+            return Outcome::Value {
+                result: term_eval,
+                steps,
+            };
+        }
+        if limits
+            .gc_interval
+            .is_some_and(|every| every > 0 && steps > 0 && steps % every == 0)
+        {
+            term_eval.collect_garbage();
+        }
+        let store = term_eval.store.bindings.len();
+        if let Some(limit) = limits.reached(steps, store, start) {
+            return Outcome::OutOfFuel {
+                limit,
+                result: term_eval,
+                steps,
+            };
+        }
+        term_eval = match one_step_eval(term_eval) {
+            Ok(term_eval) => term_eval,
+            Err(error) => return Outcome::Stuck { error, steps },
+        };
+        steps += 1;
+    }
+}
+
 #[cfg(test)]
 mod test {
     use crate::formatter::{self, TermFormatter};
@@ -372,4 +1321,283 @@ mod test {
             );
         }
     }
+
+    #[test]
+    fn test_eval_ascription() {
+        let store = Store::new_empty();
+        let mut formatter = TermFormatter::new(formatter::DEFAULT_LINE_WIDTH);
+        let input = "let x: int = 5 in let f = |y: int| (diff(x, y) : int) in f(2)";
+        let term = parse_program(input).unwrap();
+        let mut result = TermEval { store, term };
+        for _ in 0..20 {
+            result = one_step_eval(result).unwrap();
+            println!("{}", formatter.format_termctx(&result.term));
+        }
+        assert!(matches!(result.term.1, Term::Integer(_, 3)));
+    }
+
+    #[test]
+    fn test_run() {
+        let input = std::fs::read_to_string("examples/fact.txt").unwrap();
+        let term = parse_program(&input).unwrap();
+        let outcome = run(term, &Limits::default());
+        println!("{:?}", outcome);
+        match outcome {
+            Outcome::Value { result, .. } => assert!(matches!(result.term.1, Term::Integer(_, 6))),
+            outcome => panic!("expect a value, given {:?}", outcome),
+        }
+
+        let input = "let f = fix(|ff: int -> int| |x: int| ff(x)) in f(1)";
+        let limits = Limits {
+            max_steps: Some(1000),
+            ..Default::default()
+        };
+        let outcome = run(parse_program(input).unwrap(), &limits);
+        assert!(matches!(
+            outcome,
+            Outcome::OutOfFuel {
+                limit: Limit::Steps,
+                steps: 1000,
+                ..
+            }
+        ));
+
+        // the clock is looked at every `CLOCK_INTERVAL` steps
+        let no_time = Limits {
+            max_steps: None,
+            max_millis: Some(0),
+            ..Default::default()
+        };
+        let start = now_millis() - 1.0;
+        assert_eq!(no_time.reached(1, 0, start), None);
+        assert_eq!(no_time.reached(CLOCK_INTERVAL, 0, start), Some(Limit::Time));
+
+        let outcome = run(parse_program("diff(1, ?x)").unwrap(), &limits);
+        match outcome {
+            Outcome::Stuck { error, .. } => assert_eq!(error.code(), ErrorCode::UnfilledHole),
+            outcome => panic!("expect to get stuck, given {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn test_run_monitored() {
+        let input = std::fs::read_to_string("examples/io_error1.txt").unwrap();
+        let outcome = run_monitored(parse_program(&input).unwrap(), &Limits::default());
+        println!("{:?}", outcome);
+        match outcome {
+            Outcome::Leaked { errors, .. } => {
+                assert_eq!(errors.len(), 1);
+                assert_eq!(errors[0].code(), ErrorCode::LinearLeaked);
+                assert!(matches!(&errors[0], Error::EvaluateError(error)
+                    if &input[error.start..error.end] == "$0"));
+            }
+            outcome => panic!("expect a leak, given {:?}", outcome),
+        }
+
+        let input = "let h = $1 in let _ = iszero(h) in iszero(h)";
+        let outcome = run_monitored(parse_program(input).unwrap(), &Limits::default());
+        match outcome {
+            Outcome::Stuck { error, .. } => {
+                println!("{}", error);
+                assert_eq!(error.code(), ErrorCode::LinearUsedAgain);
+                assert!(matches!(&error, Error::EvaluateError(e) if e.labels.len() == 2));
+            }
+            outcome => panic!("expect to get stuck, given {:?}", outcome),
+        }
+
+        // the result itself is not a leak, nor is what it refers to
+        let input = std::fs::read_to_string("examples/http.txt").unwrap();
+        let outcome = run_monitored(parse_program(&input).unwrap(), &Limits::default());
+        assert!(matches!(outcome, Outcome::Value { .. }), "{:?}", outcome);
+        let outcome = run_monitored(parse_program("$<$1, 2>").unwrap(), &Limits::default());
+        assert!(matches!(outcome, Outcome::Value { .. }), "{:?}", outcome);
+    }
+    #[test]
+    fn test_collect_garbage() {
+        let input =
+            "let f = fix(|ff: int -> int| |x: int| if iszero(x) {0} else {ff(diff(x, 1))}) in \
+                     f(4000)";
+        let no_gc = Limits {
+            gc_interval: None,
+            ..Default::default()
+        };
+        let outcome = run(parse_program(input).unwrap(), &no_gc);
+        assert!(matches!(
+            outcome,
+            Outcome::OutOfFuel {
+                limit: Limit::Store,
+                ..
+            }
+        ));
+        let outcome = run(parse_program(input).unwrap(), &Limits::default());
+        match outcome {
+            Outcome::Value { result, steps } => {
+                let stats = result.gc_stats();
+                println!("{} steps, {:?}", steps, stats);
+                assert_eq!(result.answer(), Some(Answer::Integer(Qualifier::Nop, 0)));
+                assert_eq!(stats.collections, steps / 1000);
+                assert!(stats.live < 20);
+            }
+            outcome => panic!("expect a value, given {:?}", outcome),
+        }
+
+        // unreachable linear bindings are kept and reported
+        let mut term_eval: TermEval = parse_program("let h = $1 in let y = 2 in 3")
+            .unwrap()
+            .into();
+        for _ in 0..4 {
+            term_eval = one_step_eval(term_eval).unwrap();
+        }
+        println!("{:?}", term_eval);
+        let stats = term_eval.collect_garbage().clone();
+        println!("{:?}", stats);
+        assert_eq!(stats.freed, 1);
+        assert_eq!(stats.unreachable_linear.len(), 1);
+        assert_eq!(term_eval.store.bindings.len(), 1);
+    }
+    #[test]
+    fn test_reductions() {
+        let input = "let h = $1 in iszero(h)";
+        let mut term_eval: TermEval = parse_program(input).unwrap().into();
+        let mut reductions = vec![];
+        while let (next, Some(reduction)) = one_step_eval_traced(term_eval).unwrap() {
+            println!("{:?}", reduction);
+            term_eval = next;
+            reductions.push(reduction);
+        }
+        let rules: Vec<_> = reductions.iter().map(|r| r.rule).collect();
+        assert_eq!(rules, [Rule::Alloc, Rule::Let, Rule::IsZero]);
+        let Reduction { redex, diff, .. } = &reductions[0];
+        assert_eq!(&input[redex.start..redex.end], "$1");
+        assert_eq!(diff.added, ["%x1"]);
+        let Reduction { redex, diff, .. } = &reductions[2];
+        assert_eq!(&input[redex.start..redex.end], "iszero(h)");
+        assert_eq!(diff.consumed, ["%x1"]);
+        assert!(diff.added.is_empty() && diff.removed.is_empty());
+        // the redex of the first step is found by a congruence rule
+        let Reduction {
+            contraction,
+            derivation,
+            ..
+        } = &reductions[0];
+        assert_eq!(contraction.redex, "$1");
+        assert_eq!(contraction.contractum, "%x1");
+        assert_eq!(derivation.rule, "E-Let1");
+        assert_eq!(derivation.term, input);
+        assert_eq!(derivation.result, "let h = %x1 in iszero(h)");
+        assert_eq!(derivation.premises[0].rule, "E-Alloc");
+        let latex = derivation.to_latex();
+        println!("{}", latex);
+        assert!(
+            latex.starts_with("\\inferrule*[right=E-Let1]\n  {\n    \\inferrule*[right=E-Alloc]")
+        );
+        assert!(latex.contains("\\longrightarrow"));
+        assert!(reductions[2].derivation.premises.is_empty());
+        let json = serde_json::to_string(&reductions[1]).unwrap();
+        println!("{}", json);
+        assert!(json.contains("\"rule\":\"E-Let\""));
+    }
+
+    fn rules(input: &str, strategy: Strategy) -> Result<(Option<Answer>, Vec<Rule>), Error> {
+        let store = Store::new_monitored().with_strategy(strategy);
+        let mut term_eval = TermEval::new(parse_program(input).unwrap(), store);
+        let mut rules = vec![];
+        loop {
+            let (next, reduction) = one_step_eval_traced(term_eval)?;
+            term_eval = next;
+            match reduction {
+                Some(reduction) => rules.push(reduction.rule),
+                None => break,
+            }
+        }
+        println!("{:?}: {:?}", strategy, rules);
+        Ok((term_eval.answer(), rules))
+    }
+
+    #[test]
+    fn test_strategies() {
+        let int = |v| Some(Answer::Integer(Qualifier::Nop, v));
+        // an argument that is never used is never evaluated
+        let input = "let f = fix(|ff: int -> int| |x: int| ff(x)) in (|y: int| 1)(f(0))";
+        let limits = Limits {
+            max_steps: Some(1000),
+            ..Default::default()
+        };
+        let outcome = run(parse_program(input).unwrap(), &limits);
+        assert!(matches!(outcome, Outcome::OutOfFuel { .. }));
+        for strategy in [Strategy::CallByName, Strategy::CallByNeed] {
+            let store = Store::new_empty().with_strategy(strategy);
+            let term_eval = TermEval::new(parse_program(input).unwrap(), store);
+            match run_eval(term_eval, &limits) {
+                Outcome::Value { result, .. } => assert_eq!(result.answer(), int(1)),
+                outcome => panic!("expect a value, given {:?}", outcome),
+            }
+        }
+
+        // call-by-name evaluates `x` at each use, call-by-need once
+        let input = "let x = diff(10, 1) in diff(x, x)";
+        let count = |rules: &[Rule]| rules.iter().filter(|r| **r == Rule::Diff).count();
+        let (answer, by_value) = rules(input, Strategy::CallByValue).unwrap();
+        assert_eq!(answer, int(0));
+        let (answer, by_name) = rules(input, Strategy::CallByName).unwrap();
+        assert_eq!(answer, int(0));
+        let (answer, by_need) = rules(input, Strategy::CallByNeed).unwrap();
+        assert_eq!(answer, int(0));
+        assert_eq!(
+            (count(&by_value), count(&by_name), count(&by_need)),
+            (2, 3, 2)
+        );
+        assert!(by_need.contains(&Rule::Delay) && by_need.contains(&Rule::Memo));
+        assert!(!by_value.contains(&Rule::Force));
+
+        // a linear value is forced once: evaluating `y` again would consume `h` twice, so
+        // even call-by-name memoizes it
+        let input = "let h = $0 in let y = iszero(h) in if y { if y {1} else {2} } else {3}";
+        assert!(crate::typing::type_check(&parse_program(input).unwrap()).is_ok());
+        for strategy in [
+            Strategy::CallByValue,
+            Strategy::CallByName,
+            Strategy::CallByNeed,
+        ] {
+            assert_eq!(rules(input, strategy).unwrap().0, int(1));
+        }
+
+        // the same answer; a pair, though, may be left with components that are not forced
+        let input = std::fs::read_to_string("examples/fact.txt").unwrap();
+        for strategy in [Strategy::CallByName, Strategy::CallByNeed] {
+            assert_eq!(rules(&input, strategy).unwrap().0, int(6));
+        }
+        let input = "(|x: int| <x, x>)(diff(2, 1))";
+        let (answer, _) = rules(input, Strategy::CallByNeed).unwrap();
+        assert_eq!(answer, None);
+        // which `run_eval` forces before it gives the value
+        let pair = Some(Answer::Pair(
+            Qualifier::Nop,
+            Box::new(Answer::Integer(Qualifier::Nop, 1)),
+            Box::new(Answer::Integer(Qualifier::Nop, 1)),
+        ));
+        for strategy in [Strategy::CallByName, Strategy::CallByNeed] {
+            let store = Store::new_monitored().with_strategy(strategy);
+            let term_eval = TermEval::new(parse_program(input).unwrap(), store);
+            match run_eval(term_eval, &Limits::default()) {
+                Outcome::Value { result, .. } => assert_eq!(result.answer(), pair),
+                outcome => panic!("expect a value, given {:?}", outcome),
+            }
+        }
+
+        // a linear value that is never forced is a leak all the same
+        let input = "let h = $0 in 1";
+        for strategy in [Strategy::CallByName, Strategy::CallByNeed] {
+            let store = Store::new_monitored().with_strategy(strategy);
+            let term_eval = TermEval::new(parse_program(input).unwrap(), store);
+            match run_eval(term_eval, &Limits::default()) {
+                Outcome::Leaked { errors, .. } => {
+                    assert_eq!(errors.len(), 1);
+                    assert!(matches!(&errors[0], Error::EvaluateError(error)
+                        if &input[error.start..error.end] == "$0"));
+                }
+                outcome => panic!("expect a leak, given {:?}", outcome),
+            }
+        }
+    }
 }
@@ -0,0 +1,436 @@
+use crate::error::ErrorCode;
+use serde::Serialize;
+
+/// The long-form explanation of a diagnostic, with a minimal program that triggers it and the
+/// same program fixed.
+#[derive(Debug, Serialize)]
+pub struct Explanation {
+    pub code: ErrorCode,
+    pub title: &'static str,
+    pub explanation: &'static str,
+    pub failing: Option<&'static str>,
+    pub fixed: Option<&'static str>,
+}
+
+/// Look up the explanation of an error code such as `E0003`.
+pub fn explain(code: &str) -> Option<Explanation> {
+    ErrorCode::from_code(code).map(explain_code)
+}
+
+pub fn explain_code(code: ErrorCode) -> Explanation {
+    let (title, explanation, failing, fixed) = match code {
+        ErrorCode::SyntaxError => (
+            "syntax error",
+            "The program does not follow the grammar of the language. The message lists the \
+             tokens that would have been accepted at the reported position. There are no infix \
+             operators: arithmetic is written with the primitives `diff(a, b)` and `iszero(a)`.",
+            Some("let x = 3 - 1 in x"),
+            Some("let x = diff(3, 1) in x"),
+        ),
+        ErrorCode::UndefinedVariable => (
+            "undefined variable",
+            "A variable is used outside the scope of any `let`, `let <_, _>` or function \
+             parameter that binds it. Bind it before use, or check the spelling.",
+            Some("let f = |x: int| y in f(1)"),
+            Some("let y = 2 in let f = |x: int| y in f(1)"),
+        ),
+        ErrorCode::LinearNotConsumed => (
+            "linear variable not consumed",
+            "A variable of linear type (`$T`) must be used exactly once before it goes out of \
+             scope. Forgetting to use it is like forgetting to close a file handle: the \
+             resource it stands for leaks. Pass it to a function that consumes it.",
+            Some(
+                "let close = |handle: $int| iszero(handle) in\n\
+                 let h = $0 in\n\
+                 123 // forget to close the handle",
+            ),
+            Some(
+                "let close = |handle: $int| iszero(handle) in\n\
+                 let h = $0 in\n\
+                 let _ = close(h) in\n\
+                 123",
+            ),
+        ),
+        ErrorCode::LinearConsumedTwice => (
+            "linear variable consumed twice",
+            "A variable of linear type (`$T`) may be used only once. After its first use it is \
+             gone, so a second use is like using a file handle after closing it. The \
+             diagnostic points at the binding and at the first use.",
+            Some(
+                "let close = |handle: $int| iszero(handle) in\n\
+                 let h = $0 in\n\
+                 let _ = close(h) in\n\
+                 close(h) // double close",
+            ),
+            Some(
+                "let close = |handle: $int| iszero(handle) in\n\
+                 let h = $0 in\n\
+                 close(h)",
+            ),
+        ),
+        ErrorCode::BranchConsumption => (
+            "linear variables consumed differently in different branches",
+            "Only one branch of an `if` runs, so both branches must consume the same linear \
+             variables. Otherwise the variable would either leak or be used twice, depending \
+             on the condition. The diagnostic lists which variables each branch consumes.",
+            Some(
+                "let close = |handle: $int| iszero(handle) in\n\
+                 let h = $0 in\n\
+                 if true { close(h) } else { false }",
+            ),
+            Some(
+                "let close = |handle: $int| iszero(handle) in\n\
+                 let h = $0 in\n\
+                 if true { close(h) } else { let _ = close(h) in false }",
+            ),
+        ),
+        ErrorCode::LinearCapturedByFunction => (
+            "linear variable captured by an unrestricted function",
+            "An unrestricted function may be called any number of times, so its body must not \
+             use a linear variable from the enclosing scope: each call would use it again. \
+             Make the function linear with `$` so that it can be called only once.",
+            Some(
+                "let h = $0 in\n\
+                 let f = |x: int| diff(h, x) in\n\
+                 f(1)",
+            ),
+            Some(
+                "let h = $0 in\n\
+                 let f = $|x: int| diff(h, x) in\n\
+                 f(1)",
+            ),
+        ),
+        ErrorCode::LinearCapturedByPair => (
+            "linear variable stored in an unrestricted pair",
+            "An unrestricted pair may be copied freely, so it must not contain linear values. \
+             Make the pair linear with `$`.",
+            Some("let h = $0 in let <a, b> = <h, 1> in diff(a, b)"),
+            Some("let h = $0 in let <a, b> = $<h, 1> in diff(a, b)"),
+        ),
+        ErrorCode::ArgumentMismatch => (
+            "argument type mismatch",
+            "The argument does not have the parameter type of the function. Note that `int` \
+             and `$int` are different types: a linear parameter only accepts a linear value.",
+            Some("let f = |x: $int| iszero(x) in f(1)"),
+            Some("let f = |x: $int| iszero(x) in f($1)"),
+        ),
+        ErrorCode::NotAFunction => (
+            "not a function",
+            "Only functions can be applied to an argument.",
+            Some("let x = 1 in x(2)"),
+            Some("let x = |y: int| y in x(2)"),
+        ),
+        ErrorCode::BranchTypeMismatch => (
+            "branch types differ",
+            "Both branches of an `if` must have the same type, qualifier included.",
+            Some("if true { 1 } else { false }"),
+            Some("if true { 1 } else { 0 }"),
+        ),
+        ErrorCode::ConditionNotBoolean => (
+            "condition is not a boolean",
+            "The condition of an `if` must be of type `bool` or `$bool`.",
+            Some("if 1 { 2 } else { 3 }"),
+            Some("if iszero(1) { 2 } else { 3 }"),
+        ),
+        ErrorCode::InvalidRecursion => (
+            "invalid recursion",
+            "`fix` takes a function of type `T->T`, whose parameter stands for the recursive \
+             function itself. Usually `T` is a function type, so the argument of `fix` has two \
+             parameters: the recursive function and its actual argument.",
+            Some("fix |x: int| true"),
+            Some("fix |f: int->int| |x: int| x"),
+        ),
+        ErrorCode::LinearRecursion => (
+            "linear recursion",
+            "A recursive function may call itself any number of times, so neither it nor its \
+             argument of `fix` may be linear.",
+            Some("fix $|f: int->int| f"),
+            Some("fix |f: int->int| f"),
+        ),
+        ErrorCode::NotAPair => (
+            "not a pair",
+            "Only pairs can be destructured with `let <x, y> = ...`.",
+            Some("let <a, b> = 1 in a"),
+            Some("let <a, b> = <1, 2> in a"),
+        ),
+        ErrorCode::DuplicatePatternVariable => (
+            "duplicate variable in pair pattern",
+            "The two variables of `let <x, y> = ...` must have different names.",
+            Some("let <a, a> = <1, 2> in a"),
+            Some("let <a, b> = <1, 2> in a"),
+        ),
+        ErrorCode::NotAnInteger => (
+            "not an integer",
+            "The arithmetic primitives `diff` and `iszero` only work on integers.",
+            Some("iszero(true)"),
+            Some("iszero(0)"),
+        ),
+        ErrorCode::LinearResult => (
+            "program of linear type",
+            "Nothing consumes the value of the whole program, so it must not be linear.",
+            Some("let h = $0 in h"),
+            Some("let h = $0 in diff(h, 0)"),
+        ),
+        ErrorCode::IncompatibleQualifiers => (
+            "incompatible qualifiers",
+            "A type can be qualified with `$` only once.",
+            Some("|x: $($bool)| x"),
+            Some("|x: $bool| x"),
+        ),
+        ErrorCode::MissingAnnotation => (
+            "missing type annotation",
+            "The type of a function parameter cannot be inferred here, so it must be written \
+             down as in `|x: int| ...`. It can be left out where the function is checked \
+             against a known function type: as the argument of a function, in \
+             `let f: int->int = |x| ...`, or in `(|x| ... : int->int)`.",
+            Some("(|x| x)(1)"),
+            Some("(|x: int| x)(1)"),
+        ),
+        ErrorCode::AnnotationMismatch => (
+            "type annotation mismatch",
+            "The term does not have the type written in its annotation, `let x: T = ...` or \
+             `(e : T)`. Note that `int` and `$int` are different types.",
+            Some("let x: $int = 1 in diff(x, 1)"),
+            Some("let x: $int = $1 in diff(x, 1)"),
+        ),
+        ErrorCode::UnboundStoreVariable => (
+            "value not found in the store",
+            "Evaluation looked up a value that is not in the store. Linear values are removed \
+             from the store when they are used, so this happens when an ill-typed program uses \
+             a linear value twice. The type checker reports such programs before evaluation.",
+            Some(
+                "let close = |handle: $int| iszero(handle) in\n\
+                 let h = $0 in\n\
+                 let _ = close(h) in\n\
+                 close(h)",
+            ),
+            Some(
+                "let close = |handle: $int| iszero(handle) in\n\
+                 let h = $0 in\n\
+                 close(h)",
+            ),
+        ),
+        ErrorCode::StuckTerm => (
+            "evaluation is stuck",
+            "The term cannot take a step because a value has the wrong shape, e.g. an integer \
+             used as the condition of an `if`. Well-typed programs never get stuck.",
+            Some("if 1 { 2 } else { 3 }"),
+            Some("if iszero(1) { 2 } else { 3 }"),
+        ),
+        ErrorCode::MalformedTerm => (
+            "malformed term",
+            "The program contains a region that could not be parsed, and evaluation reached \
+             it. Fix the syntax errors first.",
+            Some("let x = in 1"),
+            Some("let x = 0 in 1"),
+        ),
+        ErrorCode::UnfilledHole => (
+            "unfilled hole",
+            "Evaluation reached a hole `?` or `?name`, a part of the program that is still to \
+             be written. Holes are accepted by the type checker, which reports what they \
+             should be filled with, but they cannot run.",
+            Some("iszero(?n)"),
+            Some("iszero(0)"),
+        ),
+        ErrorCode::LinearUsedAgain => (
+            "linear value used again",
+            "A monitored run used a linear value that had already been consumed. The labels \
+             show where the value was created and where it was consumed the first time. The \
+             type checker reports such programs as E0004 before evaluation.",
+            Some(
+                "let close = |handle: $int| iszero(handle) in\n\
+                 let h = $0 in\n\
+                 let _ = close(h) in\n\
+                 close(h)",
+            ),
+            Some(
+                "let close = |handle: $int| iszero(handle) in\n\
+                 let h = $0 in\n\
+                 close(h)",
+            ),
+        ),
+        ErrorCode::LinearLeaked => (
+            "linear value leaked",
+            "A monitored run ended with a linear value that was never consumed, like a file \
+             handle that is never closed. The error points at where the value was created. The \
+             type checker reports such programs as E0003 before evaluation.",
+            Some("let h = $0 in 1"),
+            Some("let h = $0 in let _ = iszero(h) in 1"),
+        ),
+        ErrorCode::UnknownBinding => (
+            "no step created the binding",
+            "The debugger was asked to go to the step that created a binding of the store, \
+             such as `%x3`, but none of the steps taken so far created it. Bindings are named \
+             as evaluation creates them: step further, or check the name.",
+            None,
+            None,
+        ),
+        ErrorCode::UnusedVariable => (
+            "unused variable",
+            "An unrestricted variable is bound but never used, which is often a typo or a \
+             leftover. Name it `_`, or start its name with `_`, if this is intentional.",
+            Some("let x = 1 in let y = 2 in x"),
+            Some("let x = 1 in let _y = 2 in x"),
+        ),
+        ErrorCode::ShadowedLinear => (
+            "linear variable shadowed before it is consumed",
+            "A new variable hides a linear variable of the same name that has not been \
+             consumed yet. The hidden variable cannot be used until the scope of the new one \
+             ends. When that scope lasts as long as the hidden variable's own, the hidden \
+             variable leaks, and the type checker rejects the program with E0003 at its \
+             binding; this warning points at the binding that hides it. When the hidden \
+             variable is consumed after the inner scope, the program is well typed, but the \
+             reused name is still easy to misread. Reusing a name is fine once the old \
+             variable is consumed, as in `let h = write(h) in ...`.",
+            Some(
+                "let close = |handle: $int| iszero(handle) in\n\
+                 let h = $0 in\n\
+                 let h = $1 in\n\
+                 close(h)",
+            ),
+            Some(
+                "let close = |handle: $int| iszero(handle) in\n\
+                 let h = $0 in\n\
+                 let h = $diff(h, 1) in\n\
+                 close(h)",
+            ),
+        ),
+        ErrorCode::RedundantQualifier => (
+            "redundant qualifier",
+            "A `$` on a literal makes it linear, which only matters if the value is passed on. \
+             The operands of `diff` and `iszero`, the condition of an `if` and the pair of a \
+             `let <x, y> = ...` are consumed right away, so the `$` has no effect there.",
+            Some("iszero($0)"),
+            Some("iszero(0)"),
+        ),
+        ErrorCode::DeepLetNesting => (
+            "deeply nested let",
+            "A `let` is nested in the bound value of another `let` more levels deep than the \
+             configured `max_let_depth`. Such code reads inside out. Bind the inner values \
+             first, one after the other, as a sequence of `let`s.",
+            Some("let a = let b = let c = let d = 1 in d in c in b in a"),
+            Some("let d = 1 in let c = d in let b = c in let a = b in a"),
+        ),
+        ErrorCode::DiscardedLinearResult => (
+            "linear result bound to _",
+            "The value bound to `_` is linear, so it still has to be consumed, but `_` cannot \
+             be used. The type checker rejects the program; bind the value to a name and pass \
+             it to a function that consumes it.",
+            Some("let open = |x: int| $diff(x, 0) in let _ = open(1) in 0"),
+            Some("let open = |x: int| $diff(x, 0) in let h = open(1) in iszero(h)"),
+        ),
+        ErrorCode::LargeClosureCapture => (
+            "closure captures a large environment",
+            "An unrestricted function uses more variables of the enclosing scope than the \
+             configured `max_captures`. They are all kept alive as long as the function is. \
+             Pass them as arguments, or group them in pairs.",
+            Some(
+                "let a = 1 in let b = 2 in let c = 3 in let d = 4 in let e = 5 in\n\
+                 let f = |x: int| diff(diff(diff(a, b), diff(c, d)), diff(e, x)) in\n\
+                 f(0)",
+            ),
+            Some(
+                "let a = 1 in let b = 2 in let c = 3 in let d = 4 in let e = 5 in\n\
+                 let f = |x: int| diff(diff(a, b), x) in\n\
+                 diff(f(0), diff(diff(c, d), e))",
+            ),
+        ),
+        ErrorCode::TypedHole => (
+            "typed hole",
+            "A hole `?` or `?name` stands for a part of the program that is still to be \
+             written. The diagnostic shows the type the hole should have, when it is known \
+             from the context, the linear variables that the missing code has to consume, and \
+             the unrestricted variables that it may use.",
+            Some("let h = $0 in ?goal"),
+            Some("let h = $0 in iszero(h)"),
+        ),
+        ErrorCode::Internal => (
+            "internal error",
+            "Something went wrong inside the tool itself, not in the program. Please report \
+             it together with the program that caused it.",
+            None,
+            None,
+        ),
+        ErrorCode::Custom(_) => (
+            "house rule",
+            "The diagnostic comes from a lint rule that was added to the linter, not from the \
+             language itself. The rule is documented where it is defined.",
+            None,
+            None,
+        ),
+    };
+    Explanation {
+        code,
+        title,
+        explanation,
+        failing,
+        fixed,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::Error;
+    use crate::eval::{one_step_eval, run_monitored, Limits, Outcome, TermEval};
+    use crate::lint::lint;
+    use crate::syntax::{parse_program, parse_program_recovering};
+    use crate::typing::type_check;
+
+    fn check(input: &str) -> Result<(), Error> {
+        type_check(&parse_program(input)?).map(|_| ())
+    }
+
+    fn lint_first(input: &str) -> Result<(), Error> {
+        match lint(&parse_program(input)?).into_iter().next() {
+            Some(warning) => Err(warning),
+            None => Ok(()),
+        }
+    }
+
+    fn evaluate(input: &str) -> Result<(), Error> {
+        let (term, _) = parse_program_recovering(input);
+        let mut term_eval: TermEval = term.into();
+        for _ in 0..1000 {
+            term_eval = one_step_eval(term_eval)?;
+        }
+        Ok(())
+    }
+
+    fn monitor(input: &str) -> Result<(), Error> {
+        match run_monitored(parse_program(input)?, &Limits::default()) {
+            Outcome::Stuck { error, .. } => Err(error),
+            Outcome::Leaked { mut errors, .. } => Err(errors.remove(0)),
+            _ => Ok(()),
+        }
+    }
+
+    #[test]
+    fn test_examples() {
+        for code in ErrorCode::ALL {
+            let explanation = explain(code.as_str()).unwrap();
+            println!("{}: {}", code, explanation.title);
+            let run = match &code.as_str()[..3] {
+                _ if [ErrorCode::LinearUsedAgain, ErrorCode::LinearLeaked].contains(&code) => {
+                    monitor
+                }
+                "E01" => evaluate,
+                "E02" => lint_first,
+                _ => check,
+            };
+            if let Some(failing) = explanation.failing {
+                assert_eq!(run(failing).unwrap_err().code(), code);
+            }
+            if let Some(fixed) = explanation.fixed {
+                run(fixed).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_unknown_code() {
+        assert!(explain("E9999").is_none());
+        let json = serde_json::to_string(&explain("E0003").unwrap()).unwrap();
+        assert!(json.contains("\"code\":\"E0003\""));
+    }
+}
@@ -31,7 +31,7 @@ impl TermFormatter {
     }
 
     fn write_termctx(&mut self, t: &TermCtx, need_bracket: bool) -> String {
-        let TermCtx(_, t) = t;
+        let TermCtx(_, t, ..) = t;
         self.write_term(&t, need_bracket)
     }
 
@@ -68,6 +68,7 @@ impl TermFormatter {
         let s = match t {
             Pretype::Boolean => "bool".to_owned(),
             Pretype::Integer => "int".to_owned(),
+            Pretype::Unknown => "_".to_owned(),
             Pretype::Function(t1, t2) => {
                 // the arrow is right-associated.
                 let left_is_arrow = match **t1 {
@@ -113,16 +114,22 @@ impl TermFormatter {
             Term::Variable(v) => v.to_string(),
             Term::Boolean(q, b) => format!("{}{}", self.write_qualifer(q), b),
             Term::Integer(q, i) => format!("{}{}", self.write_qualifer(q), i),
+            Term::Error(s) => s.to_string(),
+            Term::Hole(name) => format!("?{}", name.as_deref().unwrap_or_default()),
+            Term::Ascription(t1, ty) => {
+                let s1 = self.write_termctx(t1, false);
+                format!("({} : {})", s1, self.format_type(ty))
+            }
             Term::Compound(..) => self.write_term_compound(t, need_bracket),
             Term::Let(..) => self.write_term_let(t, need_bracket),
             Term::Letc(..) => self.write_term_letc(t, need_bracket),
             Term::Arith1(..) | Term::Arith2(..) => self.write_term_arith(t, need_bracket),
             Term::Application(t1, t2) => {
                 let need_backet_on_s1 = match **t1 {
-                    TermCtx(_, Term::Abstraction(..)) => true,
-                    TermCtx(_, Term::Fix(..)) => true,
-                    TermCtx(_, Term::Let(..)) => true,
-                    TermCtx(_, Term::Letc(..)) => true,
+                    TermCtx(_, Term::Abstraction(..), ..) => true,
+                    TermCtx(_, Term::Fix(..), ..) => true,
+                    TermCtx(_, Term::Let(..), ..) => true,
+                    TermCtx(_, Term::Letc(..), ..) => true,
                     _ => false,
                 };
                 let s1 = self.write_termctx(t1, need_backet_on_s1);
@@ -229,6 +236,13 @@ impl TermFormatter {
 
     fn write_term_let(&mut self, t: &Term, need_bracket: bool) -> String {
         if let Term::Let(v, t1, t2) = t {
+            // `let x = (e : T) in ...` is written `let x: T = e in ...`
+            let (v, t1) = match &**t1 {
+                TermCtx(_, Term::Ascription(t1, ty), ..) => {
+                    (format!("{}: {}", v, self.format_type(ty)), &**t1)
+                }
+                t1 => (v.to_owned(), t1),
+            };
             self.indent();
             let s1 = self.write_termctx(t1, false);
             self.dedent();
@@ -1,10 +1,18 @@
 use wasm_bindgen::prelude::*;
 
+pub mod derivation;
 pub mod error;
 pub mod eval;
+pub mod explain;
 pub mod formatter;
+pub mod lint;
+pub mod machine;
+pub mod render;
+pub mod session;
+pub mod source_map;
 pub mod syntax;
 pub mod typing;
+pub mod vm;
 
 #[wasm_bindgen]
 extern "C" {
@@ -23,18 +31,29 @@ pub fn greet(name: &str) {
 #[wasm_bindgen]
 pub fn term_lint(
     program: &str,
+    config: Option<String>,
     cb_ok: &js_sys::Function,
     cb_err: &js_sys::Function,
 ) -> Result<JsValue, JsValue> {
     let this = JsValue::NULL;
-    let tree = syntax::parse_program(program);
-    let result = match tree {
-        Ok(tree) => tree,
-        Err(error) => {
-            let error = serde_json::to_string(&error).unwrap();
-            return cb_err.call1(&this, &JsValue::from_str(&error));
-        }
+    // `config` is a `lint::LintConfig` in JSON; without it every rule runs at its default level.
+    let config = match config {
+        Some(config) => serde_json::from_str::<lint::LintConfig>(&config)
+            .map_err(|e| JsValue::from_str(&format!("invalid lint config: {}", e)))?,
+        None => lint::LintConfig::default(),
     };
+    // Report every syntax error, then carry on with the recovered term. Lint warnings go
+    // through `cb_err` as well, with their `severity`.
+    let (result, errors) = syntax::parse_program_recovering(program);
+    let map = source_map::SourceMap::new(program);
+    let warnings = lint::LintRunner::new(config)
+        .run(&result)
+        .into_iter()
+        .map(|w| w.with_source_map(&map));
+    for error in errors.into_iter().chain(warnings) {
+        let error = serde_json::to_string(&error).unwrap();
+        cb_err.call1(&this, &JsValue::from_str(&error))?;
+    }
     let result = formatter::format_termctx(&result);
     let result = JsValue::from_str(&result);
     let this = JsValue::NULL;
@@ -48,43 +67,60 @@ pub fn typing(
     cb_err: &js_sys::Function,
 ) -> Result<JsValue, JsValue> {
     let this = JsValue::NULL;
-    let term = match serde_json::from_str::<syntax::TermCtx>(program) {
-        Ok(term) => term,
-        Err(_error) => match syntax::parse_program(program) {
-            Ok(term) => term,
-            Err(_error) => {
-                let error = error::Error::InternalError {
-                    message: "Failed to parse program for typing".to_string(),
-                };
-                let error = serde_json::to_string(&error).unwrap();
-                let error = JsValue::from_str(&error);
-                return cb_err.call1(&this, &error);
-            }
-        },
+    // Syntax errors are reported by `term_lint`; a partially parsed program still gets typed.
+    let (term, is_recovered, is_source) = match serde_json::from_str::<syntax::TermCtx>(program) {
+        Ok(mut term) => {
+            // the ids in the JSON, if any, are not trusted to be unique
+            term.assign_ids(0);
+            (term, false, false)
+        }
+        Err(_error) => {
+            let (term, errors) = syntax::parse_program_recovering(program);
+            (term, !errors.is_empty(), true)
+        }
+    };
+    let check = if is_recovered {
+        typing::type_check_recovering
+    } else {
+        typing::type_check_live
     };
-    let _result = match typing::type_check(&term) {
+    let _result = match check(&term) {
         Ok(result) => result,
         Err(error) => {
+            let error = if is_source {
+                error.with_source_map(&source_map::SourceMap::new(program))
+            } else {
+                error
+            };
             let error = serde_json::to_string(&error).unwrap();
             return cb_err.call1(&this, &JsValue::from_str(&error));
         }
     };
-    // Reformat the code and do the typing again.
-    let term_s = formatter::format_termctx(&term);
-    let term = syntax::parse_program(&term_s).unwrap();
-    let result = typing::type_check(&term);
-    let result = match result {
+    // Reformat the code and do the typing again. A recovered program may not survive the
+    // round trip through the formatter, so its types are shown on the original source.
+    let (term_s, term) = if is_recovered {
+        (program.to_owned(), term)
+    } else {
+        let term_s = formatter::format_termctx(&term);
+        let term = syntax::parse_program(&term_s).unwrap();
+        (term_s, term)
+    };
+    let result = check(&term);
+    let (result, live) = match result {
         Ok(result) => result,
         Err(error) => {
+            let error = error.with_source_map(&source_map::SourceMap::new(&term_s));
             let error = serde_json::to_string(&error).unwrap();
             return cb_err.call1(&this, &JsValue::from_str(&error));
         }
     };
-    let result = typing::convert_hashmap_to_vec(&result, &term_s);
+    // Each tip also shows the linear variables live before and after its term.
+    let result = typing::type_tips(&term, &result, Some(&live), &term_s);
     let result = match serde_json::to_string(&result) {
         Ok(result) => result,
         Err(error) => {
             let error = error::Error::InternalError {
+                code: error::ErrorCode::Internal,
                 message: error.to_string(),
             };
             let error = serde_json::to_string(&error).unwrap();
@@ -95,6 +131,39 @@ pub fn typing(
     return cb_ok.call1(&this, &result);
 }
 
+/// Build the typing derivation of a program.
+///
+/// # Arguments
+/// * `program` - The source code.
+/// * `format` - `json` for the `derivation::Derivation` as JSON, or `latex` for `mathpartir`
+/// source.
+#[wasm_bindgen]
+pub fn typing_derivation(
+    program: &str,
+    format: &str,
+    cb_ok: &js_sys::Function,
+    cb_err: &js_sys::Function,
+) -> Result<JsValue, JsValue> {
+    let this = JsValue::NULL;
+    let map = source_map::SourceMap::new(program);
+    let result = syntax::parse_program(program)
+        .and_then(|term| typing::type_derivation(&term))
+        .and_then(|derivation| match format {
+            "latex" => Ok(derivation.to_latex()),
+            _ => serde_json::to_string(&derivation).map_err(|e| error::Error::InternalError {
+                code: error::ErrorCode::Internal,
+                message: e.to_string(),
+            }),
+        });
+    match result {
+        Ok(result) => cb_ok.call1(&this, &JsValue::from_str(&result)),
+        Err(error) => {
+            let error = serde_json::to_string(&error.with_source_map(&map)).unwrap();
+            cb_err.call1(&this, &JsValue::from_str(&error))
+        }
+    }
+}
+
 /// Evaluate a program.
 ///
 /// # Arguments
@@ -108,14 +177,20 @@ pub fn one_step_eval(
     cb_err: &js_sys::Function,
 ) -> Result<JsValue, JsValue> {
     let this = JsValue::NULL;
+    // Spans can be located only if the program is given as source code.
+    let mut source_map = None;
     let term_eval = match serde_json::from_str::<eval::TermEval>(program) {
         Ok(term_eval) => term_eval,
         Err(_error) => match serde_json::from_str::<syntax::TermCtx>(program) {
             Ok(term) => Into::<eval::TermEval>::into(term),
             Err(_error) => match syntax::parse_program(program) {
-                Ok(term) => Into::<eval::TermEval>::into(term),
+                Ok(term) => {
+                    source_map = Some(source_map::SourceMap::new(program));
+                    Into::<eval::TermEval>::into(term)
+                }
                 Err(_error) => {
                     let error = error::Error::InternalError {
+                        code: error::ErrorCode::Internal,
                         message: "Failed to parse program for evaluation".to_string(),
                     };
                     let error = serde_json::to_string(&error).unwrap();
@@ -125,9 +200,14 @@ pub fn one_step_eval(
             },
         },
     };
-    let result = match eval::one_step_eval(term_eval) {
-        Ok(result) => result,
+    // the reduction that was made goes along with the new state
+    let result = match eval::one_step_eval_traced(term_eval) {
+        Ok((state, reduction)) => eval::Stepped { state, reduction },
         Err(error) => {
+            let error = match &source_map {
+                Some(map) => error.with_source_map(map),
+                None => error,
+            };
             let error = serde_json::to_string(&error).unwrap();
             let error = JsValue::from_str(&error);
             return cb_err.call1(&this, &error);
@@ -138,6 +218,255 @@ pub fn one_step_eval(
     cb_ok.call1(&this, &result)
 }
 
+/// Evaluate a program until it is a value, it gets stuck, or it runs out of fuel. The
+/// `eval::Outcome` is passed to `cb_ok` as JSON.
+///
+/// # Arguments
+/// * `program` - The source code.
+/// * `limits` - The `eval::Limits` in JSON; without it the defaults apply.
+/// * `monitor` - Whether to run with `eval::run_monitored`, which reports linear values that
+/// are used twice or never.
+/// * `strategy` - The `eval::Strategy`, e.g. `call-by-need`; without it, call-by-value.
+#[wasm_bindgen]
+pub fn eval_run(
+    program: &str,
+    limits: Option<String>,
+    monitor: bool,
+    strategy: Option<String>,
+    cb_ok: &js_sys::Function,
+    cb_err: &js_sys::Function,
+) -> Result<JsValue, JsValue> {
+    let this = JsValue::NULL;
+    let limits = match limits {
+        Some(limits) => serde_json::from_str::<eval::Limits>(&limits)
+            .map_err(|e| JsValue::from_str(&format!("invalid limits: {}", e)))?,
+        None => eval::Limits::default(),
+    };
+    let strategy = parse_strategy(strategy)?;
+    let map = source_map::SourceMap::new(program);
+    let term = match syntax::parse_program(program) {
+        Ok(term) => term,
+        Err(error) => {
+            let error = serde_json::to_string(&error.with_source_map(&map)).unwrap();
+            return cb_err.call1(&this, &JsValue::from_str(&error));
+        }
+    };
+    let store = if monitor {
+        eval::Store::new_monitored()
+    } else {
+        eval::Store::new_empty()
+    };
+    let term_eval = eval::TermEval::new(term, store.with_strategy(strategy));
+    let outcome = eval::run_eval(term_eval, &limits);
+    let result = match outcome {
+        eval::Outcome::Stuck { error, steps } => eval::Outcome::Stuck {
+            error: error.with_source_map(&map),
+            steps,
+        },
+        eval::Outcome::Leaked {
+            errors,
+            result,
+            steps,
+        } => eval::Outcome::Leaked {
+            errors: errors
+                .into_iter()
+                .map(|e| e.with_source_map(&map))
+                .collect(),
+            result,
+            steps,
+        },
+        outcome => outcome,
+    };
+    let result = serde_json::to_string(&result).unwrap();
+    cb_ok.call1(&this, &JsValue::from_str(&result))
+}
+
+/// Collect the garbage in the store of a program being evaluated. The `eval::TermEval` with
+/// the smaller store, and its `eval::GcStats`, is passed to `cb_ok` as JSON.
+///
+/// # Arguments
+/// * `term_eval` - The `eval::TermEval` in JSON, as given by `one_step_eval`.
+#[wasm_bindgen]
+pub fn eval_collect(
+    term_eval: &str,
+    cb_ok: &js_sys::Function,
+    cb_err: &js_sys::Function,
+) -> Result<JsValue, JsValue> {
+    let this = JsValue::NULL;
+    let mut term_eval = match serde_json::from_str::<eval::TermEval>(term_eval) {
+        Ok(term_eval) => term_eval,
+        Err(e) => {
+            let error = error::Error::InternalError {
+                code: error::ErrorCode::Internal,
+                message: format!("invalid program state: {}", e),
+            };
+            let error = serde_json::to_string(&error).unwrap();
+            return cb_err.call1(&this, &JsValue::from_str(&error));
+        }
+    };
+    term_eval.collect_garbage();
+    let result = serde_json::to_string(&term_eval).unwrap();
+    cb_ok.call1(&this, &JsValue::from_str(&result))
+}
+
+/// An evaluation in the playground that can step back and forth, see `session::Session`. Each
+/// method passes the `session::Snapshot` of the state it ends at to `cb_ok` as JSON, or the
+/// error to `cb_err`.
+#[wasm_bindgen]
+pub struct EvalSession {
+    session: session::Session,
+    source: String,
+}
+
+#[wasm_bindgen]
+impl EvalSession {
+    /// Start evaluating a program, given as source code, with the `eval::Strategy` named by
+    /// `strategy`, call-by-value by default. Syntax errors are thrown as JSON.
+    #[wasm_bindgen(constructor)]
+    pub fn new(program: &str, strategy: Option<String>) -> Result<EvalSession, JsValue> {
+        let store = eval::Store::new_empty().with_strategy(parse_strategy(strategy)?);
+        match syntax::parse_program(program) {
+            Ok(term) => Ok(EvalSession {
+                session: eval::TermEval::new(term, store).into(),
+                source: program.to_owned(),
+            }),
+            Err(error) => {
+                let error = error.with_source_map(&source_map::SourceMap::new(program));
+                Err(JsValue::from_str(&serde_json::to_string(&error).unwrap()))
+            }
+        }
+    }
+
+    pub fn step(
+        &mut self,
+        cb_ok: &js_sys::Function,
+        cb_err: &js_sys::Function,
+    ) -> Result<JsValue, JsValue> {
+        let result = self.session.step().map(|_| ());
+        self.reply(result, cb_ok, cb_err)
+    }
+
+    pub fn step_back(
+        &mut self,
+        cb_ok: &js_sys::Function,
+        cb_err: &js_sys::Function,
+    ) -> Result<JsValue, JsValue> {
+        let result = self.session.step_back().map(|_| ());
+        self.reply(result, cb_ok, cb_err)
+    }
+
+    pub fn goto(
+        &mut self,
+        step: usize,
+        cb_ok: &js_sys::Function,
+        cb_err: &js_sys::Function,
+    ) -> Result<JsValue, JsValue> {
+        let result = self.session.goto(step).map(|_| ());
+        self.reply(result, cb_ok, cb_err)
+    }
+
+    /// Go to the step that created the store binding `name`, e.g. `%x3`.
+    pub fn goto_binding(
+        &mut self,
+        name: &str,
+        cb_ok: &js_sys::Function,
+        cb_err: &js_sys::Function,
+    ) -> Result<JsValue, JsValue> {
+        let result = self.session.goto_binding(name).map(|_| ());
+        self.reply(result, cb_ok, cb_err)
+    }
+
+    /// Step until a breakpoint is hit, the term is a value, or one of the `eval::Limits` in
+    /// the JSON `limits` is reached; see `Session::run`. `cb_ok` gets the `session::Paused`,
+    /// which tells why it stopped.
+    pub fn run(
+        &mut self,
+        limits: Option<String>,
+        cb_ok: &js_sys::Function,
+        cb_err: &js_sys::Function,
+    ) -> Result<JsValue, JsValue> {
+        let limits = match limits {
+            Some(limits) => serde_json::from_str::<eval::Limits>(&limits)
+                .map_err(|e| JsValue::from_str(&format!("invalid limits: {}", e)))?,
+            None => eval::Limits::default(),
+        };
+        match self.session.run(&limits) {
+            Ok(stop) => {
+                let paused = session::Paused {
+                    stop,
+                    snapshot: self.session.snapshot(),
+                };
+                let paused = serde_json::to_string(&paused).unwrap();
+                cb_ok.call1(&JsValue::NULL, &JsValue::from_str(&paused))
+            }
+            Err(error) => self.reply(Err(error), cb_ok, cb_err),
+        }
+    }
+
+    /// Add a `session::Breakpoint`, given as JSON, e.g. `{"Binding": "%x3"}` or
+    /// `{"Span": {"start": 10, "end": 20}}` with byte offsets in the program. Gives the index
+    /// of the breakpoint; an invalid one is thrown.
+    pub fn add_breakpoint(&mut self, breakpoint: &str) -> Result<usize, JsValue> {
+        let breakpoint = serde_json::from_str::<session::Breakpoint>(breakpoint)
+            .map_err(|e| JsValue::from_str(&format!("invalid breakpoint: {}", e)))?;
+        Ok(self.session.add_breakpoint(breakpoint))
+    }
+
+    /// Remove the breakpoint at `index`; the ones after it move down. Whether there was one.
+    pub fn remove_breakpoint(&mut self, index: usize) -> bool {
+        self.session.remove_breakpoint(index).is_some()
+    }
+
+    /// Show the value of the store binding `name` in every snapshot from now on.
+    pub fn watch(&mut self, name: &str) {
+        self.session.watch(name);
+    }
+
+    pub fn unwatch(&mut self, name: &str) {
+        self.session.unwatch(name);
+    }
+
+    /// Collect the garbage in the store of the current state; see `Session::collect_garbage`.
+    pub fn collect_garbage(
+        &mut self,
+        cb_ok: &js_sys::Function,
+        cb_err: &js_sys::Function,
+    ) -> Result<JsValue, JsValue> {
+        self.session.collect_garbage();
+        self.reply(Ok(()), cb_ok, cb_err)
+    }
+}
+
+fn parse_strategy(strategy: Option<String>) -> Result<eval::Strategy, JsValue> {
+    match strategy {
+        Some(name) => serde_json::from_value(serde_json::Value::String(name))
+            .map_err(|e| JsValue::from_str(&format!("invalid strategy: {}", e))),
+        None => Ok(eval::Strategy::default()),
+    }
+}
+
+impl EvalSession {
+    fn reply(
+        &self,
+        result: Result<(), error::Error>,
+        cb_ok: &js_sys::Function,
+        cb_err: &js_sys::Function,
+    ) -> Result<JsValue, JsValue> {
+        let this = JsValue::NULL;
+        match result {
+            Ok(()) => {
+                let snapshot = serde_json::to_string(&self.session.snapshot()).unwrap();
+                cb_ok.call1(&this, &JsValue::from_str(&snapshot))
+            }
+            Err(error) => {
+                let error = error.with_source_map(&source_map::SourceMap::new(&self.source));
+                let error = serde_json::to_string(&error).unwrap();
+                cb_err.call1(&this, &JsValue::from_str(&error))
+            }
+        }
+    }
+}
+
 /// Prettify the term
 ///
 /// # Arguments
@@ -153,16 +482,18 @@ pub fn prettify(
     let this = JsValue::NULL;
     let term_ctx = match serde_json::from_str::<syntax::TermCtx>(term_ctx) {
         Ok(term_ctx) => term_ctx,
-        Err(_error) => match syntax::parse_program(term_ctx) {
-            Ok(term) => Into::<syntax::TermCtx>::into(term),
-            Err(_error) => {
+        Err(_error) => match syntax::parse_program_recovering(term_ctx) {
+            // Nothing could be recovered, so there is nothing to prettify.
+            (syntax::TermCtx(_, syntax::Term::Error(_), ..), _) => {
                 let error = error::Error::InternalError {
+                    code: error::ErrorCode::Internal,
                     message: "Failed to parse term for prettifying".to_string(),
                 };
                 let error = serde_json::to_string(&error).unwrap();
                 let error = JsValue::from_str(&error);
                 return cb_err.call1(&this, &error);
             }
+            (term, _errors) => term,
         },
     };
     let line_width = line_width.unwrap_or(formatter::DEFAULT_LINE_WIDTH);
@@ -171,3 +502,29 @@ pub fn prettify(
     let result = JsValue::from_str(&result);
     cb_ok.call1(&this, &result)
 }
+
+/// Explain an error code.
+///
+/// # Arguments
+/// * `code` - The error code, e.g. `E0003`. The explanation is passed to `cb_ok` as JSON.
+#[wasm_bindgen]
+pub fn explain(
+    code: &str,
+    cb_ok: &js_sys::Function,
+    cb_err: &js_sys::Function,
+) -> Result<JsValue, JsValue> {
+    let this = JsValue::NULL;
+    let result = match explain::explain(code) {
+        Some(result) => result,
+        None => {
+            let error = error::Error::InternalError {
+                code: error::ErrorCode::Internal,
+                message: format!("Unknown error code {}", code),
+            };
+            let error = serde_json::to_string(&error).unwrap();
+            return cb_err.call1(&this, &JsValue::from_str(&error));
+        }
+    };
+    let result = serde_json::to_string(&result).unwrap();
+    cb_ok.call1(&this, &JsValue::from_str(&result))
+}
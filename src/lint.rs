@@ -0,0 +1,685 @@
+use crate::error::{Edit, Error, ErrorCode, Label, LintError, Severity, Suggestion};
+use crate::syntax::{Context, NodeId, Pretype, Qualifier, Term, TermCtx, Type};
+use crate::typing;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// The types of terms by node, as computed by `typing::type_check`.
+pub type TypeMap = HashMap<NodeId, Type>;
+
+/// A lint rule. Rules report warnings; `LintRunner` decides whether a rule runs at all and
+/// what severity its findings get.
+pub trait Lint {
+    /// The name of the rule in `LintConfig`, e.g. `unused-variable`.
+    fn name(&self) -> &'static str;
+
+    fn code(&self) -> ErrorCode;
+
+    /// The rules the lint reports on, by name and code. A lint that finds several kinds of
+    /// problem in one walk has a rule for each, and each rule has its own level.
+    fn rules(&self) -> Vec<(&'static str, ErrorCode)> {
+        vec![(self.name(), self.code())]
+    }
+
+    fn default_level(&self) -> Level {
+        Level::Warning
+    }
+
+    /// Look for problems in the program. `types` has the types of the terms that were checked
+    /// before the first type error, if there is one.
+    fn check(&self, term_ctx: &TermCtx, types: &TypeMap) -> Vec<Error>;
+}
+
+/// Whether a rule runs, and with what severity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Allow,
+    Warning,
+    Error,
+}
+
+/// The lint configuration, e.g. `{"rules": {"deep-let-nesting": "error"}, "max_let_depth": 2}`.
+/// Rules that are not mentioned run at their default level.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LintConfig {
+    #[serde(default)]
+    pub rules: HashMap<String, Level>,
+    /// How many `let`s may be nested in the bound value of one another.
+    #[serde(default = "default_max_let_depth")]
+    pub max_let_depth: usize,
+    /// How many variables an unrestricted closure may capture.
+    #[serde(default = "default_max_captures")]
+    pub max_captures: usize,
+}
+
+fn default_max_let_depth() -> usize {
+    3
+}
+
+fn default_max_captures() -> usize {
+    4
+}
+
+impl Default for LintConfig {
+    fn default() -> Self {
+        LintConfig {
+            rules: HashMap::new(),
+            max_let_depth: default_max_let_depth(),
+            max_captures: default_max_captures(),
+        }
+    }
+}
+
+/// A variable in scope while linting. The type is the one the type checker found, if it got
+/// that far; nothing is reported about variables of unknown type.
+struct Binding {
+    name: String,
+    ty: Option<Type>,
+    site: Context,
+    used: bool,
+}
+
+struct ScopeWalker<'a> {
+    types: &'a TypeMap,
+    scope: Vec<Binding>,
+    warnings: Vec<Error>,
+}
+
+fn warning(code: ErrorCode, span: &Context, message: String) -> Error {
+    Error::from(LintError {
+        code,
+        severity: Severity::Warning,
+        message,
+        start: span.start,
+        end: span.end,
+        location: None,
+        labels: vec![],
+        suggestions: vec![],
+    })
+}
+
+fn is_linear(ty: &Option<Type>) -> bool {
+    matches!(ty, Some(Type(Qualifier::Linear, _)))
+}
+
+impl ScopeWalker<'_> {
+    fn bind(&mut self, name: &str, ty: Option<Type>, site: Context) {
+        let live = self
+            .scope
+            .iter()
+            .rev()
+            .find(|b| b.name == name)
+            .filter(|b| is_linear(&b.ty) && !b.used);
+        if let Some(shadowed) = live {
+            let message = format!(
+                "{} shadows the linear variable {}, which is not consumed yet",
+                name, name
+            );
+            let label = Label {
+                message: format!("{} is bound here", name),
+                start: shadowed.site.start,
+                end: shadowed.site.end,
+                location: None,
+            };
+            let error = warning(ErrorCode::ShadowedLinear, &site, message);
+            self.warnings.push(error.with_labels(vec![label]));
+        }
+        self.scope.push(Binding {
+            name: name.to_owned(),
+            ty,
+            site,
+            used: false,
+        });
+    }
+
+    /// Leave the scope of the innermost binding. Names starting with `_` are meant to be
+    /// unused; linear variables that are not used are a type error already.
+    fn unbind(&mut self) {
+        let binding = self.scope.pop().unwrap();
+        if !binding.used
+            && !binding.name.starts_with('_')
+            && matches!(binding.ty, Some(Type(Qualifier::Nop, _)))
+        {
+            let message = format!("unused variable: {}", binding.name);
+            let error = warning(ErrorCode::UnusedVariable, &binding.site, message);
+            self.warnings.push(error);
+        }
+    }
+
+    /// A `$` on a literal that is consumed on the spot makes no difference.
+    fn check_consumed(&mut self, term_ctx: &TermCtx) {
+        let TermCtx(span, term, ..) = term_ctx;
+        if let Term::Boolean(Qualifier::Linear, _) | Term::Integer(Qualifier::Linear, _) = term {
+            let message = "redundant $: the value is consumed right away".to_string();
+            let suggestion = Suggestion {
+                message: "remove the $".to_string(),
+                edits: vec![Edit {
+                    start: span.start,
+                    end: span.start + 1,
+                    replacement: String::new(),
+                    location: None,
+                }],
+            };
+            let error = warning(ErrorCode::RedundantQualifier, span, message);
+            self.warnings.push(error.with_suggestion(Some(suggestion)));
+        }
+    }
+
+    /// Walk the term, binding variables to their types in `types`.
+    fn lint(&mut self, term_ctx: &TermCtx) {
+        let TermCtx(_, term, ..) = term_ctx;
+        let span = &term_ctx.key_span();
+        match term {
+            Term::Variable(x) => {
+                if let Some(binding) = self.scope.iter_mut().rev().find(|b| b.name == *x) {
+                    binding.used = true;
+                }
+            }
+            Term::Conditional(t1, t2, t3) => {
+                self.check_consumed(t1);
+                self.lint(t1);
+                self.lint(t2);
+                self.lint(t3);
+            }
+            Term::Abstraction(_, x, _, body) => {
+                let ty = match type_of(term_ctx, self.types) {
+                    Some(Type(_, Pretype::Function(ty1, _))) => Some(*ty1),
+                    _ => None,
+                };
+                self.bind(x, ty, *span);
+                self.lint(body);
+                self.unbind();
+            }
+            Term::Let(x, t1, t2) => {
+                self.lint(t1);
+                self.bind(x, type_of(t1, self.types), *span);
+                self.lint(t2);
+                self.unbind();
+            }
+            Term::Letc(x1, x2, t1, t2) => {
+                self.check_consumed(t1);
+                self.lint(t1);
+                let (ty1, ty2) = match type_of(t1, self.types) {
+                    Some(Type(_, Pretype::Compound(ty1, ty2))) => (Some(*ty1), Some(*ty2)),
+                    _ => (None, None),
+                };
+                self.bind(x1, ty1, *span);
+                self.bind(x2, ty2, *span);
+                self.lint(t2);
+                self.unbind();
+                self.unbind();
+            }
+            Term::Arith1(_, _, t) => {
+                self.check_consumed(t);
+                self.lint(t);
+            }
+            Term::Arith2(_, _, t1, t2) => {
+                self.check_consumed(t1);
+                self.check_consumed(t2);
+                self.lint(t1);
+                self.lint(t2);
+            }
+            // the code that fills the hole may use anything in scope
+            Term::Hole(_) => {
+                for binding in &mut self.scope {
+                    binding.used = true;
+                }
+            }
+            _ => {
+                for child in term.children() {
+                    self.lint(child);
+                }
+            }
+        }
+    }
+}
+
+/// The rules that come out of one walk over the scopes: unused unrestricted variables, linear
+/// variables shadowed before they are consumed, and redundant `$` qualifiers. The name and
+/// code of the lint are those of the first rule.
+struct ScopeLint;
+
+const SCOPE_RULES: [(&str, ErrorCode); 3] = [
+    ("unused-variable", ErrorCode::UnusedVariable),
+    ("shadowed-linear", ErrorCode::ShadowedLinear),
+    ("redundant-qualifier", ErrorCode::RedundantQualifier),
+];
+
+impl Lint for ScopeLint {
+    fn name(&self) -> &'static str {
+        SCOPE_RULES[0].0
+    }
+
+    fn code(&self) -> ErrorCode {
+        SCOPE_RULES[0].1
+    }
+
+    fn rules(&self) -> Vec<(&'static str, ErrorCode)> {
+        SCOPE_RULES.to_vec()
+    }
+
+    fn check(&self, term_ctx: &TermCtx, types: &TypeMap) -> Vec<Error> {
+        let mut walker = ScopeWalker {
+            types,
+            scope: vec![],
+            warnings: vec![],
+        };
+        walker.lint(term_ctx);
+        walker.warnings
+    }
+}
+
+/// `let`s nested in the bound value of other `let`s, deeper than `max_depth`. A `let` in the
+/// body of another one is a sequence rather than nesting, so it does not count.
+pub struct DeepLetNesting {
+    pub max_depth: usize,
+}
+
+impl DeepLetNesting {
+    fn walk(&self, term_ctx: &TermCtx, depth: usize, warnings: &mut Vec<Error>) {
+        let TermCtx(_, term, ..) = term_ctx;
+        match term {
+            Term::Let(_, t1, t2) | Term::Letc(_, _, t1, t2) => {
+                if depth + 1 > self.max_depth {
+                    let message = format!(
+                        "let is nested {} levels deep, more than {}",
+                        depth + 1,
+                        self.max_depth
+                    );
+                    let span = &term_ctx.key_span();
+                    warnings.push(warning(ErrorCode::DeepLetNesting, span, message));
+                    // report the outermost offending `let` only
+                    return;
+                }
+                self.walk(t1, depth + 1, warnings);
+                self.walk(t2, depth, warnings);
+            }
+            _ => {
+                for child in term.children() {
+                    self.walk(child, depth, warnings);
+                }
+            }
+        }
+    }
+}
+
+impl Lint for DeepLetNesting {
+    fn name(&self) -> &'static str {
+        "deep-let-nesting"
+    }
+
+    fn code(&self) -> ErrorCode {
+        ErrorCode::DeepLetNesting
+    }
+
+    fn check(&self, term_ctx: &TermCtx, _types: &TypeMap) -> Vec<Error> {
+        let mut warnings = vec![];
+        self.walk(term_ctx, 0, &mut warnings);
+        warnings
+    }
+}
+
+/// The type of a term in the type map.
+fn type_of(term_ctx: &TermCtx, types: &TypeMap) -> Option<Type> {
+    types.get(&term_ctx.2).cloned()
+}
+
+/// `let _ = e in ...` where `e` is linear: the result has to be consumed, and naming it `_`
+/// suggests that it was meant to be thrown away.
+pub struct DiscardedLinearResult;
+
+impl Lint for DiscardedLinearResult {
+    fn name(&self) -> &'static str {
+        "discarded-linear-result"
+    }
+
+    fn code(&self) -> ErrorCode {
+        ErrorCode::DiscardedLinearResult
+    }
+
+    fn check(&self, term_ctx: &TermCtx, types: &TypeMap) -> Vec<Error> {
+        let mut warnings = vec![];
+        let mut stack = vec![term_ctx];
+        while let Some(t @ TermCtx(_, term, ..)) = stack.pop() {
+            if let Term::Let(x, t1, _) = term {
+                if let (true, Some(ty @ Type(Qualifier::Linear, _))) =
+                    (x == "_", type_of(t1, types))
+                {
+                    let message = format!(
+                        "the linear result of type {} is bound to _ and cannot be discarded",
+                        typing::format_type(&ty)
+                    );
+                    let span = &t.key_span();
+                    warnings.push(warning(ErrorCode::DiscardedLinearResult, span, message));
+                }
+            }
+            stack.extend(term.children());
+        }
+        warnings
+    }
+}
+
+/// Unrestricted closures that capture more than `max_captures` variables from their
+/// environment, all of which are kept alive as long as the closure is.
+pub struct LargeClosureCapture {
+    pub max_captures: usize,
+}
+
+impl Lint for LargeClosureCapture {
+    fn name(&self) -> &'static str {
+        "large-closure-capture"
+    }
+
+    fn code(&self) -> ErrorCode {
+        ErrorCode::LargeClosureCapture
+    }
+
+    fn check(&self, term_ctx: &TermCtx, _types: &TypeMap) -> Vec<Error> {
+        let mut warnings = vec![];
+        let mut stack = vec![term_ctx];
+        while let Some(t) = stack.pop() {
+            if let TermCtx(_, Term::Abstraction(Qualifier::Nop, ..), ..) = t {
+                let free = t.free_variables();
+                if free.len() > self.max_captures {
+                    let captured: Vec<&str> = free.iter().map(|x| x.as_str()).collect();
+                    let message = format!(
+                        "closure captures {} variables, more than {}: {}",
+                        free.len(),
+                        self.max_captures,
+                        captured.join(", ")
+                    );
+                    let span = &t.key_span();
+                    warnings.push(warning(ErrorCode::LargeClosureCapture, span, message));
+                }
+            }
+            stack.extend(t.1.children());
+        }
+        warnings
+    }
+}
+
+/// Holes left in the program, with what they can be filled with.
+pub struct TypedHole;
+
+impl Lint for TypedHole {
+    fn name(&self) -> &'static str {
+        "typed-hole"
+    }
+
+    fn code(&self) -> ErrorCode {
+        ErrorCode::TypedHole
+    }
+
+    fn check(&self, term_ctx: &TermCtx, _types: &TypeMap) -> Vec<Error> {
+        typing::find_holes(term_ctx)
+            .iter()
+            .map(|hole| hole.to_error())
+            .collect()
+    }
+}
+
+/// The built-in rules, with the parameters from `config`.
+pub fn builtin_lints(config: &LintConfig) -> Vec<Box<dyn Lint>> {
+    vec![
+        Box::new(ScopeLint),
+        Box::new(DeepLetNesting {
+            max_depth: config.max_let_depth,
+        }),
+        Box::new(DiscardedLinearResult),
+        Box::new(LargeClosureCapture {
+            max_captures: config.max_captures,
+        }),
+        Box::new(TypedHole),
+    ]
+}
+
+/// Runs a set of rules at the levels given by a `LintConfig`.
+pub struct LintRunner {
+    config: LintConfig,
+    lints: Vec<Box<dyn Lint>>,
+}
+
+impl LintRunner {
+    /// A runner with the built-in rules.
+    pub fn new(config: LintConfig) -> Self {
+        let lints = builtin_lints(&config);
+        LintRunner { config, lints }
+    }
+
+    /// Add a house rule.
+    pub fn register(&mut self, lint: Box<dyn Lint>) {
+        self.lints.push(lint);
+    }
+
+    pub fn level(&self, lint: &dyn Lint) -> Level {
+        self.rule_level(lint, lint.name())
+    }
+
+    /// The level of one of the rules of `lint`.
+    fn rule_level(&self, lint: &dyn Lint, name: &str) -> Level {
+        match self.config.rules.get(name) {
+            Some(level) => *level,
+            None => lint.default_level(),
+        }
+    }
+
+    /// Run the enabled rules on a program and return their findings in source order.
+    pub fn run(&self, term_ctx: &TermCtx) -> Vec<Error> {
+        let (types, _) = typing::type_check_partial(term_ctx);
+        let mut findings = vec![];
+        for lint in &self.lints {
+            let levels: HashMap<ErrorCode, Level> = lint
+                .rules()
+                .into_iter()
+                .map(|(name, code)| (code, self.rule_level(lint.as_ref(), name)))
+                .collect();
+            if levels.values().all(|level| *level == Level::Allow) {
+                continue;
+            }
+            for mut finding in lint.check(term_ctx, &types) {
+                let level = match levels.get(&finding.code()) {
+                    Some(level) => *level,
+                    None => self.level(lint.as_ref()),
+                };
+                let severity = match level {
+                    Level::Allow => continue,
+                    Level::Warning => Severity::Warning,
+                    Level::Error => Severity::Error,
+                };
+                if let Error::LintError(error) = &mut finding {
+                    error.severity = severity;
+                }
+                findings.push(finding);
+            }
+        }
+        findings.sort_by_key(|w| match w {
+            Error::LintError(error) => error.start,
+            _ => 0,
+        });
+        findings
+    }
+}
+
+/// Lint a program with the built-in rules at their default levels.
+pub fn lint(term_ctx: &TermCtx) -> Vec<Error> {
+    LintRunner::new(LintConfig::default()).run(term_ctx)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::syntax::parse_program;
+
+    fn codes(input: &str) -> Vec<ErrorCode> {
+        let warnings = lint(&parse_program(input).unwrap());
+        for w in &warnings {
+            println!("{}", w);
+        }
+        warnings.iter().map(|w| w.code()).collect()
+    }
+
+    #[test]
+    fn test_unused() {
+        assert_eq!(
+            codes("let x = 1 in let f = |y: int| 2 in f(3)"),
+            [ErrorCode::UnusedVariable, ErrorCode::UnusedVariable]
+        );
+        // intentionally unused, or linear (which is a type error)
+        assert!(codes("let _ = 1 in let _x = 2 in let h = $3 in 4").is_empty());
+        assert!(codes("let <a, b> = <1, 2> in diff(a, b)").is_empty());
+        // the parameter has the type of the annotation on the `let`
+        assert_eq!(
+            codes("let f: int -> int = |x| 1 in f(2)"),
+            [ErrorCode::UnusedVariable]
+        );
+    }
+
+    #[test]
+    fn test_shadowed_linear() {
+        assert_eq!(
+            codes("let h = $1 in let h = 2 in h"),
+            [ErrorCode::ShadowedLinear]
+        );
+        // the type checker reports the leak at the hidden variable, the lint the cause
+        let term = parse_program("let h = $1 in let h = 2 in h").unwrap();
+        let error = crate::typing::type_check(&term).unwrap_err();
+        assert!(matches!(
+            error,
+            Error::TypeError(error) if error.code == ErrorCode::LinearNotConsumed && error.start == 0
+        ));
+        // the outer `h` is consumed once the inner one goes out of scope
+        let input = "let h = $1 in let y = (let h = 2 in h) in diff(h, y)";
+        assert!(crate::typing::type_check(&parse_program(input).unwrap()).is_ok());
+        assert_eq!(codes(input), [ErrorCode::ShadowedLinear]);
+        // the chains in the examples consume `h` before shadowing it
+        let input = include_str!("../examples/io.txt");
+        assert!(!codes(input).contains(&ErrorCode::ShadowedLinear));
+    }
+
+    #[test]
+    fn test_redundant_qualifier() {
+        let input = "if $true { iszero($0) } else { false }";
+        assert_eq!(
+            codes(input),
+            [ErrorCode::RedundantQualifier, ErrorCode::RedundantQualifier]
+        );
+        assert!(codes("(|x: $int| diff(x, 1)) ($2)").is_empty());
+    }
+
+    #[test]
+    fn test_examples() {
+        assert!(codes(include_str!("../examples/fact.txt")).is_empty());
+        assert!(codes(include_str!("../examples/http.txt")).is_empty());
+        // the parameters `dummy` of `open` and `msg` of `write` are never used
+        let input = include_str!("../examples/io.txt");
+        let warnings = lint(&parse_program(input).unwrap());
+        let unused: Vec<&str> = warnings
+            .iter()
+            .map(|w| match w {
+                Error::LintError(error) => &input[error.start..error.end],
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(unused, ["|dummy: int|", "$|msg: int|"]);
+    }
+
+    #[test]
+    fn test_deep_let_nesting() {
+        let input = "let a = let b = let c = let d = let e = 1 in e in d in c in b in a";
+        assert_eq!(codes(input), [ErrorCode::DeepLetNesting]);
+        // a sequence of lets is not nesting
+        let input = "let a = 1 in let b = a in let c = b in let d = c in let e = d in e";
+        assert!(codes(input).is_empty());
+    }
+
+    #[test]
+    fn test_discarded_linear_result() {
+        let input = "let f = |x: int| $diff(x, 0) in let _ = f(1) in 0";
+        assert_eq!(codes(input), [ErrorCode::DiscardedLinearResult]);
+    }
+
+    #[test]
+    fn test_large_closure_capture() {
+        let input = "let a = 1 in let b = 2 in let c = 3 in let d = 4 in let e = 5 in \
+                     let f = |x: int| diff(diff(diff(a, b), diff(c, d)), diff(e, x)) in \
+                     f(0)";
+        assert_eq!(codes(input), [ErrorCode::LargeClosureCapture]);
+        // linear closures are called once and can capture anything
+        let input = input.replace("|x: int|", "$|x: int|");
+        assert!(codes(&input).is_empty());
+    }
+
+    #[test]
+    fn test_config() {
+        let term = parse_program("let x = 1 in let y = let z = 2 in z in y").unwrap();
+        let config: LintConfig = serde_json::from_str(
+            r#"{"rules": {"unused-variable": "allow", "deep-let-nesting": "error"},
+                "max_let_depth": 1}"#,
+        )
+        .unwrap();
+        let findings = LintRunner::new(config).run(&term);
+        for f in &findings {
+            println!("{}", f);
+        }
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].code(), ErrorCode::DeepLetNesting);
+        assert_eq!(findings[0].severity(), Severity::Error);
+        assert_eq!(lint(&term)[0].code(), ErrorCode::UnusedVariable);
+        // the rules of one walk over the scopes have their own levels
+        let term = parse_program("let x = 1 in let h = $2 in let h = iszero($0) in h").unwrap();
+        let config: LintConfig =
+            serde_json::from_str(r#"{"rules": {"shadowed-linear": "error"}}"#).unwrap();
+        let findings = LintRunner::new(config).run(&term);
+        let levels: Vec<_> = findings.iter().map(|f| (f.code(), f.severity())).collect();
+        assert_eq!(
+            levels,
+            [
+                (ErrorCode::UnusedVariable, Severity::Warning),
+                (ErrorCode::ShadowedLinear, Severity::Error),
+                (ErrorCode::RedundantQualifier, Severity::Warning),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_register() {
+        /// A house rule: no `fix`.
+        struct NoFix;
+
+        impl Lint for NoFix {
+            fn name(&self) -> &'static str {
+                "no-fix"
+            }
+
+            fn code(&self) -> ErrorCode {
+                ErrorCode::Custom("L0001")
+            }
+
+            fn check(&self, term_ctx: &TermCtx, _types: &TypeMap) -> Vec<Error> {
+                let mut warnings = vec![];
+                let mut stack = vec![term_ctx];
+                while let Some(TermCtx(span, term, ..)) = stack.pop() {
+                    if let Term::Fix(_) = term {
+                        warnings.push(warning(self.code(), span, "fix".to_owned()));
+                    }
+                    stack.extend(term.children());
+                }
+                warnings
+            }
+        }
+
+        let term = parse_program(
+            "(fix |f: int->int| |x: int| if iszero(x) { 0 } else { f(diff(x, 1)) }) (1)",
+        )
+        .unwrap();
+        let mut runner = LintRunner::new(LintConfig::default());
+        assert!(runner.run(&term).is_empty());
+        runner.register(Box::new(NoFix));
+        let findings = runner.run(&term);
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].code().as_str(), "L0001");
+        let json = serde_json::to_value(&findings[0]).unwrap();
+        assert_eq!(json["LintError"]["code"], "L0001");
+    }
+}
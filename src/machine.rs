@@ -0,0 +1,521 @@
+use crate::error::{Error, ErrorCode, EvaluateError};
+use crate::eval::{self, Answer, Limits, MachineOutcome};
+use crate::syntax::{ArithOp, Qualifier, Term, TermCtx};
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+/// A location in the store.
+type Loc = usize;
+
+/// The locations of the variables in scope, innermost first.
+#[derive(Clone, Default)]
+struct Env<'a>(Option<Rc<(&'a str, Loc, Env<'a>)>>);
+
+impl<'a> Env<'a> {
+    fn bind(&self, x: &'a str, loc: Loc) -> Env<'a> {
+        Env(Some(Rc::new((x, loc, self.clone()))))
+    }
+
+    fn get(&self, x: &str) -> Option<Loc> {
+        let mut env = self;
+        while let Some(node) = &env.0 {
+            let (y, loc, next) = &**node;
+            if *y == x {
+                return Some(*loc);
+            }
+            env = next;
+        }
+        None
+    }
+
+    /// Add the locations of the environment to `locs`. Environments share their tails, so the
+    /// nodes in `seen` are not walked again.
+    fn locs(&self, seen: &mut HashSet<*const ()>, locs: &mut Vec<Loc>) {
+        let mut env = self;
+        while let Some(node) = &env.0 {
+            if !seen.insert(Rc::as_ptr(node) as *const ()) {
+                break;
+            }
+            let (_, loc, next) = &**node;
+            locs.push(*loc);
+            env = next;
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Value<'a> {
+    Boolean(Qualifier, bool),
+    Integer(Qualifier, i64),
+    Closure(Qualifier, &'a str, &'a TermCtx, Env<'a>),
+    Pair(Qualifier, Loc, Loc),
+    /// `fix` of a function `|f| body`, whose `f` is bound to the location of the value itself.
+    /// Like recursive functions in the small-step evaluator, it is unrestricted.
+    Fix(&'a str, &'a TermCtx, Env<'a>),
+}
+
+impl Value<'_> {
+    fn qualifier(&self) -> Qualifier {
+        match self {
+            Value::Boolean(q, _)
+            | Value::Integer(q, _)
+            | Value::Closure(q, ..)
+            | Value::Pair(q, ..) => *q,
+            Value::Fix(..) => Qualifier::Nop,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Store<'a> {
+    values: HashMap<Loc, Value<'a>>,
+    next: Loc,
+}
+
+impl<'a> Store<'a> {
+    fn alloc(&mut self, value: Value<'a>) -> Loc {
+        let loc = self.next;
+        self.next += 1;
+        self.values.insert(loc, value);
+        loc
+    }
+
+    /// Take the value at `loc`. A linear value is gone afterwards.
+    fn extract(&mut self, loc: Loc) -> Option<Value<'a>> {
+        match self.values.get(&loc)?.qualifier() {
+            Qualifier::Linear => self.values.remove(&loc),
+            Qualifier::Nop => self.values.get(&loc).cloned(),
+        }
+    }
+
+    /// Free the unrestricted values that cannot be reached from the locations in `roots`,
+    /// like `eval::Store::collect`. Unreachable linear values are kept. Give the number of
+    /// values freed.
+    fn collect(&mut self, mut roots: Vec<Loc>, seen: &mut HashSet<*const ()>) -> usize {
+        let mut reachable = HashSet::new();
+        while let Some(loc) = roots.pop() {
+            if !reachable.insert(loc) {
+                continue;
+            }
+            match self.values.get(&loc) {
+                Some(Value::Closure(.., env) | Value::Fix(_, _, env)) => env.locs(seen, &mut roots),
+                Some(Value::Pair(_, l1, l2)) => roots.extend([*l1, *l2]),
+                _ => (),
+            }
+        }
+        let before = self.values.len();
+        self.values
+            .retain(|loc, value| reachable.contains(loc) || value.qualifier() == Qualifier::Linear);
+        before - self.values.len()
+    }
+
+    fn answer(&self, value: &Value) -> Option<Answer> {
+        let answer = match value {
+            Value::Boolean(q, v) => Answer::Boolean(*q, *v),
+            Value::Integer(q, v) => Answer::Integer(*q, *v),
+            Value::Closure(q, ..) => Answer::Function(*q),
+            Value::Fix(..) => Answer::Function(Qualifier::Nop),
+            Value::Pair(q, l1, l2) => {
+                let a1 = self.answer(self.values.get(l1)?)?;
+                let a2 = self.answer(self.values.get(l2)?)?;
+                Answer::Pair(*q, Box::new(a1), Box::new(a2))
+            }
+        };
+        Some(answer)
+    }
+}
+
+/// What is left to do once the term under evaluation has a value. Frames keep the node that
+/// errors point at.
+enum Frame<'a> {
+    /// The function of an application is done; the argument is next.
+    Argument(&'a TermCtx, &'a TermCtx, Env<'a>),
+    /// Both are done; call the function at the location.
+    Call(&'a TermCtx, Loc),
+    Branch(&'a TermCtx, &'a TermCtx, &'a TermCtx, Env<'a>),
+    Let(&'a str, &'a TermCtx, Env<'a>),
+    Split(&'a TermCtx, (&'a str, &'a str), &'a TermCtx, Env<'a>),
+    Fix(&'a TermCtx),
+    /// The first component of a pair is done; the second is next.
+    Second(Qualifier, &'a TermCtx, Env<'a>),
+    Pair(Qualifier, Loc),
+    IsZero(&'a TermCtx, Qualifier),
+    /// The first operand of `diff` is done; the second is next.
+    Subtrahend(&'a TermCtx, Qualifier, &'a TermCtx, Env<'a>),
+    Diff(&'a TermCtx, Qualifier, Loc),
+}
+
+enum Control<'a> {
+    Eval(&'a TermCtx, Env<'a>),
+    Return(Loc),
+}
+
+fn error(term_ctx: &TermCtx, code: ErrorCode, message: String) -> Error {
+    Error::from(EvaluateError {
+        code,
+        message,
+        start: term_ctx.0.start,
+        end: term_ctx.0.end,
+        location: None,
+        node: term_ctx.2,
+        labels: vec![],
+    })
+}
+
+fn stuck<T>(term_ctx: &TermCtx, message: &str) -> Result<T, Error> {
+    Err(error(term_ctx, ErrorCode::StuckTerm, message.to_string()))
+}
+
+/// An abstract machine with an environment and a stack of continuations, a CEK machine, for
+/// the same semantics as `eval::one_step_eval`.
+///
+/// The small-step evaluator rebuilds the term on every step and substitutes store variables
+/// into function bodies. The machine walks the program as it is instead: variables are looked
+/// up in an environment that maps them to locations in the store. Values live in the store,
+/// and a linear value leaves it when it is consumed, as in `eval::Store`. A variable passed as
+/// an argument, bound by `let` or put in a pair is not consumed; it is consumed where its
+/// value is taken apart: by an application, a conditional, `let <_, _>`, `fix` or an
+/// arithmetic primitive, and at the end of the program. The unrestricted values that neither
+/// the control nor the stack can reach are freed every `Limits::gc_interval` steps.
+#[derive(Default)]
+struct Machine<'a> {
+    store: Store<'a>,
+    stack: Vec<Frame<'a>>,
+}
+
+impl<'a> Machine<'a> {
+    fn extract(&mut self, at: &TermCtx, loc: Loc) -> Result<Value<'a>, Error> {
+        self.store.extract(loc).ok_or_else(|| {
+            let message = format!("Location {} not found", loc);
+            error(at, ErrorCode::UnboundStoreVariable, message)
+        })
+    }
+
+    fn integer(&mut self, at: &TermCtx, loc: Loc) -> Result<i64, Error> {
+        match self.extract(at, loc)? {
+            Value::Integer(_, v) => Ok(v),
+            _ => stuck(at, "Expect an Integer"),
+        }
+    }
+
+    fn eval(&mut self, term_ctx: &'a TermCtx, env: Env<'a>) -> Result<Control<'a>, Error> {
+        let value = match &term_ctx.1 {
+            Term::Variable(x) => {
+                let Some(loc) = env.get(x) else {
+                    let message = format!("Variable {} not found", x);
+                    return Err(error(term_ctx, ErrorCode::UnboundStoreVariable, message));
+                };
+                // unroll a recursive function
+                if let Some(Value::Fix(f, body, env)) = self.store.values.get(&loc) {
+                    return Ok(Control::Eval(body, env.bind(f, loc)));
+                }
+                return Ok(Control::Return(loc));
+            }
+            Term::Boolean(q, v) => Value::Boolean(*q, *v),
+            Term::Integer(q, v) => Value::Integer(*q, *v),
+            Term::Abstraction(q, x, _, body) => Value::Closure(*q, x, body, env),
+            Term::Compound(q, t1, t2) => {
+                self.stack.push(Frame::Second(*q, t2, env.clone()));
+                return Ok(Control::Eval(t1, env));
+            }
+            Term::Application(t1, t2) => {
+                self.stack.push(Frame::Argument(term_ctx, t2, env.clone()));
+                return Ok(Control::Eval(t1, env));
+            }
+            Term::Conditional(t1, t2, t3) => {
+                self.stack
+                    .push(Frame::Branch(term_ctx, t2, t3, env.clone()));
+                return Ok(Control::Eval(t1, env));
+            }
+            Term::Let(x, t1, t2) => {
+                self.stack.push(Frame::Let(x, t2, env.clone()));
+                return Ok(Control::Eval(t1, env));
+            }
+            Term::Letc(x1, x2, t1, t2) => {
+                self.stack
+                    .push(Frame::Split(term_ctx, (x1, x2), t2, env.clone()));
+                return Ok(Control::Eval(t1, env));
+            }
+            Term::Fix(t) => {
+                self.stack.push(Frame::Fix(term_ctx));
+                return Ok(Control::Eval(t, env));
+            }
+            Term::Arith1(q, ArithOp::IsZero, t) => {
+                self.stack.push(Frame::IsZero(term_ctx, *q));
+                return Ok(Control::Eval(t, env));
+            }
+            Term::Arith2(q, ArithOp::Diff, t1, t2) => {
+                self.stack
+                    .push(Frame::Subtrahend(term_ctx, *q, t2, env.clone()));
+                return Ok(Control::Eval(t1, env));
+            }
+            Term::Arith1(_, op, _) | Term::Arith2(_, op, ..) => {
+                return stuck(term_ctx, &format!("Unknown op {:?}", op));
+            }
+            // annotations do not matter at run time
+            Term::Ascription(t, _) => return Ok(Control::Eval(t, env)),
+            Term::Error(_) => {
+                let message = "Cannot evaluate a malformed term".to_string();
+                return Err(error(term_ctx, ErrorCode::MalformedTerm, message));
+            }
+            Term::Hole(name) => {
+                let name = name.as_deref().unwrap_or_default();
+                let message = format!("Cannot evaluate the hole ?{}", name);
+                return Err(error(term_ctx, ErrorCode::UnfilledHole, message));
+            }
+        };
+        Ok(Control::Return(self.store.alloc(value)))
+    }
+
+    /// Continue with the value at `loc` as `frame` says.
+    fn ret(&mut self, frame: Frame<'a>, loc: Loc) -> Result<Control<'a>, Error> {
+        let next = match frame {
+            Frame::Argument(at, arg, env) => {
+                self.stack.push(Frame::Call(at, loc));
+                Control::Eval(arg, env)
+            }
+            Frame::Call(at, f) => match self.extract(at, f)? {
+                Value::Closure(_, x, body, env) => Control::Eval(body, env.bind(x, loc)),
+                _ => return stuck(at, "Expect abstraction"),
+            },
+            Frame::Branch(at, t2, t3, env) => match self.extract(at, loc)? {
+                Value::Boolean(_, v) => Control::Eval(if v { t2 } else { t3 }, env),
+                _ => return stuck(at, "Conditional term must be boolean"),
+            },
+            Frame::Let(x, t2, env) => Control::Eval(t2, env.bind(x, loc)),
+            Frame::Split(at, (x1, x2), t2, env) => match self.extract(at, loc)? {
+                Value::Pair(_, l1, l2) => Control::Eval(t2, env.bind(x1, l1).bind(x2, l2)),
+                _ => return stuck(at, "Expect compound"),
+            },
+            Frame::Fix(at) => match self.extract(at, loc)? {
+                Value::Closure(_, f, body, env) => {
+                    let loc = self.store.alloc(Value::Fix(f, body, env.clone()));
+                    Control::Eval(body, env.bind(f, loc))
+                }
+                _ => return stuck(at, "Expect abstraction"),
+            },
+            Frame::Second(q, t2, env) => {
+                self.stack.push(Frame::Pair(q, loc));
+                Control::Eval(t2, env)
+            }
+            Frame::Pair(q, l1) => Control::Return(self.store.alloc(Value::Pair(q, l1, loc))),
+            Frame::IsZero(at, q) => {
+                let v = self.integer(at, loc)?;
+                Control::Return(self.store.alloc(Value::Boolean(q, v == 0)))
+            }
+            Frame::Subtrahend(at, q, t2, env) => {
+                self.stack.push(Frame::Diff(at, q, loc));
+                Control::Eval(t2, env)
+            }
+            Frame::Diff(at, q, l1) => {
+                let v1 = self.integer(at, l1)?;
+                let v2 = self.integer(at, loc)?;
+                Control::Return(self.store.alloc(Value::Integer(q, v1 - v2)))
+            }
+        };
+        Ok(next)
+    }
+
+    /// Free the values that `control` and the stack cannot reach.
+    fn collect(&mut self, control: &Control<'a>) -> usize {
+        let mut seen = HashSet::new();
+        let mut roots = vec![];
+        match control {
+            Control::Eval(_, env) => env.locs(&mut seen, &mut roots),
+            Control::Return(loc) => roots.push(*loc),
+        }
+        for frame in &self.stack {
+            match frame {
+                Frame::Argument(_, _, env)
+                | Frame::Branch(_, _, _, env)
+                | Frame::Let(_, _, env)
+                | Frame::Split(_, _, _, env)
+                | Frame::Second(_, _, env)
+                | Frame::Subtrahend(_, _, _, env) => env.locs(&mut seen, &mut roots),
+                Frame::Call(_, loc) | Frame::Pair(_, loc) | Frame::Diff(_, _, loc) => {
+                    roots.push(*loc)
+                }
+                Frame::Fix(_) | Frame::IsZero(..) => (),
+            }
+        }
+        self.store.collect(roots, &mut seen)
+    }
+
+    /// Consume the value of the program, at `loc`.
+    fn finish(&mut self, term_ctx: &TermCtx, loc: Loc, steps: usize) -> MachineOutcome {
+        let value = match self.extract(term_ctx, loc) {
+            Ok(value) => value,
+            Err(error) => {
+                let error = Box::new(error);
+                return MachineOutcome::Stuck { error, steps };
+            }
+        };
+        match self.store.answer(&value) {
+            Some(answer) => MachineOutcome::Value { answer, steps },
+            None => {
+                let message = "Dangling location in the value".to_string();
+                let error = error(term_ctx, ErrorCode::UnboundStoreVariable, message);
+                let error = Box::new(error);
+                MachineOutcome::Stuck { error, steps }
+            }
+        }
+    }
+}
+
+/// Evaluate a program until it is a value, or until one of the `limits` is reached. The
+/// value is read back from the store, so that it can be compared with the one of
+/// `eval::run`.
+pub fn run(term_ctx: &TermCtx, limits: &Limits) -> MachineOutcome {
+    let start = eval::now_millis();
+    let mut machine = Machine::default();
+    let mut control = Control::Eval(term_ctx, Env::default());
+    let mut steps: usize = 0;
+    loop {
+        if limits
+            .gc_interval
+            .is_some_and(|every| every > 0 && steps > 0 && steps.is_multiple_of(every))
+        {
+            machine.collect(&control);
+        }
+        if let Some(limit) = limits.reached(steps, machine.store.values.len(), start) {
+            return MachineOutcome::OutOfFuel { limit, steps };
+        }
+        let next = match control {
+            Control::Eval(term_ctx, env) => machine.eval(term_ctx, env),
+            Control::Return(loc) => match machine.stack.pop() {
+                Some(frame) => machine.ret(frame, loc),
+                // the end of the program, where its value is consumed
+                None => return machine.finish(term_ctx, loc, steps),
+            },
+        };
+        control = match next {
+            Ok(next) => next,
+            Err(error) => {
+                let error = Box::new(error);
+                return MachineOutcome::Stuck { error, steps };
+            }
+        };
+        steps += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::eval::Limit;
+    use crate::syntax::parse_program;
+
+    /// Run a program on both evaluators and check that they agree.
+    fn cross_check(input: &str) -> MachineOutcome {
+        let term = parse_program(input).unwrap();
+        let limits = Limits {
+            max_millis: None,
+            ..Default::default()
+        };
+        let expected = eval::run(term.clone(), &limits);
+        let outcome = run(&term, &limits);
+        println!("{:?}\n{:?}", expected, outcome);
+        match (&expected, &outcome) {
+            (eval::Outcome::Value { result, .. }, MachineOutcome::Value { answer, .. }) => {
+                assert_eq!(result.answer().as_ref(), Some(answer))
+            }
+            (eval::Outcome::Stuck { error: e1, .. }, MachineOutcome::Stuck { error: e2, .. }) => {
+                assert_eq!(e1.code(), e2.code())
+            }
+            (eval::Outcome::OutOfFuel { .. }, MachineOutcome::OutOfFuel { .. }) => {}
+            _ => panic!("the evaluators disagree on {}", input),
+        }
+        outcome
+    }
+
+    #[test]
+    fn test_examples() {
+        let mut paths: Vec<_> = std::fs::read_dir("examples")
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|e| e == "txt"))
+            .collect();
+        paths.sort();
+        for path in paths {
+            println!("{}", path.display());
+            cross_check(&std::fs::read_to_string(path).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_cross_check() {
+        let inputs = [
+            "(|x| |y| x) (true) (false)",
+            "let f = fix(|ff: bool -> bool| |x: bool| if x {ff(false)} else {ff(true)}) in f(true)",
+            "let negate = |x| $diff($0, x) in negate($5)",
+            "let <a, b> = $<$1, <true, |x: int| x>> in <b, a>",
+            "let x: int = 5 in let f = |y: int| (diff(x, y) : int) in f(2)",
+            "let h = $1 in let _ = iszero(h) in iszero(h)",
+            "iszero(?n)",
+            "if 1 { 2 } else { 3 }",
+        ];
+        for input in inputs {
+            cross_check(input);
+        }
+    }
+
+    #[test]
+    fn test_fact() {
+        let input = std::fs::read_to_string("examples/fact.txt").unwrap();
+        let input = input.replace("fact(3)", "fact(7)");
+        let outcome = run(&parse_program(&input).unwrap(), &Limits::default());
+        match outcome {
+            MachineOutcome::Value { answer, .. } => {
+                assert_eq!(answer, Answer::Integer(Qualifier::Nop, 5040))
+            }
+            outcome => panic!("expect a value, given {:?}", outcome),
+        }
+        // the intermediate results are freed along the way
+        let small = Limits {
+            max_store: Some(2_000),
+            ..Default::default()
+        };
+        let outcome = run(&parse_program(&input).unwrap(), &small);
+        assert!(matches!(outcome, MachineOutcome::Value { .. }));
+        let no_gc = Limits {
+            gc_interval: None,
+            ..small
+        };
+        let outcome = run(&parse_program(&input).unwrap(), &no_gc);
+        assert!(matches!(
+            outcome,
+            MachineOutcome::OutOfFuel {
+                limit: Limit::Store,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_collect() {
+        // the pair and the bound values are live, the integers allocated along the way are not
+        let input = "let p = <1, 2> in let _ = iszero(diff(3, 4)) in let h = $5 in p";
+        let term = parse_program(input).unwrap();
+        let mut machine = Machine::default();
+        let mut control = Control::Eval(&term, Env::default());
+        loop {
+            if let Control::Eval(TermCtx(_, Term::Variable(x), ..), _) = &control {
+                if x == "p" {
+                    break;
+                }
+            }
+            control = match control {
+                Control::Eval(term_ctx, env) => machine.eval(term_ctx, env).unwrap(),
+                Control::Return(loc) => {
+                    let frame = machine.stack.pop().unwrap();
+                    machine.ret(frame, loc).unwrap()
+                }
+            };
+        }
+        let before = machine.store.values.len();
+        let freed = machine.collect(&control);
+        // `<1, 2>`, `1`, `2`, the boolean bound to `_` and `$5` are left
+        assert_eq!(machine.store.values.len(), 5);
+        assert_eq!(freed, before - 5);
+    }
+}
@@ -0,0 +1,246 @@
+use crate::error::{Error, Label, Severity, Suggestion};
+use crate::source_map::{Position, SourceMap};
+
+/// Tabs are expanded to this many spaces so that carets line up with the source.
+const TAB_WIDTH: usize = 4;
+
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// Renders diagnostics for the terminal in the style of rustc: the offending source lines,
+/// underlined with `^` for the primary span and `-` for labels, followed by notes and help.
+/// Errors are drawn in red and warnings in yellow.
+pub struct Renderer {
+    color: bool,
+}
+
+struct Annotation {
+    start: Position,
+    end: Position,
+    message: String,
+    primary: bool,
+}
+
+impl Renderer {
+    pub fn new(color: bool) -> Self {
+        Renderer { color }
+    }
+
+    pub fn plain() -> Self {
+        Self::new(false)
+    }
+
+    pub fn colored() -> Self {
+        Self::new(true)
+    }
+
+    fn paint(&self, style: &str, s: &str) -> String {
+        if self.color && !s.is_empty() {
+            format!("{}{}{}", style, s, RESET)
+        } else {
+            s.to_owned()
+        }
+    }
+
+    /// Render `error`, reported on the source of `map`. `path` names the source in the
+    /// `-->` line.
+    pub fn render(&self, error: &Error, map: &SourceMap, path: &str) -> String {
+        let severity = error.severity();
+        let primary_style = match severity {
+            Severity::Error => RED,
+            Severity::Warning => YELLOW,
+        };
+        let mut out = format!(
+            "{}{}\n",
+            self.paint(primary_style, &format!("{}[{}]", severity, error.code())),
+            self.paint(BOLD, &format!(": {}", headline(error)))
+        );
+        let mut annotations = vec![];
+        let mut notes = vec![];
+        let (labels, suggestions): (&[Label], &[Suggestion]) = match error {
+            Error::ParseError(_) | Error::InternalError { .. } => (&[], &[]),
+            Error::EvaluateError(e) => (&e.labels, &[]),
+            Error::TypeError(e) => {
+                if let (Some(expected), Some(found)) = (&e.expected, &e.found) {
+                    notes.push(format!("note: expected `{}`, found `{}`", expected, found));
+                }
+                (&e.labels, &e.suggestions)
+            }
+            Error::LintError(e) => (&e.labels, &e.suggestions),
+        };
+        if let Some((start, end)) = error.span() {
+            annotations.push(annotation(map, start, end, String::new(), true));
+        }
+        for label in labels {
+            let message = label.message.clone();
+            annotations.push(annotation(map, label.start, label.end, message, false));
+        }
+        for suggestion in suggestions {
+            notes.push(format!("help: {}", suggestion.message));
+        }
+        let mut lines: Vec<usize> = annotations.iter().map(|a| a.start.line).collect();
+        lines.sort_unstable();
+        lines.dedup();
+        let width = lines.last().map_or(1, |l| (l + 1).to_string().len());
+        let gutter = self.paint(BLUE, &format!("{} |", " ".repeat(width)));
+        if let Some(primary) = annotations.first() {
+            out.push_str(&format!(
+                "{}{} {}:{}:{}\n",
+                " ".repeat(width),
+                self.paint(BLUE, "-->"),
+                path,
+                primary.start.line + 1,
+                primary.start.column + 1
+            ));
+            out.push_str(&format!("{}\n", gutter));
+        }
+        let source_lines: Vec<&str> = map.source().split('\n').collect();
+        let mut previous: Option<usize> = None;
+        for &line in &lines {
+            if previous.is_some_and(|p| line > p + 1) {
+                out.push_str(&format!("{}\n", self.paint(BLUE, "...")));
+            }
+            previous = Some(line);
+            let text = source_lines.get(line).copied().unwrap_or("");
+            let text = text.strip_suffix('\r').unwrap_or(text);
+            out.push_str(&format!(
+                "{} {}\n",
+                self.paint(BLUE, &format!("{:>width$} |", line + 1, width = width)),
+                expand_tabs(text)
+            ));
+            let mut on_line: Vec<&Annotation> = annotations
+                .iter()
+                .filter(|a| a.start.line == line)
+                .collect();
+            on_line.sort_by_key(|a| (a.start.column, !a.primary));
+            for a in on_line {
+                let from = display_column(text, a.start.column);
+                let to = if a.end.line == a.start.line {
+                    display_column(text, a.end.column)
+                } else {
+                    display_column(text, text.chars().count())
+                };
+                let (style, mark) = if a.primary {
+                    (primary_style, "^")
+                } else {
+                    (BLUE, "-")
+                };
+                let underline = mark.repeat(to.saturating_sub(from).max(1));
+                let marker = if a.message.is_empty() {
+                    underline
+                } else {
+                    format!("{} {}", underline, a.message)
+                };
+                out.push_str(&format!(
+                    "{} {}{}\n",
+                    gutter,
+                    " ".repeat(from),
+                    self.paint(style, &marker)
+                ));
+            }
+        }
+        for note in notes {
+            out.push_str(&format!(
+                "{} {}\n",
+                self.paint(BLUE, &format!("{} =", " ".repeat(width))),
+                note
+            ));
+        }
+        out
+    }
+}
+
+fn annotation(
+    map: &SourceMap,
+    start: usize,
+    end: usize,
+    message: String,
+    primary: bool,
+) -> Annotation {
+    Annotation {
+        start: map.position(start),
+        end: map.position(end),
+        message,
+        primary,
+    }
+}
+
+/// The first line of the message. Parse errors carry pest's own rendering, whose summary is
+/// the `= ...` line at the end.
+fn headline(error: &Error) -> String {
+    let message = error.message();
+    message
+        .lines()
+        .rev()
+        .find_map(|l| l.trim_start().strip_prefix("= "))
+        .or_else(|| message.lines().next())
+        .unwrap_or("")
+        .to_owned()
+}
+
+fn expand_tabs(text: &str) -> String {
+    text.replace('\t', &" ".repeat(TAB_WIDTH))
+}
+
+/// The column at which the character at `column` is displayed, once tabs are expanded.
+fn display_column(text: &str, column: usize) -> usize {
+    text.chars()
+        .take(column)
+        .map(|c| if c == '\t' { TAB_WIDTH } else { 1 })
+        .sum()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::syntax::{parse_program, parse_program_recovering};
+    use crate::typing::type_check;
+
+    fn render(input: &str) -> String {
+        let (term, errors) = parse_program_recovering(input);
+        let error = match errors.into_iter().next() {
+            Some(error) => error,
+            None => type_check(&term).unwrap_err(),
+        };
+        Renderer::plain().render(&error, &SourceMap::new(input), "test.txt")
+    }
+
+    #[test]
+    fn test_render_type_error() {
+        let input = include_str!("../examples/http_error2.txt");
+        let report = render(input);
+        println!("{}", report);
+        assert!(report.starts_with("error[E0004]: linear variable request_handler"));
+        assert!(report.contains(" --> test.txt:11:7\n"));
+        assert!(report.contains("11 |       request_handler ($43)\n"));
+        assert!(report.contains("   |       ^^^^^^^^^^^^^^^\n"));
+        assert!(report.contains("request_handler is first consumed here"));
+    }
+
+    #[test]
+    fn test_render_parse_error() {
+        let report = render("let x = diff(1, 2 in\nx");
+        println!("{}", report);
+        assert!(report.starts_with("error[E0001]: expected"));
+        assert!(report.contains("1 | let x = diff(1, 2 in\n"));
+    }
+
+    #[test]
+    fn test_render_color() {
+        let input = "let y = 1 in\n\tlet x = $1 in y";
+        let error = type_check(&parse_program(input).unwrap()).unwrap_err();
+        let map = SourceMap::new(input);
+        let plain = Renderer::plain().render(&error, &map, "test.txt");
+        println!("{}", plain);
+        assert!(!plain.contains('\x1b'));
+        // the tab is expanded and the carets line up with `let`
+        assert!(plain.contains("2 |     let x = $1 in y\n  |     ^^^\n"));
+        assert!(plain.contains("help: consume x with iszero(x)"));
+        let colored = Renderer::colored().render(&error, &map, "test.txt");
+        println!("{}", colored);
+        assert!(colored.contains(RED));
+    }
+}
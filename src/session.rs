@@ -0,0 +1,498 @@
+use crate::error::{Error, ErrorCode, EvaluateError};
+use crate::eval::{now_millis, one_step_eval_traced, GcStats, Limit, Limits, Reduction, TermEval};
+use crate::formatter;
+use crate::syntax::{Context, TermCtx};
+use serde::{Deserialize, Serialize};
+
+/// How many steps apart the full states that a session keeps are.
+const CHECKPOINT_INTERVAL: usize = 64;
+
+/// An evaluation that records every step it takes, so that it can step back, go to any step,
+/// and find the step where a binding of the store was created. It can also run to a
+/// breakpoint, and show the values of watched bindings at each step.
+///
+/// Steps are recorded as they are first taken, by their reductions, which have the diff of the
+/// store. Full states are only kept every `CHECKPOINT_INTERVAL` steps; the other recorded
+/// states are replayed from the checkpoint before them.
+#[derive(Debug)]
+pub struct Session {
+    /// Full states by step, in order: the first one, one every `CHECKPOINT_INTERVAL` steps,
+    /// and the ones whose garbage was collected.
+    checkpoints: Vec<(usize, TermEval)>,
+    /// `reductions[i]` leads from the state after `i` steps to the next one.
+    reductions: Vec<Reduction>,
+    current: usize,
+    state: TermEval,
+    /// The state before the current one, for the watches.
+    previous: Option<TermEval>,
+    breakpoints: Vec<Breakpoint>,
+    /// The names of the store bindings whose values are shown at each step.
+    watches: Vec<String>,
+}
+
+/// Where `Session::run` stops.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Breakpoint {
+    /// A step whose redex lies in the span, e.g. of the body of a function.
+    Span(Context),
+    /// A step that creates, removes or consumes the store binding, e.g. `%x3`.
+    Binding(String),
+}
+
+impl Breakpoint {
+    /// Whether the step that made `reduction` hits the breakpoint.
+    pub fn hit(&self, reduction: &Reduction) -> bool {
+        match self {
+            Breakpoint::Span(span) => {
+                span.start <= reduction.redex.start && reduction.redex.end <= span.end
+            }
+            Breakpoint::Binding(name) => {
+                let diff = &reduction.diff;
+                [&diff.added, &diff.removed, &diff.consumed]
+                    .iter()
+                    .any(|names| names.contains(name))
+            }
+        }
+    }
+}
+
+/// Why `Session::run` stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Stop {
+    /// The step to the current state hit the breakpoint with this index.
+    Breakpoint(usize),
+    Value,
+    OutOfFuel(Limit),
+}
+
+/// The value of a watched binding in a state.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Watch {
+    pub name: String,
+    /// The value, e.g. `$<1, true>`; none if the binding is not created yet, or is gone.
+    pub value: Option<String>,
+    /// Whether the value differs from the one in the state before.
+    pub changed: bool,
+}
+
+/// A state of the session, as sent to the playground.
+#[derive(Debug, Serialize)]
+pub struct Snapshot<'a> {
+    /// The number of steps from the start.
+    pub step: usize,
+    /// The number of steps recorded so far.
+    pub recorded: usize,
+    pub is_value: bool,
+    pub state: &'a TermEval,
+    /// The reduction that led to the state, unless it is the first one.
+    pub reduction: Option<&'a Reduction>,
+    pub watches: Vec<Watch>,
+}
+
+/// Where `Session::run` stopped, as sent to the playground.
+#[derive(Debug, Serialize)]
+pub struct Paused<'a> {
+    pub stop: Stop,
+    #[serde(flatten)]
+    pub snapshot: Snapshot<'a>,
+}
+
+impl Session {
+    pub fn new(term: TermCtx) -> Self {
+        TermEval::from(term).into()
+    }
+
+    /// The number of steps from the start to the current state.
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    pub fn state(&self) -> &TermEval {
+        &self.state
+    }
+
+    /// The number of states recorded so far.
+    pub fn recorded(&self) -> usize {
+        self.reductions.len() + 1
+    }
+
+    pub fn snapshot(&self) -> Snapshot<'_> {
+        Snapshot {
+            step: self.current,
+            recorded: self.recorded(),
+            is_value: self.state().is_value(),
+            state: self.state(),
+            reduction: self.reduction(),
+            watches: self.watches(),
+        }
+    }
+
+    /// The watched bindings, evaluated in the current state.
+    pub fn watches(&self) -> Vec<Watch> {
+        let previous = self.previous.as_ref();
+        (self.watches.iter())
+            .map(|name| {
+                let value = watch_value(self.state(), name);
+                let changed = previous.is_some_and(|state| watch_value(state, name) != value);
+                Watch {
+                    name: name.clone(),
+                    value,
+                    changed,
+                }
+            })
+            .collect()
+    }
+
+    /// Watch the store binding `name`. Watching it twice does nothing.
+    pub fn watch(&mut self, name: &str) {
+        if !self.watches.iter().any(|x| x == name) {
+            self.watches.push(name.to_owned());
+        }
+    }
+
+    pub fn unwatch(&mut self, name: &str) {
+        self.watches.retain(|x| x != name);
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    /// Add a breakpoint, and give its index.
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> usize {
+        self.breakpoints.push(breakpoint);
+        self.breakpoints.len() - 1
+    }
+
+    /// Remove the breakpoint at `index`. The ones after it move down by one.
+    pub fn remove_breakpoint(&mut self, index: usize) -> Option<Breakpoint> {
+        (index < self.breakpoints.len()).then(|| self.breakpoints.remove(index))
+    }
+
+    /// The reduction that led to the current state, unless it is the first one.
+    pub fn reduction(&self) -> Option<&Reduction> {
+        self.reductions.get(self.current.checked_sub(1)?)
+    }
+
+    /// Take one step. A value stays where it is; a stuck state gives the error and stays too.
+    pub fn step(&mut self) -> Result<&TermEval, Error> {
+        if !self.state.is_value() {
+            self.advance()?;
+        }
+        Ok(self.state())
+    }
+
+    /// Go back one step, if not at the start.
+    pub fn step_back(&mut self) -> Result<&TermEval, Error> {
+        self.goto(self.current.saturating_sub(1))
+    }
+
+    /// Go to the state after `step` steps, evaluating up to it if it is not recorded yet. Stops
+    /// early at a value, and at the state that gets stuck, with its error.
+    pub fn goto(&mut self, step: usize) -> Result<&TermEval, Error> {
+        if step < self.current {
+            self.previous = match step.checked_sub(1) {
+                Some(before) => Some(self.replay(before)?),
+                None => None,
+            };
+            self.state = self.replay(step)?;
+            self.current = step;
+        }
+        while self.current < step && !self.state.is_value() {
+            self.advance()?;
+        }
+        Ok(self.state())
+    }
+
+    /// Take the step after the current state: load it if it is a checkpoint, replay it if it
+    /// is recorded, and record it otherwise.
+    fn advance(&mut self) -> Result<(), Error> {
+        let step = self.current + 1;
+        let next = match self.checkpoints.iter().find(|(at, _)| *at == step) {
+            Some((_, state)) => state.clone(),
+            None if step < self.recorded() => one_step_eval_traced(self.state.clone())?.0,
+            None => {
+                let (next, reduction) = one_step_eval_traced(self.state.clone())?;
+                self.reductions.extend(reduction);
+                if step.is_multiple_of(CHECKPOINT_INTERVAL) {
+                    self.checkpoints.push((step, next.clone()));
+                }
+                next
+            }
+        };
+        self.previous = Some(std::mem::replace(&mut self.state, next));
+        self.current = step;
+        Ok(())
+    }
+
+    /// The recorded state after `step` steps, replayed from the last checkpoint up to it.
+    fn replay(&self, step: usize) -> Result<TermEval, Error> {
+        let (at, state) = (self.checkpoints.iter().rev())
+            .find(|(at, _)| *at <= step)
+            .unwrap();
+        let mut state = state.clone();
+        for _ in *at..step {
+            state = one_step_eval_traced(state)?.0;
+        }
+        Ok(state)
+    }
+
+    /// Step until a step hits a breakpoint, the term is a value, or one of the `limits` is
+    /// reached. The garbage is not collected. A state that gets stuck gives its error, like
+    /// `step`.
+    pub fn run(&mut self, limits: &Limits) -> Result<Stop, Error> {
+        let start = now_millis();
+        let mut steps = 0;
+        loop {
+            if self.state().is_value() {
+                return Ok(Stop::Value);
+            }
+            let store = self.state().store().len();
+            if let Some(limit) = limits.reached(steps, store, start) {
+                return Ok(Stop::OutOfFuel(limit));
+            }
+            self.step()?;
+            steps += 1;
+            let reduction = self.reduction();
+            let hit = (self.breakpoints.iter())
+                .position(|breakpoint| reduction.is_some_and(|r| breakpoint.hit(r)));
+            if let Some(index) = hit {
+                return Ok(Stop::Breakpoint(index));
+            }
+        }
+    }
+
+    /// Go to the recorded step that created the store binding `name`.
+    pub fn goto_binding(&mut self, name: &str) -> Result<&TermEval, Error> {
+        let step = if self.checkpoints[0].1.store().contains(name) {
+            Some(0)
+        } else {
+            (self.reductions.iter())
+                .position(|reduction| reduction.diff.added.iter().any(|x| x == name))
+                .map(|i| i + 1)
+        };
+        match step {
+            Some(step) => self.goto(step),
+            None => {
+                let TermCtx(span, _, node, _) = self.state.term();
+                Err(Error::from(EvaluateError {
+                    code: ErrorCode::UnknownBinding,
+                    message: format!("No step created the binding {}", name),
+                    start: span.start,
+                    end: span.end,
+                    location: None,
+                    node: *node,
+                    labels: vec![],
+                }))
+            }
+        }
+    }
+
+    /// Collect the garbage in the store of the current state, which becomes a checkpoint. The
+    /// recorded states before it keep their stores; the ones after it are forgotten, since
+    /// they would replay a store that was not collected.
+    pub fn collect_garbage(&mut self) -> &GcStats {
+        self.reductions.truncate(self.current);
+        self.checkpoints.retain(|(at, _)| *at < self.current);
+        self.state.collect_garbage();
+        self.checkpoints.push((self.current, self.state.clone()));
+        self.state.gc_stats()
+    }
+}
+
+impl From<TermEval> for Session {
+    fn from(term_eval: TermEval) -> Self {
+        Session {
+            checkpoints: vec![(0, term_eval.clone())],
+            reductions: vec![],
+            current: 0,
+            state: term_eval,
+            previous: None,
+            breakpoints: vec![],
+            watches: vec![],
+        }
+    }
+}
+
+fn watch_value(state: &TermEval, name: &str) -> Option<String> {
+    let store = state.store();
+    match store.answer_of(name) {
+        Some(answer) => Some(answer.to_string()),
+        // e.g. a pair of a thunk that is not forced
+        None => store.lookup(name).map(formatter::format_termctx),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::eval::Answer;
+    use crate::syntax::{parse_program, Qualifier};
+
+    #[test]
+    fn test_session() {
+        let input = std::fs::read_to_string("examples/fact.txt").unwrap();
+        let mut session = Session::new(parse_program(&input).unwrap());
+        for _ in 0..5 {
+            session.step().unwrap();
+        }
+        assert_eq!(session.current(), 5);
+        let fifth = format!("{:?}", session.state());
+        session.step_back().unwrap();
+        session.step_back().unwrap();
+        assert_eq!(session.current(), 3);
+        // stepping forward again replays the same states
+        session.step().unwrap();
+        session.step().unwrap();
+        assert_eq!(format!("{:?}", session.state()), fifth);
+
+        let answer = session.goto(100_000).unwrap().answer();
+        assert_eq!(answer, Some(Answer::Integer(Qualifier::Nop, 6)));
+        let last = session.current();
+        println!("{} steps", last);
+        assert_eq!(
+            session.step().unwrap().answer(),
+            Some(Answer::Integer(Qualifier::Nop, 6))
+        );
+        assert_eq!(session.current(), last);
+        session.goto(0).unwrap();
+        assert_eq!(session.step_back().unwrap().answer(), None);
+        assert_eq!(session.current(), 0);
+
+        session.goto_binding("%x2").unwrap();
+        let step = session.current();
+        assert!(session.state().store().contains("%x2"));
+        // the reduction that created it
+        let reduction = session.reduction().unwrap();
+        assert!(reduction.diff.added.contains(&"%x2".to_string()));
+        session.step_back().unwrap();
+        assert!(!session.state().store().contains("%x2"));
+        assert_eq!(session.current(), step - 1);
+        let error = session.goto_binding("%nope").unwrap_err();
+        assert_eq!(error.code(), ErrorCode::UnknownBinding);
+        assert_eq!(session.current(), step - 1);
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        let input = std::fs::read_to_string("examples/fact.txt").unwrap();
+        let mut session = Session::new(parse_program(&input).unwrap());
+        let mut states = vec![format!("{:?}", session.state())];
+        while !session.state().is_value() {
+            states.push(format!("{:?}", session.step().unwrap()));
+        }
+        let last = session.current();
+        assert!(last > 2 * CHECKPOINT_INTERVAL, "{} steps", last);
+        assert_eq!(session.checkpoints.len(), last / CHECKPOINT_INTERVAL + 1);
+        // the states between the checkpoints are replayed
+        for step in [130, 129, 3, CHECKPOINT_INTERVAL, last, 0, last - 1] {
+            assert_eq!(format!("{:?}", session.goto(step).unwrap()), states[step]);
+        }
+        session.goto(100).unwrap();
+        let watches = session.watches();
+        session.goto(20).unwrap();
+        session.goto(100).unwrap();
+        assert_eq!(session.watches(), watches);
+
+        // the collected state is kept as it is
+        session.collect_garbage();
+        let collected = format!("{:?}", session.state());
+        assert_ne!(collected, states[100]);
+        assert_eq!(session.recorded(), 101);
+        session.goto(20).unwrap();
+        assert_eq!(format!("{:?}", session.goto(100).unwrap()), collected);
+        session.goto(10).unwrap();
+        session.goto(101).unwrap();
+        assert_eq!(format!("{:?}", session.step_back().unwrap()), collected);
+    }
+
+    #[test]
+    fn test_session_stuck() {
+        let mut session = Session::new(parse_program("iszero(diff(1, ?x))").unwrap());
+        assert!(session.goto(10).is_err());
+        let stuck = session.current();
+        assert!(session.step().is_err());
+        assert_eq!(session.current(), stuck);
+        println!("{}", serde_json::to_string(&session.snapshot()).unwrap());
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let input = std::fs::read_to_string("examples/fact.txt").unwrap();
+        let mut session = Session::new(parse_program(&input).unwrap());
+        let start = input.find("$iszero(x) { 1 }").unwrap();
+        let span = Context {
+            start,
+            end: start + "$iszero(x)".len(),
+        };
+        assert_eq!(session.add_breakpoint(Breakpoint::Span(span)), 0);
+        let limits = Limits::default();
+        let mut hits = 0;
+        loop {
+            match session.run(&limits).unwrap() {
+                Stop::Breakpoint(0) => {
+                    let redex = session.reduction().unwrap().redex;
+                    println!("{}: {}", session.current(), &input[redex.start..redex.end]);
+                    assert!(span.start <= redex.start && redex.end <= span.end);
+                    hits += 1;
+                }
+                Stop::Value => break,
+                stop => panic!("unexpected stop {:?}", stop),
+            }
+        }
+        // `fact` is called with 3, 2, 1 and 0
+        assert!(hits >= 4, "{} hits", hits);
+        assert_eq!(
+            session.state().answer(),
+            Some(Answer::Integer(Qualifier::Nop, 6))
+        );
+
+        // running again replays the recorded steps
+        session.goto(0).unwrap();
+        assert_eq!(session.remove_breakpoint(0), Some(Breakpoint::Span(span)));
+        assert!(session.remove_breakpoint(0).is_none());
+        let limits = Limits {
+            max_steps: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(session.run(&limits).unwrap(), Stop::OutOfFuel(Limit::Steps));
+        assert_eq!(session.current(), 10);
+    }
+
+    #[test]
+    fn test_watches() {
+        let input = "let h = $1 in let y = iszero(h) in y";
+        let mut session = Session::new(parse_program(input).unwrap());
+        session.watch("%x1");
+        session.watch("%x1");
+        session.add_breakpoint(Breakpoint::Binding("%x1".to_string()));
+        let watch = |session: &Session| {
+            let watches = session.watches();
+            assert_eq!(watches.len(), 1);
+            println!("{}: {:?}", session.current(), watches[0]);
+            (watches[0].value.clone(), watches[0].changed)
+        };
+        assert_eq!(watch(&session), (None, false));
+        // created
+        assert_eq!(
+            session.run(&Limits::default()).unwrap(),
+            Stop::Breakpoint(0)
+        );
+        assert_eq!(watch(&session), (Some("$1".to_string()), true));
+        session.step().unwrap();
+        assert_eq!(watch(&session), (Some("$1".to_string()), false));
+        // consumed by `iszero`
+        assert_eq!(
+            session.run(&Limits::default()).unwrap(),
+            Stop::Breakpoint(0)
+        );
+        assert_eq!(session.reduction().unwrap().diff.consumed, ["%x1"]);
+        assert_eq!(watch(&session), (None, true));
+        assert_eq!(session.run(&Limits::default()).unwrap(), Stop::Value);
+        let json = serde_json::to_string(&session.snapshot()).unwrap();
+        println!("{}", json);
+        assert!(json.contains("\"watches\":[{\"name\":\"%x1\""));
+        session.unwatch("%x1");
+        assert!(session.watches().is_empty());
+    }
+}
@@ -0,0 +1,122 @@
+use crate::syntax::Context;
+use serde::{Deserialize, Serialize};
+
+/// A position in the source. All fields count from 0; `Display` shows them from 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Position {
+    pub line: usize,
+    /// The column in characters (Unicode scalar values).
+    pub column: usize,
+    /// The column in UTF-8 bytes.
+    pub utf8_column: usize,
+    /// The column in UTF-16 code units, as used by JavaScript strings and editors.
+    pub utf16_column: usize,
+}
+
+/// The line/column extent of a span.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Location {
+    pub start: Position,
+    pub end: Position,
+}
+
+/// Converts byte offsets into a source text, such as those in `syntax::Context`, to lines and
+/// columns.
+pub struct SourceMap<'a> {
+    source: &'a str,
+    /// The byte offset at which each line starts.
+    line_starts: Vec<usize>,
+}
+
+impl<'a> SourceMap<'a> {
+    pub fn new(source: &'a str) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        SourceMap {
+            source,
+            line_starts,
+        }
+    }
+
+    pub fn source(&self) -> &'a str {
+        self.source
+    }
+
+    /// The position of a byte offset. Offsets past the end are clamped to the end of the
+    /// source, and offsets inside a character to the start of that character.
+    pub fn position(&self, offset: usize) -> Position {
+        let mut offset = offset.min(self.source.len());
+        while !self.source.is_char_boundary(offset) {
+            offset -= 1;
+        }
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        let prefix = &self.source[self.line_starts[line]..offset];
+        Position {
+            line,
+            column: prefix.chars().count(),
+            utf8_column: prefix.len(),
+            utf16_column: prefix.encode_utf16().count(),
+        }
+    }
+
+    pub fn location(&self, start: usize, end: usize) -> Location {
+        Location {
+            start: self.position(start),
+            end: self.position(end),
+        }
+    }
+
+    pub fn span(&self, span: &Context) -> Location {
+        self.location(span.start, span.end)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::syntax::parse_program;
+    use crate::typing::type_check;
+
+    #[test]
+    fn test_ascii() {
+        let map = SourceMap::new("let x = 1 in\nx\n");
+        let pos = map.position(13);
+        assert_eq!((pos.line, pos.column), (1, 0));
+        let pos = map.position(4);
+        assert_eq!((pos.line, pos.column, pos.utf16_column), (0, 4, 4));
+        // the end of the input is on the empty last line
+        let pos = map.position(100);
+        assert_eq!((pos.line, pos.column), (2, 0));
+    }
+
+    #[test]
+    fn test_unicode() {
+        // `é` takes 2 bytes and 1 UTF-16 unit, `🦀` takes 4 bytes and 2 UTF-16 units
+        let source = "// é🦀\nlet x = 1 in x /* 🦀 */ ";
+        let map = SourceMap::new(source);
+        let pos = map.position(source.find('\n').unwrap());
+        println!("{:?}", pos);
+        assert_eq!(pos.column, 5);
+        assert_eq!(pos.utf8_column, 9);
+        assert_eq!(pos.utf16_column, 6);
+        let end = source.rfind('*').unwrap();
+        let pos = map.position(end);
+        assert_eq!((pos.line, pos.column, pos.utf16_column), (1, 20, 21));
+        // an offset inside `🦀` is moved to its start
+        assert_eq!(map.position(6), map.position(5));
+    }
+
+    #[test]
+    fn test_error_location() {
+        let input = include_str!("../examples/io_error3.txt");
+        let error = type_check(&parse_program(input).unwrap()).unwrap_err();
+        let error = error.with_source_map(&SourceMap::new(input));
+        println!("{}", error);
+        let display = error.to_string();
+        assert!(display.starts_with("Type error [E0004] in [Ln 11, Col 7]"));
+        assert!(display.contains("[Ln 10, Col 15] h is first consumed here"));
+        let json = serde_json::to_value(&error).unwrap();
+        assert_eq!(json["TypeError"]["location"]["start"]["line"], 10);
+    }
+}
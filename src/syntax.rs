@@ -1,8 +1,11 @@
-use crate::error::Error;
+use crate::error::{Error, ErrorCode, ParseError};
+use crate::source_map::SourceMap;
+use pest::error::{Error as PestError, ErrorVariant};
 use pest::iterators::{Pair, Pairs};
-use pest::{Parser, Span};
+use pest::{Parser, Position, Span};
 use pest_derive::Parser;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
 use std::fmt;
 
 #[derive(Parser)]
@@ -15,19 +18,109 @@ pub struct Context {
     pub end: usize,
 }
 
+/// Identifies a node of a program. Unlike spans, which a term shares with its enclosing
+/// brackets or ascription, ids are unique: the parser numbers the nodes in preorder, from 0.
+#[derive(
+    Clone, Serialize, Deserialize, PartialEq, Eq, Hash, Copy, Debug, Default, PartialOrd, Ord,
+)]
+pub struct NodeId(pub u32);
+
+/// A term with the full extent of its source, its id, and the span of its keyword or operator
+/// if it has one: `let`, `if`, `fix`, `diff`, `iszero`, the `|x: T|` of an abstraction, the
+/// comma of a pair, the `(e)` of an application, or the `T` of an ascription.
 #[derive(Clone, Serialize, Deserialize)]
-pub struct TermCtx(pub Context, pub Term);
+pub struct TermCtx(
+    pub Context,
+    pub Term,
+    #[serde(default)] pub NodeId,
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub Option<Context>,
+);
 
 impl fmt::Debug for TermCtx {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let TermCtx(Context { start, end }, _) = self;
+        let TermCtx(Context { start, end }, _, NodeId(id), key) = self;
+        let key = match key {
+            Some(Context { start, end }) => format!(" key: {}~{}", start, end),
+            None => String::new(),
+        };
         f.debug_tuple("")
-            .field(&format!("context: {}~{}", start, end))
+            .field(&format!("#{} context: {}~{}{}", id, start, end, key))
             .field(&self.1)
             .finish()
     }
 }
 
+impl TermCtx {
+    /// A node whose id is assigned later, by `assign_ids`.
+    pub fn new(context: Context, term: Term) -> Self {
+        TermCtx(context, term, NodeId::default(), None)
+    }
+
+    /// The node with the span of its keyword or operator.
+    pub fn with_key(mut self, key: Context) -> Self {
+        self.3 = Some(key);
+        self
+    }
+
+    /// The span that diagnostics about the node itself point at: its keyword or operator, or
+    /// else the whole node.
+    pub fn key_span(&self) -> Context {
+        self.3.unwrap_or(self.0)
+    }
+
+    /// Number the nodes of the term in preorder, starting from `next`, and return the next
+    /// free id.
+    pub fn assign_ids(&mut self, next: u32) -> u32 {
+        self.2 = NodeId(next);
+        let mut next = next + 1;
+        for child in self.1.children_mut() {
+            next = child.assign_ids(next);
+        }
+        next
+    }
+
+    /// The variables that occur free in the term.
+    pub fn free_variables(&self) -> BTreeSet<String> {
+        let mut free = BTreeSet::new();
+        self.collect_free(&mut vec![], &mut free);
+        free
+    }
+
+    fn collect_free(&self, bound: &mut Vec<String>, free: &mut BTreeSet<String>) {
+        match &self.1 {
+            Term::Variable(x) => {
+                if !bound.contains(x) {
+                    free.insert(x.clone());
+                }
+            }
+            Term::Abstraction(_, x, _, body) => {
+                bound.push(x.clone());
+                body.collect_free(bound, free);
+                bound.pop();
+            }
+            Term::Let(x, t1, t2) => {
+                t1.collect_free(bound, free);
+                bound.push(x.clone());
+                t2.collect_free(bound, free);
+                bound.pop();
+            }
+            Term::Letc(x1, x2, t1, t2) => {
+                t1.collect_free(bound, free);
+                bound.push(x1.clone());
+                bound.push(x2.clone());
+                t2.collect_free(bound, free);
+                bound.pop();
+                bound.pop();
+            }
+            term => {
+                for child in term.children() {
+                    child.collect_free(bound, free);
+                }
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ArithOp {
     Diff,
@@ -49,6 +142,9 @@ pub enum Term {
     Fix(Box<TermCtx>), // all recursive functions are unrestricted data structures
     Let(String, Box<TermCtx>, Box<TermCtx>),
     Letc(String, String, Box<TermCtx>, Box<TermCtx>),
+    Error(String), // a malformed region skipped by the recovering parser, with its source text
+    Hole(Option<String>), // `?` or `?name`
+    Ascription(Box<TermCtx>, Box<Type>), // `(e : T)`, and the `T` of `let x: T = e in ...`
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
@@ -60,17 +156,352 @@ pub enum Qualifier {
     Linear,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub enum Pretype {
     Boolean,
     Integer,
     Function(Box<Type>, Box<Type>),
     Compound(Box<Type>, Box<Type>),
+    Unknown, // the type of a malformed term; it is compatible with every other pretype
 }
 
+/// Parse a program, failing on the first syntax error. Unlike `parse_program_recovering`, it
+/// does not look for repairs, and the character that marks them is an error.
 pub fn parse_program(input: &str) -> Result<TermCtx, Error> {
-    let pairs: Pairs<Rule> = IdentParser::parse(Rule::program, input)?;
-    Ok(parse_pairs(pairs)?.0)
+    let map = SourceMap::new(input);
+    if let Some(start) = input.find(RECOVERED) {
+        let error = Error::from(ParseError {
+            code: ErrorCode::SyntaxError,
+            message: format!("unexpected character {:?}", RECOVERED),
+            start,
+            end: start + RECOVERED.len_utf8(),
+            location: None,
+        });
+        return Err(error.with_source_map(&map));
+    }
+    let pairs = match IdentParser::parse(Rule::program, input) {
+        Ok(pairs) => pairs,
+        Err(error) => {
+            let at = match error.location {
+                pest::error::InputLocation::Pos(i) => i,
+                pest::error::InputLocation::Span((i, _)) => i,
+            };
+            return Err(syntax_error(input, error.variant, at).with_source_map(&map));
+        }
+    };
+    let (mut term, _) = parse_pairs(pairs).map_err(|e| e.with_source_map(&map))?;
+    term.assign_ids(0);
+    Ok(term)
+}
+
+/// Parse a program, recovering from syntax errors.
+///
+/// Malformed or missing regions show up as `Term::Error` nodes in the returned term, and every
+/// syntax error met on the way is reported, in source order. The term is always usable: in the
+/// worst case the whole program becomes a single `Term::Error`.
+pub fn parse_program_recovering(input: &str) -> (TermCtx, Vec<Error>) {
+    let (mut term, errors) = recover(input);
+    term.assign_ids(0);
+    let map = SourceMap::new(input);
+    let errors = errors
+        .into_iter()
+        .map(|e| e.with_source_map(&map))
+        .collect();
+    (term, errors)
+}
+
+fn recover(input: &str) -> (TermCtx, Vec<Error>) {
+    let mut repairs = Repairs::new(input);
+    let mut errors: Vec<Error> = Vec::new();
+    let mut stalls = 0;
+    for _ in 0..MAX_REPAIRS {
+        let source = repairs.source();
+        let error = match IdentParser::parse(Rule::program, &source) {
+            Ok(pairs) => match parse_pairs(pairs) {
+                Ok((mut term, _)) => {
+                    repairs.relocate(&mut term, &mut errors);
+                    errors.sort_by_key(error_start);
+                    return (term, errors);
+                }
+                Err(Error::ParseError(mut error)) => {
+                    error.start = repairs.to_original(error.start, false);
+                    error.end = repairs.to_original(error.end, true).max(error.start);
+                    errors.push(Error::ParseError(error));
+                    break;
+                }
+                Err(error) => {
+                    errors.push(error);
+                    break;
+                }
+            },
+            Err(error) => error,
+        };
+        let pos = match error.location {
+            pest::error::InputLocation::Pos(i) => i,
+            pest::error::InputLocation::Span((i, _)) => i,
+        };
+        let at = repairs.to_original(pos, false);
+        if errors.iter().all(|e| error_start(e) != at) {
+            errors.push(syntax_error(input, error.variant, at));
+        }
+        match repairs.best_repair(at, pos, stalls < MAX_STALLS) {
+            Some((next, progressed)) => {
+                stalls = if progressed { 0 } else { stalls + 1 };
+                repairs = next;
+            }
+            None => break,
+        }
+    }
+    if errors.is_empty() {
+        errors.push(Error::from(ParseError {
+            code: ErrorCode::SyntaxError,
+            message: "unable to recover from syntax errors".to_string(),
+            start: 0,
+            end: input.len(),
+            location: None,
+        }));
+    }
+    errors.sort_by_key(error_start);
+    let context = Context {
+        start: 0,
+        end: input.len(),
+    };
+    (TermCtx::new(context, Term::Error(input.to_owned())), errors)
+}
+
+/// The character spliced in by the recovering parser, matched by the `recovered` rule.
+const RECOVERED: char = '\u{FFFD}';
+/// Give up on recovery after this many repairs.
+const MAX_REPAIRS: usize = 64;
+/// The number of consecutive repairs that may leave the error position unchanged, e.g. when
+/// completing an unfinished program at the end of the input.
+const MAX_STALLS: usize = 4;
+/// Tokens inserted at the error position when looking for a repair, in order of preference.
+const INSERTIONS: [&str; 8] = [" \u{FFFD} ", " in ", ")", "}", ">", ",", "|", " { "];
+/// Tokens that usually close the construct a malformed region belongs to.
+const SYNC_TOKENS: [&str; 6] = ["in", "else", ")", "}", ">", ","];
+
+/// A single repair of the original input: `removed` bytes at `start` are replaced by `inserted`.
+#[derive(Clone)]
+struct Repair {
+    start: usize,
+    removed: usize,
+    inserted: String,
+}
+
+/// The original input together with the repairs applied to it so far.
+#[derive(Clone)]
+struct Repairs<'a> {
+    input: &'a str,
+    edits: Vec<Repair>,
+}
+
+impl<'a> Repairs<'a> {
+    fn new(input: &'a str) -> Self {
+        Repairs {
+            input,
+            edits: Vec::new(),
+        }
+    }
+
+    /// Add a repair, unless it overlaps text removed by an earlier one.
+    fn with(&self, edit: Repair) -> Option<Self> {
+        let i = self.edits.partition_point(|e| e.start <= edit.start);
+        let before = i.checked_sub(1).map(|j| &self.edits[j]);
+        if before.is_some_and(|e| e.start + e.removed > edit.start)
+            || self
+                .edits
+                .get(i)
+                .is_some_and(|e| edit.start + edit.removed > e.start)
+        {
+            return None;
+        }
+        let mut next = self.clone();
+        next.edits.insert(i, edit);
+        Some(next)
+    }
+
+    fn source(&self) -> String {
+        let mut s = String::new();
+        let mut cursor = 0;
+        for edit in self.edits.iter() {
+            s.push_str(&self.input[cursor..edit.start]);
+            s.push_str(&edit.inserted);
+            cursor = edit.start + edit.removed;
+        }
+        s.push_str(&self.input[cursor..]);
+        s
+    }
+
+    /// Map an offset in the repaired source back to the original input. A position inside
+    /// inserted text maps to the start (or, for the end of a span, the end) of the repair.
+    fn to_original(&self, pos: usize, is_end: bool) -> usize {
+        let mut repaired = 0;
+        let mut original = 0;
+        for edit in self.edits.iter() {
+            let copied = edit.start - original;
+            if pos < repaired + copied || (is_end && pos == repaired + copied) {
+                return original + pos - repaired;
+            }
+            repaired += copied;
+            let inserted = edit.inserted.len();
+            if pos < repaired + inserted || (is_end && inserted > 0 && pos == repaired + inserted) {
+                return if is_end {
+                    edit.start + edit.removed
+                } else {
+                    edit.start
+                };
+            }
+            repaired += inserted;
+            original = edit.start + edit.removed;
+        }
+        (original + pos - repaired).min(self.input.len())
+    }
+
+    /// Try the candidate repairs at the original offset `at` (the repaired offset `pos`) and
+    /// return the one that lets the parser get furthest, and whether it moved the error forward.
+    fn best_repair(&self, at: usize, pos: usize, allow_stall: bool) -> Option<(Self, bool)> {
+        let token = token_len(self.input, at);
+        let mut candidates: Vec<Repair> = INSERTIONS
+            .iter()
+            .map(|s| Repair {
+                start: at,
+                removed: 0,
+                inserted: s.to_string(),
+            })
+            .collect();
+        if token > 0 {
+            candidates.push(Repair {
+                start: at,
+                removed: token,
+                inserted: RECOVERED.to_string(),
+            });
+            candidates.push(Repair {
+                start: at,
+                removed: token,
+                inserted: String::new(),
+            });
+            let sync = sync_point(self.input, at + token);
+            if sync > at + token {
+                candidates.push(Repair {
+                    start: at,
+                    removed: sync - at,
+                    inserted: String::new(),
+                });
+            }
+        }
+
+        let mut best: Option<(Self, usize)> = None;
+        let mut stall: Option<Self> = None;
+        for candidate in candidates {
+            let inserted = candidate.inserted.len();
+            let repairs = match self.with(candidate) {
+                Some(repairs) => repairs,
+                None => continue,
+            };
+            let source = repairs.source();
+            let error_pos = match IdentParser::parse(Rule::program, &source) {
+                Ok(_) => return Some((repairs, true)),
+                Err(error) => match error.location {
+                    pest::error::InputLocation::Pos(i) => i,
+                    pest::error::InputLocation::Span((i, _)) => i,
+                },
+            };
+            let reached = repairs.to_original(error_pos, false);
+            if reached > at && best.as_ref().is_none_or(|(_, r)| reached > *r) {
+                best = Some((repairs, reached));
+            } else if allow_stall && stall.is_none() && inserted > 0 && error_pos >= pos + inserted
+            {
+                stall = Some(repairs);
+            }
+        }
+        match best {
+            Some((repairs, _)) => Some((repairs, true)),
+            None => stall.map(|repairs| (repairs, false)),
+        }
+    }
+
+    /// Move the spans of a term parsed from the repaired source back onto the original input,
+    /// filling in the source text of `Term::Error` nodes.
+    fn relocate(&self, term_ctx: &mut TermCtx, errors: &mut Vec<Error>) {
+        let TermCtx(context, term, _, key) = term_ctx;
+        if let Some(key) = key {
+            let start = self.to_original(key.start, false);
+            let end = self.to_original(key.end, true).max(start);
+            *key = Context { start, end };
+        }
+        let start = self.to_original(context.start, false);
+        let end = self.to_original(context.end, true).max(start);
+        *context = Context { start, end };
+        if let Term::Error(text) = term {
+            *text = self.input[start..end].to_owned();
+            // The replacement character was typed in, not spliced in by a repair.
+            if text.contains(RECOVERED) && errors.iter().all(|e| error_start(e) != start) {
+                errors.push(Error::from(ParseError {
+                    code: ErrorCode::SyntaxError,
+                    message: format!("unexpected character {:?}", RECOVERED),
+                    start,
+                    end,
+                    location: None,
+                }));
+            }
+        }
+        for child in term.children_mut() {
+            self.relocate(child, errors);
+        }
+    }
+}
+
+/// The length of the token starting at `at`, or 0 at the end of the input.
+fn token_len(input: &str, at: usize) -> usize {
+    let rest = &input[at..];
+    let is_ident = |c: char| c == '_' || c.is_ascii_alphanumeric();
+    match rest.chars().next() {
+        None => 0,
+        Some(c) if is_ident(c) => rest.find(|c| !is_ident(c)).unwrap_or(rest.len()),
+        Some(_) if rest.starts_with("->") => 2,
+        Some(c) => c.len_utf8(),
+    }
+}
+
+/// The offset of the first synchronizing token at or after `from`.
+fn sync_point(input: &str, from: usize) -> usize {
+    let mut at = from;
+    while at < input.len() {
+        let rest = &input[at..];
+        let len = token_len(input, at);
+        if SYNC_TOKENS.iter().any(|&t| rest[..len] == *t) {
+            return at;
+        }
+        at += len;
+    }
+    input.len()
+}
+
+fn error_start(error: &Error) -> usize {
+    match error {
+        Error::ParseError(error) => error.start,
+        _ => 0,
+    }
+}
+
+/// Report a syntax error found in the repaired source at the original offset `at`.
+fn syntax_error(input: &str, variant: ErrorVariant<Rule>, at: usize) -> Error {
+    let variant = match variant {
+        ErrorVariant::ParsingError {
+            mut positives,
+            negatives,
+        } => {
+            positives.retain(|&r| r != Rule::recovered);
+            ErrorVariant::ParsingError {
+                positives,
+                negatives,
+            }
+        }
+        variant => variant,
+    };
+    let pos = Position::new(input, at).unwrap_or_else(|| Position::from_start(input));
+    PestError::new_from_pos(variant, pos).into()
 }
 
 impl Context {
@@ -94,40 +525,97 @@ impl<'a> From<Span<'a>> for Context {
     }
 }
 
-impl PartialEq for Pretype {
-    fn eq(&self, other: &Self) -> bool {
+impl Pretype {
+    /// Whether the two pretypes are equal where neither is `Unknown`, so that a malformed term
+    /// does not cause errors of its own. Unlike equality, this is not transitive.
+    pub fn compatible(&self, other: &Pretype) -> bool {
         match (self, other) {
-            (Pretype::Boolean, Pretype::Boolean) => true,
-            (Pretype::Integer, Pretype::Integer) => true,
-            (Pretype::Function(a1, b1), Pretype::Function(a2, b2)) => a1 == a2 && b1 == b2,
-            (Pretype::Compound(a1, b1), Pretype::Compound(a2, b2)) => a1 == a2 && b1 == b2,
-            _ => false,
+            (Pretype::Function(a1, b1), Pretype::Function(a2, b2))
+            | (Pretype::Compound(a1, b1), Pretype::Compound(a2, b2)) => {
+                a1.compatible(a2) && b1.compatible(b2)
+            }
+            (Pretype::Unknown, _) | (_, Pretype::Unknown) => true,
+            _ => self == other,
         }
     }
 }
 
-impl Eq for Pretype {}
+impl Type {
+    /// Whether the qualifiers are equal and the pretypes compatible, see `Pretype::compatible`.
+    pub fn compatible(&self, other: &Type) -> bool {
+        self.0 == other.0 && self.1.compatible(&other.1)
+    }
+}
+
+impl Term {
+    /// The direct subterms, from left to right.
+    pub fn children(&self) -> Vec<&TermCtx> {
+        match self {
+            Term::Variable(..)
+            | Term::Boolean(..)
+            | Term::Integer(..)
+            | Term::Error(..)
+            | Term::Hole(..) => vec![],
+            Term::Abstraction(_, _, _, t)
+            | Term::Fix(t)
+            | Term::Arith1(_, _, t)
+            | Term::Ascription(t, _) => vec![t],
+            Term::Compound(_, t1, t2)
+            | Term::Arith2(_, _, t1, t2)
+            | Term::Application(t1, t2)
+            | Term::Let(_, t1, t2)
+            | Term::Letc(_, _, t1, t2) => vec![t1, t2],
+            Term::Conditional(t1, t2, t3) => vec![t1, t2, t3],
+        }
+    }
+
+    /// The direct subterms, from left to right.
+    pub fn children_mut(&mut self) -> Vec<&mut TermCtx> {
+        match self {
+            Term::Variable(..)
+            | Term::Boolean(..)
+            | Term::Integer(..)
+            | Term::Error(..)
+            | Term::Hole(..) => vec![],
+            Term::Abstraction(_, _, _, t)
+            | Term::Fix(t)
+            | Term::Arith1(_, _, t)
+            | Term::Ascription(t, _) => vec![t],
+            Term::Compound(_, t1, t2)
+            | Term::Arith2(_, _, t1, t2)
+            | Term::Application(t1, t2)
+            | Term::Let(_, t1, t2)
+            | Term::Letc(_, _, t1, t2) => vec![t1, t2],
+            Term::Conditional(t1, t2, t3) => vec![t1, t2, t3],
+        }
+    }
+}
 
 fn parse_pairs(mut pairs: Pairs<Rule>) -> Result<(TermCtx, Pairs<Rule>), Error> {
     let pair1 = pairs.next().unwrap();
     let mut term1 = parse_pair(pair1)?;
 
     while let Some(Rule::application) = pairs.peek().map(|p| p.as_rule()) {
-        let pairs2 = pairs.next().unwrap().into_inner();
-        let (term2, _) = parse_pairs(pairs2)?;
-
-        let TermCtx(Context { start, .. }, _) = term1;
-        let TermCtx(Context { end, .. }, _) = term2;
-        
-        // option 1: i think it can be hard to read
-        let _context = Context { start, end };
+        let pair2 = pairs.next().unwrap();
+        let span2: Context = pair2.as_span().into();
+        let (term2, mut rest) = parse_pairs(pair2.into_inner())?;
+        // `f (e : T)`
+        let term2 = match rest.next() {
+            Some(typing) => {
+                let key = typing.as_span().into();
+                let ty = parse_typing(typing)?;
+                TermCtx::new(span2, Term::Ascription(Box::new(term2), Box::new(ty))).with_key(key)
+            }
+            None => term2,
+        };
 
-        // option 2:
-        let context = term2.0;
-        term1 = TermCtx(
-            context,
-            Term::Application(Box::new(term1), Box::new(term2)),
-        )
+        // from the function to the closing bracket of the argument
+        let context = Context {
+            start: term1.0.start,
+            end: span2.end,
+        };
+        term1 = TermCtx::new(context, Term::Application(Box::new(term1), Box::new(term2)))
+            .with_key(span2);
     }
 
     Ok((term1, pairs))
@@ -139,38 +627,63 @@ fn parse_pair(pair: Pair<Rule>) -> Result<TermCtx, Error> {
         Rule::variable => {
             let source = pair.as_span();
             let name = pair.as_str();
-            Ok(TermCtx(source.into(), Term::Variable(name.to_string())))
+            Ok(TermCtx::new(
+                source.into(),
+                Term::Variable(name.to_string()),
+            ))
         }
         Rule::conditional => {
+            let source = pair.as_span();
             let mut inner = pair.into_inner();
-            let kw_if = inner.next().unwrap();
-            let span1 = kw_if.as_span();
-            let span2 = inner.peek().unwrap().as_span();
+            let kw_if = inner.next().unwrap().as_span();
             let (term1, inner) = parse_pairs(inner)?;
             let (term2, inner) = parse_pairs(inner)?;
             let (term3, _) = parse_pairs(inner)?;
-            let source = span1.start_pos().span(&span2.end_pos());
-            Ok(TermCtx(
+            Ok(TermCtx::new(
                 source.into(),
                 Term::Conditional(Box::new(term1), Box::new(term2), Box::new(term3)),
-            ))
+            )
+            .with_key(kw_if.into()))
         }
         Rule::bracket => {
             let inner = pair.into_inner();
             let (term1, _) = parse_pairs(inner)?;
             Ok(term1)
         }
+        Rule::ascription => {
+            let span = pair.as_span();
+            let (term1, mut inner) = parse_pairs(pair.into_inner())?;
+            let typing = inner.next().unwrap();
+            let key = typing.as_span().into();
+            let typing = parse_typing(typing)?;
+            Ok(TermCtx::new(
+                span.into(),
+                Term::Ascription(Box::new(term1), Box::new(typing)),
+            )
+            .with_key(key))
+        }
         Rule::abstraction => parse_pair_abstraction(pair),
         Rule::fix => parse_pair_fix(pair),
         Rule::letv => parse_pair_let(pair),
         Rule::letc => parse_pair_letc(pair),
         Rule::diff => parse_pair_diff(pair),
         Rule::iszero => parse_pair_iszero(pair),
-        _ => Err(Error::ParseError {
+        Rule::hole => {
+            let name = &pair.as_str()[1..];
+            let name = (!name.is_empty()).then(|| name.to_owned());
+            Ok(TermCtx::new(pair.as_span().into(), Term::Hole(name)))
+        }
+        Rule::recovered => Ok(TermCtx::new(
+            pair.as_span().into(),
+            Term::Error(String::new()),
+        )),
+        _ => Err(Error::from(ParseError {
+            code: ErrorCode::SyntaxError,
             message: format!("Unexpected rule: {:?}", pair.as_rule()),
             start: pair.as_span().start(),
             end: pair.as_span().end(),
-        }),
+            location: None,
+        })),
     };
     term1
 }
@@ -188,31 +701,35 @@ fn parse_pair_literal(pair: Pair<Rule>) -> Result<TermCtx, Error> {
     match literal.as_rule() {
         Rule::boolean => {
             let value = string.parse::<bool>().unwrap();
-            Ok(TermCtx(source.into(), Term::Boolean(qualifier, value)))
+            Ok(TermCtx::new(source.into(), Term::Boolean(qualifier, value)))
         }
         Rule::number => {
             let value = string.parse::<i64>().unwrap();
-            Ok(TermCtx(source.into(), Term::Integer(qualifier, value)))
+            Ok(TermCtx::new(source.into(), Term::Integer(qualifier, value)))
         }
         Rule::compound => {
             let inner = literal.into_inner();
             let (first, mut inner) = parse_pairs(inner)?;
             let comma = inner.next().unwrap();
             let (second, _) = parse_pairs(inner)?;
-            Ok(TermCtx(
-                comma.as_span().into(),
+            Ok(TermCtx::new(
+                source.into(),
                 Term::Compound(qualifier, Box::new(first), Box::new(second)),
-            ))
+            )
+            .with_key(comma.as_span().into()))
         }
-        _ => Err(Error::ParseError {
+        _ => Err(Error::from(ParseError {
+            code: ErrorCode::SyntaxError,
             message: "unexpected literal".to_string(),
             start: source.start(),
             end: source.end(),
-        }),
+            location: None,
+        })),
     }
 }
 
 fn parse_pair_abstraction(pair: Pair<Rule>) -> Result<TermCtx, Error> {
+    let source = pair.as_span();
     let mut inner = pair.into_inner();
     let mut qualifier_ctx: Option<Span> = None;
     let qualifier = if let Rule::qualifier = inner.peek().unwrap().as_rule() {
@@ -225,103 +742,103 @@ fn parse_pair_abstraction(pair: Pair<Rule>) -> Result<TermCtx, Error> {
     let vertical_bar1 = inner.next().unwrap();
     let variable = inner.next().unwrap();
     let variable = variable.as_str();
-    if let Some(Rule::typing) = inner.peek().map(|p| p.as_rule()) {
-        let typing = parse_typing(inner.next().unwrap())?;
-        let vertical_bar2 = inner.next().unwrap();
-        let start = qualifier_ctx.map_or(vertical_bar1.as_span().start(), |p| p.start());
-        let end = vertical_bar2.as_span().end();
-        let source = Context { start, end };
-        let (term1, _) = parse_pairs(inner)?;
-        Ok(TermCtx(
-            source,
-            Term::Abstraction(
-                qualifier,
-                variable.to_string(),
-                Some(Box::new(typing)),
-                Box::new(term1),
-            ),
-        ))
-    } else {
-        let vertical_bar2 = inner.next().unwrap();
-        let start = vertical_bar1.as_span().start();
-        let end = vertical_bar2.as_span().end();
-        let source = Context { start, end };
-        let (term1, _) = parse_pairs(inner)?;
-        Ok(TermCtx(
-            source.into(),
-            Term::Abstraction(qualifier, variable.to_string(), None, Box::new(term1)),
-        ))
-    }
+    let typing = match inner.peek().map(|p| p.as_rule()) {
+        Some(Rule::typing) => Some(Box::new(parse_typing(inner.next().unwrap())?)),
+        _ => None,
+    };
+    let vertical_bar2 = inner.next().unwrap();
+    // the header `$|x: T|`
+    let start = qualifier_ctx.map_or(vertical_bar1.as_span().start(), |p| p.start());
+    let end = vertical_bar2.as_span().end();
+    let header = Context { start, end };
+    let (term1, _) = parse_pairs(inner)?;
+    Ok(TermCtx::new(
+        source.into(),
+        Term::Abstraction(qualifier, variable.to_string(), typing, Box::new(term1)),
+    )
+    .with_key(header))
 }
 
 fn parse_pair_diff(pair: Pair<Rule>) -> Result<TermCtx, Error> {
+    let source = pair.as_span();
     let mut inner = pair.into_inner();
-    let mut qualifier_ctx: Option<Span> = None;
     let qualifier = if let Rule::qualifier = inner.peek().unwrap().as_rule() {
-        let p = inner.next().unwrap();
-        qualifier_ctx = Some(p.as_span());
-        parse_qualifier(p)
+        parse_qualifier(inner.next().unwrap())
     } else {
         Qualifier::Nop
     };
-    let kw = inner.next().unwrap();
+    let kw = inner.next().unwrap().as_span();
     let (t1, mut inner) = parse_pairs(inner)?;
     let _comma = inner.next();
     let (t2, _) = parse_pairs(inner)?;
-    let kw = kw.as_span();
-    let start = qualifier_ctx.map_or(kw.start(), |x| x.start());
-    let end = kw.end();
-    let source = Context { start, end };
-    Ok(TermCtx(
-        source,
+    Ok(TermCtx::new(
+        source.into(),
         Term::Arith2(qualifier, ArithOp::Diff, Box::new(t1), Box::new(t2)),
-    ))
+    )
+    .with_key(kw.into()))
 }
 
 fn parse_pair_iszero(pair: Pair<Rule>) -> Result<TermCtx, Error> {
+    let source = pair.as_span();
     let mut inner = pair.into_inner();
-    let mut qualifier_ctx: Option<Span> = None;
     let qualifier = if let Rule::qualifier = inner.peek().unwrap().as_rule() {
-        let p = inner.next().unwrap();
-        qualifier_ctx = Some(p.as_span());
-        parse_qualifier(p)
+        parse_qualifier(inner.next().unwrap())
     } else {
         Qualifier::Nop
     };
-    let kw = inner.next().unwrap();
+    let kw = inner.next().unwrap().as_span();
     let (t1, mut _inner) = parse_pairs(inner)?;
-    let kw = kw.as_span();
-    let start = qualifier_ctx.map_or(kw.start(), |x| x.start());
-    let end = kw.end();
-    let source = Context { start, end };
-    Ok(TermCtx(
-        source,
+    Ok(TermCtx::new(
+        source.into(),
         Term::Arith1(qualifier, ArithOp::IsZero, Box::new(t1)),
-    ))
+    )
+    .with_key(kw.into()))
 }
 
 fn parse_pair_fix(pair: Pair<Rule>) -> Result<TermCtx, Error> {
+    let source = pair.as_span();
     let mut inner = pair.into_inner();
     let kw = inner.next().unwrap().as_span();
     let (t, _) = parse_pairs(inner)?;
-    Ok(TermCtx(kw.into(), Term::Fix(Box::new(t))))
+    Ok(TermCtx::new(source.into(), Term::Fix(Box::new(t))).with_key(kw.into()))
 }
 
 fn parse_pair_let(pair: Pair<Rule>) -> Result<TermCtx, Error> {
+    let source = pair.as_span();
     let mut inner = pair.into_inner();
     let kw_let = inner.next().unwrap().as_span();
     let var = inner.next().unwrap().as_str().to_owned();
+    // `let x: T = e in ...` checks `e` against `T`, from `T` to the end of `e`
+    let typing = match inner.peek().map(|p| p.as_rule()) {
+        Some(Rule::typing) => {
+            let pair = inner.next().unwrap();
+            Some((pair.as_span(), parse_typing(pair)?))
+        }
+        _ => None,
+    };
     let (t1, mut inner) = parse_pairs(inner)?;
+    let t1 = match typing {
+        Some((span, ty)) => {
+            let context = Context {
+                start: span.start(),
+                end: t1.0.end,
+            };
+            TermCtx::new(context, Term::Ascription(Box::new(t1), Box::new(ty)))
+                .with_key(span.into())
+        }
+        None => t1,
+    };
     let _kw_in = inner.next().unwrap().as_span();
     let (t2, _) = parse_pairs(inner)?;
 
-    Ok(TermCtx(
-        kw_let.into(),
-        Term::Let(var, Box::new(t1), Box::new(t2)),
-    ))
+    Ok(
+        TermCtx::new(source.into(), Term::Let(var, Box::new(t1), Box::new(t2)))
+            .with_key(kw_let.into()),
+    )
 }
 
 fn parse_pair_letc(pair: Pair<Rule>) -> Result<TermCtx, Error> {
+    let source = pair.as_span();
     let mut inner = pair.into_inner();
     let kw_let = inner.next().unwrap().as_span();
     let var1 = inner.next().unwrap().as_str().to_owned();
@@ -331,10 +848,11 @@ fn parse_pair_letc(pair: Pair<Rule>) -> Result<TermCtx, Error> {
     let _kw_in = inner.next().unwrap().as_span();
     let (t2, _) = parse_pairs(inner)?;
 
-    Ok(TermCtx(
-        kw_let.into(),
+    Ok(TermCtx::new(
+        source.into(),
         Term::Letc(var1, var2, Box::new(t1), Box::new(t2)),
-    ))
+    )
+    .with_key(kw_let.into()))
 }
 
 fn parse_typing(pair: Pair<Rule>) -> Result<Type, Error> {
@@ -354,11 +872,13 @@ fn parse_typing(pair: Pair<Rule>) -> Result<Type, Error> {
 
 fn parse_typing0(pair: Pair<Rule>) -> Result<Type, Error> {
     if pair.as_rule() != Rule::typing0 {
-        Err(Error::ParseError {
+        Err(Error::from(ParseError {
+            code: ErrorCode::SyntaxError,
             message: format!("Unexpected typing0 rule: {:?}", pair.as_rule()),
             start: pair.as_span().start(),
             end: pair.as_span().end(),
-        })
+            location: None,
+        }))
     } else {
         let mut inner = pair.into_inner();
         let qualifier = if inner.peek().map(|p| p.as_rule()) == Some(Rule::qualifier) {
@@ -385,20 +905,24 @@ fn parse_typing0(pair: Pair<Rule>) -> Result<Type, Error> {
                 )
             }
             _ => {
-                return Err(Error::ParseError {
+                return Err(Error::from(ParseError {
+                    code: ErrorCode::SyntaxError,
                     message: format!("Unexpected typing0: {:?}", pair.as_rule()),
                     start: pair.as_span().start(),
                     end: pair.as_span().end(),
-                })
+                    location: None,
+                }))
             }
         };
         if let Some(qualifier) = qualifier {
             if q != Qualifier::Nop {
-                return Err(Error::ParseError {
+                return Err(Error::from(ParseError {
+                    code: ErrorCode::IncompatibleQualifiers,
                     message: format!("Incompatible qualifiers {:?} and {:?}", q, qualifier),
                     start,
                     end,
-                });
+                    location: None,
+                }));
             }
             Ok(Type(qualifier, pretype))
         } else {
@@ -577,6 +1101,52 @@ mod test {
         println!("{:#?}", parse_program(input).unwrap());
     }
 
+    #[test]
+    fn test_recover_missing_term() {
+        let input = "let x = in let y = 1 in y";
+        let (term, errors) = parse_program_recovering(input);
+        println!("{:#?}", term);
+        assert_eq!(errors.len(), 1);
+        match term {
+            TermCtx(_, Term::Let(_, t1, _), ..) => match *t1 {
+                TermCtx(Context { start, end }, Term::Error(_), ..) => {
+                    assert_eq!((start, end), (8, 8))
+                }
+                t1 => panic!("expect an error node, given {:?}", t1),
+            },
+            term => panic!("expect let, given {:?}", term),
+        }
+    }
+
+    #[test]
+    fn test_recover_unfinished() {
+        let input = "let h = open(0) in let y = ";
+        let (term, errors) = parse_program_recovering(input);
+        println!("{:#?}", term);
+        println!("{:#?}", errors);
+        assert!(!errors.is_empty());
+        assert!(matches!(term, TermCtx(_, Term::Let(..), ..)));
+    }
+
+    #[test]
+    fn test_recover_multiple_errors() {
+        let input = "let a = 1 + 2 in let b = ) in f(a";
+        let (term, errors) = parse_program_recovering(input);
+        println!("{:#?}", term);
+        println!("{:#?}", errors);
+        let starts: Vec<usize> = errors.iter().map(error_start).collect();
+        assert_eq!(starts, vec![10, 25, 33]);
+        assert!(matches!(term, TermCtx(_, Term::Let(..), ..)));
+    }
+
+    #[test]
+    fn test_recover_well_formed() {
+        let input = "let <x, y> = $<1, 2> in diff(x, y)";
+        let (_, errors) = parse_program_recovering(input);
+        assert!(errors.is_empty());
+        assert!(parse_program("let x = in 1").is_err());
+    }
+
     #[test]
     fn test_arith() {
         let input = "$diff(1, 2)";
@@ -584,4 +1154,93 @@ mod test {
         println!("{:#?}", output);
         println!("{:#?}", parse_program(input).unwrap());
     }
+
+    #[test]
+    fn test_node_ids() {
+        let input = "let f = |x: int| x in f (1)";
+        let term = parse_program(input).unwrap();
+        println!("{:#?}", term);
+        let mut ids = vec![];
+        let mut stack = vec![&term];
+        while let Some(TermCtx(_, term, id, _)) = stack.pop() {
+            ids.push(id.0);
+            stack.extend(term.children().into_iter().rev());
+        }
+        // numbered in preorder
+        assert_eq!(ids, (0..6).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn test_free_variables() {
+        let input = "let f = |x: int| diff(x, y) in let <a, b> = p in f(a)";
+        let term = parse_program(input).unwrap();
+        let free: Vec<String> = term.free_variables().into_iter().collect();
+        assert_eq!(free, vec!["p", "y"]);
+    }
+
+    #[test]
+    fn test_spans() {
+        let input = "let p = $<1, f (x)> in fix |g: int -> int| diff(g (1), 2)";
+        let term = parse_program(input).unwrap();
+        println!("{:#?}", term);
+        let spans = |t: &TermCtx| {
+            let key = t.3.map(|key| &input[key.start..key.end]);
+            (&input[t.0.start..t.0.end], key)
+        };
+        assert_eq!(spans(&term), (input, Some("let")));
+        let TermCtx(_, Term::Let(_, pair, body), ..) = &term else {
+            panic!("expect let");
+        };
+        assert_eq!(spans(pair), ("$<1, f (x)>", Some(",")));
+        let TermCtx(_, Term::Compound(_, _, app), ..) = &**pair else {
+            panic!("expect a pair");
+        };
+        assert_eq!(spans(app), ("f (x)", Some("(x)")));
+        assert_eq!(spans(body), (&input[23..], Some("fix")));
+        let TermCtx(_, Term::Fix(abs), ..) = &**body else {
+            panic!("expect fix");
+        };
+        assert_eq!(spans(abs), (&input[27..], Some("|g: int -> int|")));
+        let TermCtx(_, Term::Abstraction(.., diff), ..) = &**abs else {
+            panic!("expect abstraction");
+        };
+        assert_eq!(spans(diff), ("diff(g (1), 2)", Some("diff")));
+    }
+
+    #[test]
+    fn test_parse_program_strict() {
+        for input in ["let x = in x", "iszero(1", "|x: int| diff(x, )", "<1, 2"] {
+            let error = parse_program(input).unwrap_err();
+            let (_, errors) = parse_program_recovering(input);
+            println!("{}", error);
+            assert_eq!(error.code(), errors[0].code());
+            assert_eq!(error_start(&error), error_start(&errors[0]));
+        }
+        assert!(parse_program("let x = 1 in x").is_ok());
+        // the repair marker is not valid input
+        for input in ["let x = \u{FFFD} in iszero(x)", "\u{FFFD}(1)"] {
+            let error = parse_program(input).unwrap_err();
+            assert_eq!(error.code(), ErrorCode::SyntaxError);
+            assert_eq!(error_start(&error), input.find('\u{FFFD}').unwrap());
+        }
+    }
+
+    #[test]
+    fn test_compatible() {
+        let int = Type(Qualifier::Nop, Pretype::Integer);
+        let pair = |ty1: &Type, ty2: &Type| {
+            Pretype::Compound(Box::new(ty1.clone()), Box::new(ty2.clone()))
+        };
+        let unknown = Type(Qualifier::Nop, Pretype::Unknown);
+        // equality is structural, and so transitive
+        assert_ne!(Pretype::Unknown, Pretype::Integer);
+        assert_ne!(Pretype::Unknown, Pretype::Boolean);
+        assert!(Pretype::Unknown.compatible(&Pretype::Integer));
+        assert!(Pretype::Boolean.compatible(&Pretype::Unknown));
+        assert!(!Pretype::Integer.compatible(&Pretype::Boolean));
+        assert!(pair(&int, &unknown).compatible(&pair(&int, &int)));
+        assert!(pair(&int, &unknown).compatible(&pair(&unknown, &unknown)));
+        assert_ne!(pair(&int, &unknown), pair(&int, &int));
+        assert!(!unknown.compatible(&Type(Qualifier::Linear, Pretype::Integer)));
+    }
 }
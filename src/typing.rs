@@ -1,221 +1,987 @@
-use crate::error::Error;
+use crate::derivation::{Assumption, Derivation};
+use crate::error::{Edit, Error, ErrorCode, Label, LintError, Severity, Suggestion, TypeError};
 use crate::formatter::{self, TermFormatter};
-use crate::syntax::{ArithOp, Context, Pretype, Qualifier, Term, TermCtx, Type};
+use crate::syntax::{ArithOp, Context, NodeId, Pretype, Qualifier, Term, TermCtx, Type};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-type TypeCtx = HashMap<String, Type>;
+/// A variable in the typing context, with the span of the term that introduced it.
+#[derive(Clone)]
+struct Binding {
+    ty: Type,
+    site: Context,
+}
+
+/// Where a linear variable was used up: `site` introduced it and `at` consumed it. A hole
+/// takes every live linear variable, but gives it back if it is used later on, or if the other
+/// branch of a conditional leaves it.
+#[derive(Clone, Copy)]
+struct Consumption {
+    site: Context,
+    at: Context,
+    /// The hole that took the variable, if it was not a real use.
+    hole: Option<NodeId>,
+}
+
+/// The typing context. A linear variable leaves `bindings` once it is used, and `consumed`
+/// keeps a record of where that happened, so that a second use can point at the first one.
+#[derive(Clone, Default)]
+struct TypeCtx {
+    bindings: HashMap<String, Binding>,
+    consumed: HashMap<String, Consumption>,
+}
+
+/// Whatever a new binding hides in the enclosing scope, given back by `TypeCtx::unbind`. The
+/// hidden variable is in scope again after the new one, so it still has to be consumed if it
+/// is linear, and it is not captured by a function whose parameter hides it.
+struct Shadowed(Option<Binding>, Option<Consumption>);
+
+impl TypeCtx {
+    fn get(&self, x: &str) -> Option<&Type> {
+        self.bindings.get(x).map(|b| &b.ty)
+    }
+
+    fn contains_key(&self, x: &str) -> bool {
+        self.bindings.contains_key(x)
+    }
+
+    fn bind(&mut self, x: &str, ty: Type, site: Context) -> Shadowed {
+        let binding = self.bindings.insert(x.to_owned(), Binding { ty, site });
+        Shadowed(binding, self.consumed.remove(x))
+    }
+
+    fn unbind(&mut self, x: &str, shadowed: Shadowed) {
+        self.bindings.remove(x);
+        self.consumed.remove(x);
+        let Shadowed(binding, consumption) = shadowed;
+        if let Some(binding) = binding {
+            self.bindings.insert(x.to_owned(), binding);
+        }
+        if let Some(consumption) = consumption {
+            self.consumed.insert(x.to_owned(), consumption);
+        }
+    }
+
+    fn consume(&mut self, x: &str, at: Context) {
+        if let Some(Binding { site, .. }) = self.bindings.remove(x) {
+            let hole = None;
+            self.consumed
+                .insert(x.to_owned(), Consumption { site, at, hole });
+        }
+    }
+
+    /// Let the hole `hole` at `at` take all linear variables, and return them.
+    fn consume_by_hole(&mut self, hole: NodeId, at: Context) -> Vec<(String, Binding)> {
+        let linear: Vec<(String, Binding)> = self
+            .sorted()
+            .into_iter()
+            .filter(|(_, b)| b.ty.0 == Qualifier::Linear)
+            .map(|(x, b)| (x.clone(), b.clone()))
+            .collect();
+        for (x, Binding { site, .. }) in &linear {
+            self.bindings.remove(x);
+            let (site, hole) = (*site, Some(hole));
+            self.consumed
+                .insert(x.clone(), Consumption { site, at, hole });
+        }
+        linear
+    }
+
+    /// The variables bound in `self` but consumed in `other`, by name.
+    fn consumed_in<'a>(&self, other: &'a TypeCtx) -> Vec<(&'a str, Consumption)> {
+        let mut consumed: Vec<(&str, Consumption)> = other
+            .consumed
+            .iter()
+            .filter(|(x, _)| self.contains_key(x))
+            .map(|(x, c)| (x.as_str(), *c))
+            .collect();
+        consumed.sort_by_key(|(x, _)| *x);
+        consumed
+    }
+
+    /// The bindings, outermost first.
+    fn sorted(&self) -> Vec<(&String, &Binding)> {
+        let mut bindings: Vec<(&String, &Binding)> = self.bindings.iter().collect();
+        bindings.sort_by_key(|(x, b)| (b.site.start, *x));
+        bindings
+    }
+
+    fn assumptions(&self) -> Vec<Assumption> {
+        self.sorted()
+            .into_iter()
+            .map(|(x, b)| Assumption {
+                name: x.clone(),
+                ty: format_type(&b.ty),
+            })
+            .collect()
+    }
+
+    /// The linear variables that have not been consumed yet, outermost first.
+    fn live_linear(&self) -> Vec<String> {
+        self.sorted()
+            .into_iter()
+            .filter(|(_, b)| b.ty.0 == Qualifier::Linear)
+            .map(|(x, _)| x.clone())
+            .collect()
+    }
+}
+
+/// The linear variables that are live before and after a term is checked. Those used up by
+/// the term are missing from `after`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LiveLinear {
+    pub before: Vec<String>,
+    pub after: Vec<String>,
+}
+
+/// The live linear variables around each term, by node.
+pub type LiveMap = HashMap<NodeId, LiveLinear>;
+
+/// What the checker records about the terms it has typed.
+#[derive(Default)]
+struct Record {
+    types: HashMap<NodeId, Type>,
+    /// The derivations of the terms typed so far, if they are wanted. While a term is being
+    /// checked, these are the derivations of its premises.
+    derivations: Option<Vec<Derivation>>,
+    /// The live linear variables around the terms in `types`, if they are wanted.
+    live: Option<LiveMap>,
+    holes: Vec<HoleRecord>,
+    /// Whether `Term::Error` nodes, as left by `syntax::parse_program_recovering`, are typed
+    /// as unknown rather than reported.
+    recovering: bool,
+}
+
+struct HoleRecord {
+    name: Option<String>,
+    id: NodeId,
+    span: Context,
+    expected: Option<Type>,
+    /// The linear variables taken by the hole and not given back (yet).
+    consumed: Vec<(String, Binding)>,
+    may_use: Vec<Assumption>,
+}
+
+impl Record {
+    /// Give `x` back to the context from the hole that took it.
+    fn give_back(&mut self, x: &str, type_ctx: &mut TypeCtx) {
+        let Some(Consumption { hole: Some(id), .. }) = type_ctx.consumed.get(x) else {
+            return;
+        };
+        let hole = self.holes.iter_mut().find(|h| h.id == *id);
+        let Some(hole) = hole else {
+            return;
+        };
+        if let Some(i) = hole.consumed.iter().position(|(y, _)| y == x) {
+            let (_, binding) = hole.consumed.remove(i);
+            type_ctx.consumed.remove(x);
+            type_ctx.bindings.insert(x.to_owned(), binding);
+        }
+    }
+
+    /// Give back the variables of `outer` that holes took in `inner`. Unrestricted terms
+    /// cannot consume them, so a hole in such a term must not either; nor does a hole in a
+    /// branch have to consume what the other branch leaves, with `outer` the other branch.
+    fn give_back_outer(&mut self, outer: &TypeCtx, inner: &mut TypeCtx) {
+        let taken: Vec<String> = outer
+            .consumed_in(inner)
+            .into_iter()
+            .filter(|(_, c)| c.hole.is_some())
+            .map(|(x, _)| x.to_owned())
+            .collect();
+        for x in taken {
+            self.give_back(&x, inner);
+        }
+    }
+}
+
+/// A typed hole `?` or `?name`, and what it can be filled with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hole {
+    pub name: Option<String>,
+    pub id: NodeId,
+    pub span: Context,
+    /// The type the hole should have, if the context tells.
+    pub expected: Option<String>,
+    /// The linear variables that nothing but the hole consumes.
+    pub must_consume: Vec<Assumption>,
+    /// The unrestricted variables in scope.
+    pub may_use: Vec<Assumption>,
+    /// Where the variables in `must_consume` are bound.
+    #[serde(skip)]
+    sites: Vec<Context>,
+}
+
+impl Hole {
+    /// Report the hole as a warning, pointing at the bindings that it has to consume.
+    pub fn to_error(&self) -> Error {
+        let list = |assumptions: &[Assumption]| {
+            let list: Vec<String> = assumptions
+                .iter()
+                .map(|a| format!("{}: {}", a.name, a.ty))
+                .collect();
+            list.join(", ")
+        };
+        let mut message = format!("hole ?{}", self.name.as_deref().unwrap_or_default());
+        match &self.expected {
+            Some(ty) => message.push_str(&format!(" of type {}", ty)),
+            None => message.push_str(" of unknown type"),
+        }
+        if !self.must_consume.is_empty() {
+            message.push_str(&format!("; must consume {}", list(&self.must_consume)));
+        }
+        if !self.may_use.is_empty() {
+            message.push_str(&format!("; may use {}", list(&self.may_use)));
+        }
+        let labels = self
+            .must_consume
+            .iter()
+            .zip(&self.sites)
+            .map(|(a, site)| label(format!("{} is bound here", a.name), site))
+            .collect();
+        Error::from(LintError {
+            code: ErrorCode::TypedHole,
+            severity: Severity::Warning,
+            message,
+            start: self.span.start,
+            end: self.span.end,
+            location: None,
+            labels,
+            suggestions: vec![],
+        })
+    }
+}
+
+impl From<HoleRecord> for Hole {
+    fn from(hole: HoleRecord) -> Self {
+        let must_consume = hole
+            .consumed
+            .iter()
+            .map(|(x, b)| Assumption {
+                name: x.clone(),
+                ty: format_type(&b.ty),
+            })
+            .collect();
+        Hole {
+            name: hole.name,
+            id: hole.id,
+            span: hole.span,
+            expected: hole.expected.as_ref().map(format_type),
+            must_consume,
+            may_use: hole.may_use,
+            sites: hole.consumed.iter().map(|(_, b)| b.site).collect(),
+        }
+    }
+}
+
+/// The name of the typing rule that concludes with `term`.
+fn rule_name(term: &Term) -> &'static str {
+    match term {
+        Term::Variable(_) => "T-Var",
+        Term::Boolean(..) => "T-Bool",
+        Term::Integer(..) => "T-Int",
+        Term::Conditional(..) => "T-If",
+        Term::Abstraction(..) => "T-Abs",
+        Term::Application(..) => "T-App",
+        Term::Let(..) => "T-Let",
+        Term::Fix(_) => "T-Fix",
+        Term::Compound(..) => "T-Pair",
+        Term::Letc(..) => "T-Split",
+        Term::Arith1(..) => "T-IsZero",
+        Term::Arith2(..) => "T-Diff",
+        Term::Error(..) => "T-Error",
+        Term::Hole(..) => "T-Hole",
+        Term::Ascription(..) => "T-Asc",
+    }
+}
+
+fn label(message: String, span: &Context) -> Label {
+    Label {
+        message,
+        start: span.start,
+        end: span.end,
+        location: None,
+    }
+}
+
+pub(crate) fn format_type(ty: &Type) -> String {
+    TermFormatter::new(formatter::DEFAULT_LINE_WIDTH).format_type(ty)
+}
+
+/// A placeholder for the parts of an expected type that do not matter, printed as `_`.
+fn any_type() -> Box<Type> {
+    Box::new(Type(Qualifier::Nop, Pretype::Unknown))
+}
+
+/// A type error reporting that `found` was given where `expected` (described by `what`) was
+/// expected.
+fn type_mismatch(
+    code: ErrorCode,
+    span: &Context,
+    what: &str,
+    expected: &Type,
+    found: &Type,
+) -> Error {
+    let (expected, found) = (format_type(expected), format_type(found));
+    Error::from(TypeError {
+        code,
+        start: span.start,
+        end: span.end,
+        location: None,
+        message: format!("expect {}{}, given {}", expected, what, found),
+        expected: Some(expected),
+        found: Some(found),
+        labels: vec![],
+        suggestions: vec![],
+    })
+}
 
 fn type_ctx_eq(a: &TypeCtx, b: &TypeCtx) -> bool {
-    if a.len() != b.len() {
+    if a.bindings.len() != b.bindings.len() {
         return false;
     }
-    for (k, v) in a {
+    for (k, v) in a.bindings.iter() {
         match b.get(k) {
-            Some(v1) if v1 == v => {}
+            Some(v1) if *v1 == v.ty => {}
             _ => return false,
         }
     }
     true
 }
 
+/// Point at the linear variables that `inner` consumed from `outer`, for errors about free
+/// linear variables in unrestricted terms.
+fn captured_labels(outer: &TypeCtx, inner: &TypeCtx) -> Vec<Label> {
+    outer
+        .consumed_in(inner)
+        .into_iter()
+        .map(|(x, c)| label(format!("linear variable {} is used here", x), &c.at))
+        .collect()
+}
+
+/// Suggest consuming the forgotten linear variable `x` at the start of `body`, by passing it to
+/// an unrestricted function in scope that takes it and returns an unrestricted value, or to
+/// `iszero` if it is an integer.
+fn consume_suggestion(type_ctx: &TypeCtx, x: &str, body: &TermCtx) -> Option<Suggestion> {
+    let ty = type_ctx.get(x)?;
+    let mut consumers: Vec<&str> = type_ctx
+        .bindings
+        .iter()
+        .filter(|(_, b)| match &b.ty {
+            Type(Qualifier::Nop, Pretype::Function(ty1, ty2)) => {
+                **ty1 == *ty && ty2.0 == Qualifier::Nop && !matches!(ty1.1, Pretype::Unknown)
+            }
+            _ => false,
+        })
+        .map(|(f, _)| f.as_str())
+        .collect();
+    consumers.sort();
+    let call = match (consumers.first(), &ty.1) {
+        (Some(f), _) => format!("{}({})", f, x),
+        (None, Pretype::Integer) => format!("iszero({})", x),
+        _ => return None,
+    };
+    Some(Suggestion::insert(
+        format!("consume {} with {}", x, call),
+        body.0.start,
+        format!("let _ = {} in ", call),
+    ))
+}
+
+/// Suggest adding or removing `$` on a literal or function so that it gets the expected
+/// qualifier.
+fn qualifier_suggestion(term_ctx: &TermCtx, expected: &Type) -> Option<Suggestion> {
+    let TermCtx(span, term, ..) = term_ctx;
+    let q = match term {
+        Term::Boolean(q, _) | Term::Integer(q, _) | Term::Abstraction(q, ..) => *q,
+        _ => return None,
+    };
+    match (q, expected.0) {
+        (Qualifier::Nop, Qualifier::Linear) => Some(Suggestion::insert(
+            "make it linear with $".to_string(),
+            span.start,
+            "$".to_string(),
+        )),
+        (Qualifier::Linear, Qualifier::Nop) => Some(Suggestion {
+            message: "make it unrestricted by removing $".to_string(),
+            edits: vec![Edit {
+                start: span.start,
+                end: span.start + 1,
+                replacement: String::new(),
+                location: None,
+            }],
+        }),
+        _ => None,
+    }
+}
+
+/// Synthesize the type of a term, in the inference mode of the bidirectional algorithm.
+fn infer(term_ctx: &TermCtx, type_ctx: &mut TypeCtx, record: &mut Record) -> Result<Type, Error> {
+    type_check_aux(term_ctx, type_ctx, record, None)
+}
+
+/// Check a term against the type `expected`, in the checking mode of the bidirectional
+/// algorithm. Unannotated function parameters and holes take their types from `expected`.
+fn check(
+    term_ctx: &TermCtx,
+    type_ctx: &mut TypeCtx,
+    record: &mut Record,
+    expected: &Type,
+) -> Result<Type, Error> {
+    let found = type_check_aux(term_ctx, type_ctx, record, Some(expected))?;
+    if !found.compatible(expected) {
+        let what = " as annotated";
+        let error = type_mismatch(
+            ErrorCode::AnnotationMismatch,
+            &term_ctx.key_span(),
+            what,
+            expected,
+            &found,
+        );
+        let suggestion = match (expected, &found) {
+            (Type(_, p1), Type(_, p2)) if p1 == p2 => qualifier_suggestion(term_ctx, expected),
+            _ => None,
+        };
+        return Err(error.with_suggestion(suggestion));
+    }
+    Ok(expected.clone())
+}
+
 fn type_check_aux(
     term_ctx: &TermCtx,
     type_ctx: &mut TypeCtx,
-    type_map: &mut HashMap<Context, Type>,
+    record: &mut Record,
+    // the type the context expects, if it tells: checking mode rather than inference mode.
+    // Callers compare the result with it and report mismatches in their own terms.
+    expected: Option<&Type>,
 ) -> Result<Type, Error> {
-    let TermCtx(span, term) = term_ctx;
-    let err = |s: String| Error::TypeError {
-        start: span.start,
-        end: span.end,
-        message: s,
+    let TermCtx(_, term, node, _) = term_ctx;
+    // errors about the term itself point at its keyword or operator
+    let span = &term_ctx.key_span();
+    // the derivations of the siblings of this node, while those of its premises are collected
+    let siblings = record.derivations.as_mut().map(std::mem::take);
+    let input = siblings.as_ref().map(|_| type_ctx.assumptions());
+    let before = record.live.as_ref().map(|_| type_ctx.live_linear());
+    let err = |code: ErrorCode, s: String| {
+        Error::from(TypeError {
+            code,
+            start: span.start,
+            end: span.end,
+            location: None,
+            message: s,
+            expected: None,
+            found: None,
+            labels: vec![],
+            suggestions: vec![],
+        })
     };
-    let need_type_tip = match term {
-        Term::Application(..) => false,
-        _ => true,
+    let mismatch = |code: ErrorCode, what: &str, expected: &Type, found: &Type| {
+        type_mismatch(code, span, what, expected, found)
     };
+    let need_type_tip = !matches!(term, Term::Error(..));
     let type_: Type = match term {
         Term::Variable(id) => {
-            let ty = type_ctx
-                .get(id)
-                .ok_or_else(|| err(format!("undefined variable: {}", id)))?;
-            let Type(q, _) = ty;
-            let ty = ty.clone();
-            if *q == Qualifier::Linear {
-                type_ctx.remove(id);
+            record.give_back(id, type_ctx);
+            let ty = match (type_ctx.get(id), type_ctx.consumed.get(id)) {
+                (Some(ty), _) => ty.clone(),
+                (None, Some(Consumption { site, at, .. })) => {
+                    return Err(Error::from(TypeError {
+                        code: ErrorCode::LinearConsumedTwice,
+                        start: span.start,
+                        end: span.end,
+                        location: None,
+                        message: format!("linear variable {} was already consumed", id),
+                        expected: None,
+                        found: None,
+                        labels: vec![
+                            label(format!("{} is bound here", id), site),
+                            label(format!("{} is first consumed here", id), at),
+                        ],
+                        suggestions: vec![],
+                    }));
+                }
+                (None, None) => {
+                    let message = format!("undefined variable: {}", id);
+                    return Err(err(ErrorCode::UndefinedVariable, message));
+                }
+            };
+            if ty.0 == Qualifier::Linear {
+                type_ctx.consume(id, *span);
             }
             ty
         }
         Term::Boolean(q, _) => Type(*q, Pretype::Boolean),
         Term::Integer(q, _) => Type(*q, Pretype::Integer),
         Term::Conditional(cond, then, alter) => {
-            let Type(_, cond_type) = type_check_aux(cond, type_ctx, type_map)?;
+            let bool_type = Type(Qualifier::Nop, Pretype::Boolean);
+            let cond_type = type_check_aux(cond, type_ctx, record, Some(&bool_type))?;
             let mut type_ctx1 = type_ctx.clone();
             let type_ctx1 = &mut type_ctx1;
-            let then_type = type_check_aux(then, type_ctx, type_map)?;
-            let alter_type = type_check_aux(alter, type_ctx1, type_map)?;
+            let then_type = type_check_aux(then, type_ctx, record, expected)?;
+            let alter_type = type_check_aux(alter, type_ctx1, record, Some(&then_type))?;
+            // a hole in one branch only has to consume what the other branch consumes
+            record.give_back_outer(type_ctx, type_ctx1);
+            record.give_back_outer(type_ctx1, type_ctx);
             if !type_ctx_eq(type_ctx, type_ctx1) {
-                return Err(err(
-                    "variables are consumed differently in different branches".to_string(),
-                ));
+                let mut breakdown = vec![];
+                let mut labels = vec![];
+                for (x, c, branch, other) in type_ctx1
+                    .consumed_in(type_ctx)
+                    .into_iter()
+                    .map(|(x, c)| (x, c, "then", alter))
+                    .chain(
+                        type_ctx
+                            .consumed_in(type_ctx1)
+                            .into_iter()
+                            .map(|(x, c)| (x, c, "else", then)),
+                    )
+                {
+                    breakdown.push(format!("{} only in the {} branch", x, branch));
+                    labels.push(label(format!("{} is consumed here", x), &c.at));
+                    labels.push(label(
+                        format!("{} is not consumed in this branch", x),
+                        &other.0,
+                    ));
+                }
+                return Err(Error::from(TypeError {
+                    code: ErrorCode::BranchConsumption,
+                    start: span.start,
+                    end: span.end,
+                    location: None,
+                    message: format!(
+                        "variables are consumed differently in different branches: {}",
+                        breakdown.join(", ")
+                    ),
+                    expected: None,
+                    found: None,
+                    labels,
+                    suggestions: vec![],
+                }));
             }
-            if cond_type != Pretype::Boolean {
-                return Err(err(format!("expect Boolean, given {:?}", cond_type)));
+            if cond_type.1 != Pretype::Boolean {
+                let expected = Type(cond_type.0, Pretype::Boolean);
+                return Err(mismatch(
+                    ErrorCode::ConditionNotBoolean,
+                    " as condition",
+                    &expected,
+                    &cond_type,
+                ));
             }
-            if then_type != alter_type {
-                return Err(err(format!(
-                    "different branch types: {:?} vs {:?}",
-                    then_type, alter_type
-                )));
+            if !then_type.compatible(&alter_type) {
+                let (expected, found) = (format_type(&then_type), format_type(&alter_type));
+                return Err(Error::from(TypeError {
+                    code: ErrorCode::BranchTypeMismatch,
+                    start: span.start,
+                    end: span.end,
+                    location: None,
+                    message: format!("different branch types: {} vs {}", expected, found),
+                    expected: Some(expected),
+                    found: Some(found),
+                    labels: vec![],
+                    suggestions: vec![],
+                }));
             }
             then_type
         }
-        Term::Abstraction(q, x, Some(ty), body) => {
+        Term::Abstraction(q, x, ty, body) => {
+            // without an annotation, the parameter type has to come from the expected type
+            let ty = match (ty, expected) {
+                (Some(ty), _) => ty.clone(),
+                (None, Some(Type(_, Pretype::Function(ty1, _)))) => ty1.clone(),
+                (None, _) => {
+                    let message = format!("type annotation needed for parameter {}", x);
+                    return Err(err(ErrorCode::MissingAnnotation, message));
+                }
+            };
             let type_ctx0 = type_ctx.clone();
-            type_ctx.insert(x.clone(), ty.as_ref().clone());
-            let body_type = type_check_aux(body, type_ctx, type_map)?;
+            let shadowed = type_ctx.bind(x, ty.as_ref().clone(), *span);
+            let body_expected = match expected {
+                Some(Type(_, Pretype::Function(_, ty2))) => Some(&**ty2),
+                _ => None,
+            };
+            let body_type = type_check_aux(body, type_ctx, record, body_expected)?;
             // output typing context should not contain introduced linear type
             if ty.0 == Qualifier::Linear && type_ctx.contains_key(x) {
-                return Err(err(format!(
-                    "linear variable {} is not consumed in function body",
-                    x
-                )));
+                let error = err(
+                    ErrorCode::LinearNotConsumed,
+                    format!("linear variable {} is not consumed in function body", x),
+                );
+                return Err(error.with_suggestion(consume_suggestion(type_ctx, x, body)));
+            }
+            type_ctx.unbind(x, shadowed);
+            if *q == Qualifier::Nop {
+                record.give_back_outer(&type_ctx0, type_ctx);
             }
-            type_ctx.remove(x);
             // if the closure is unrestricted,
             // there should be no reference to linear variable in body
             if *q == Qualifier::Nop && !type_ctx_eq(type_ctx, &type_ctx0) {
-                return Err(err(
+                let error = err(
+                    ErrorCode::LinearCapturedByFunction,
                     "free linear variable is refered in unrestricted function body".to_string(),
-                ));
+                );
+                let suggestion = Suggestion::insert(
+                    "make the function linear with $".to_string(),
+                    span.start,
+                    "$".to_string(),
+                );
+                return Err(error
+                    .with_labels(captured_labels(&type_ctx0, type_ctx))
+                    .with_suggestion(Some(suggestion)));
             }
-            Type(*q, Pretype::Function(ty.clone(), Box::new(body_type)))
+            Type(*q, Pretype::Function(ty, Box::new(body_type)))
         }
         Term::Application(fun, arg) => {
-            let fun_type = type_check_aux(fun, type_ctx, type_map)?;
-            let arg_type = type_check_aux(arg, type_ctx, type_map)?;
+            let fun_type = infer(fun, type_ctx, record)?;
+            let arg_expected = match &fun_type {
+                Type(_, Pretype::Function(ty1, _)) => Some(&**ty1),
+                _ => None,
+            };
+            let arg_type = type_check_aux(arg, type_ctx, record, arg_expected)?;
             match fun_type {
                 Type(_, Pretype::Function(ty1, ty2)) => {
-                    if *ty1 != arg_type {
-                        return Err(err(format!(
-                            "expect {:?} as argument, given {:?}",
-                            ty1, arg_type
-                        )));
+                    if !ty1.compatible(&arg_type) {
+                        let error =
+                            mismatch(ErrorCode::ArgumentMismatch, " as argument", &ty1, &arg_type);
+                        // only the qualifier is off
+                        let suggestion = match (&*ty1, &arg_type) {
+                            (Type(_, p1), Type(_, p2)) if p1.compatible(p2) => {
+                                qualifier_suggestion(arg, &ty1)
+                            }
+                            _ => None,
+                        };
+                        return Err(error.with_suggestion(suggestion));
                     }
                     *ty2
                 }
-                _ => return Err(err(format!("expect Function, given {:?}", fun_type))),
+                Type(_, Pretype::Unknown) => Type(Qualifier::Nop, Pretype::Unknown),
+                _ => {
+                    let expected = Type(Qualifier::Nop, Pretype::Function(any_type(), any_type()));
+                    return Err(mismatch(ErrorCode::NotAFunction, "", &expected, &fun_type));
+                }
             }
         }
         Term::Let(x, t1, t2) => {
-            let t1_type = type_check_aux(t1, type_ctx, type_map)?;
+            let t1_type = infer(t1, type_ctx, record)?;
             let q = t1_type.0;
-            type_ctx.insert(x.clone(), t1_type);
-            let t2_type = type_check_aux(t2, type_ctx, type_map)?;
+            let shadowed = type_ctx.bind(x, t1_type, *span);
+            let t2_type = type_check_aux(t2, type_ctx, record, expected)?;
             if q == Qualifier::Linear && type_ctx.contains_key(x) {
-                return Err(err(format!(
-                    "linear variable {} is not consumed in let body",
-                    x
-                )));
+                let error = err(
+                    ErrorCode::LinearNotConsumed,
+                    format!("linear variable {} is not consumed in let body", x),
+                );
+                return Err(error.with_suggestion(consume_suggestion(type_ctx, x, t2)));
             }
-            type_ctx.remove(x);
+            type_ctx.unbind(x, shadowed);
             t2_type
         }
         Term::Fix(t) => {
-            let t_type = type_check_aux(t, type_ctx, type_map)?;
+            let t_expected = expected.map(|ty| {
+                Type(
+                    Qualifier::Nop,
+                    Pretype::Function(Box::new(ty.clone()), Box::new(ty.clone())),
+                )
+            });
+            let t_type = type_check_aux(t, type_ctx, record, t_expected.as_ref())?;
             match t_type {
-                Type(q, Pretype::Function(ty1, ty2)) if *ty1 == *ty2 => {
+                Type(q, Pretype::Function(ty1, ty2)) if ty1.compatible(&ty2) => {
                     let Type(q1, _) = *ty1;
                     if q == Qualifier::Linear || q1 == Qualifier::Linear {
-                        return Err(err(format!("linear term is not allowed for recursion")));
+                        let message = "linear term is not allowed for recursion".to_string();
+                        return Err(err(ErrorCode::LinearRecursion, message));
                     }
                     *ty1
                 }
-                _ => return Err(err(format!("expect Function T -> T, given {:?}", t_type))),
+                Type(_, Pretype::Unknown) => t_type,
+                // show the function type `fix` would accept, given the argument type
+                Type(q, Pretype::Function(ref ty1, _)) => {
+                    let expected = Type(q, Pretype::Function(ty1.clone(), ty1.clone()));
+                    return Err(mismatch(
+                        ErrorCode::InvalidRecursion,
+                        " for recursion",
+                        &expected,
+                        &t_type,
+                    ));
+                }
+                _ => {
+                    let expected = Type(Qualifier::Nop, Pretype::Function(any_type(), any_type()));
+                    return Err(mismatch(
+                        ErrorCode::InvalidRecursion,
+                        " for recursion",
+                        &expected,
+                        &t_type,
+                    ));
+                }
             }
         }
         Term::Compound(q, t1, t2) => {
+            let (t1_expected, t2_expected) = match expected {
+                Some(Type(_, Pretype::Compound(ty1, ty2))) => (Some(&**ty1), Some(&**ty2)),
+                _ => (None, None),
+            };
             let type_ctx0 = type_ctx.clone();
-            let t1_type = type_check_aux(t1, type_ctx, type_map)?;
+            let t1_type = type_check_aux(t1, type_ctx, record, t1_expected)?;
+            if *q == Qualifier::Nop {
+                record.give_back_outer(&type_ctx0, type_ctx);
+            }
             if *q == Qualifier::Nop && !type_ctx_eq(type_ctx, &type_ctx0) {
-                return Err(err(
+                let error = err(
+                    ErrorCode::LinearCapturedByPair,
                     "free linear variable is refered in unrestricted compound pair".to_string(),
-                ));
+                );
+                return Err(error.with_labels(captured_labels(&type_ctx0, type_ctx)));
             }
             let type_ctx0 = type_ctx.clone();
-            let t2_type = type_check_aux(t2, type_ctx, type_map)?;
+            let t2_type = type_check_aux(t2, type_ctx, record, t2_expected)?;
+            if *q == Qualifier::Nop {
+                record.give_back_outer(&type_ctx0, type_ctx);
+            }
             if *q == Qualifier::Nop && !type_ctx_eq(type_ctx, &type_ctx0) {
-                return Err(err(
+                let error = err(
+                    ErrorCode::LinearCapturedByPair,
                     "free linear variable is refered in unrestricted compound pair".to_string(),
-                ));
+                );
+                return Err(error.with_labels(captured_labels(&type_ctx0, type_ctx)));
             }
             Type(*q, Pretype::Compound(Box::new(t1_type), Box::new(t2_type)))
         }
         Term::Letc(x1, x2, t1, t2) => {
             if x1 == x2 {
-                return Err(err(format!("expect different identifier, given {}", x1)));
+                let message = format!("expect different identifier, given {}", x1);
+                return Err(err(ErrorCode::DuplicatePatternVariable, message));
             }
-            let t1_type = type_check_aux(t1, type_ctx, type_map)?;
+            let t1_type = infer(t1, type_ctx, record)?;
+            let t1_type = match t1_type {
+                Type(q, Pretype::Unknown) => {
+                    let unknown = Box::new(Type(Qualifier::Nop, Pretype::Unknown));
+                    Type(q, Pretype::Compound(unknown.clone(), unknown))
+                }
+                ty => ty,
+            };
             match t1_type {
                 Type(_, Pretype::Compound(ty1, ty2)) => {
                     let (q1, q2) = (ty1.0, ty2.0);
-                    type_ctx.insert(x1.clone(), *ty1);
-                    type_ctx.insert(x2.clone(), *ty2);
-                    let t2_type = type_check_aux(t2, type_ctx, type_map)?;
+                    let shadowed1 = type_ctx.bind(x1, *ty1, *span);
+                    let shadowed2 = type_ctx.bind(x2, *ty2, *span);
+                    let t2_type = type_check_aux(t2, type_ctx, record, expected)?;
                     for (q, x) in [(q1, x1), (q2, x2)] {
                         if q == Qualifier::Linear && type_ctx.contains_key(x) {
-                            return Err(err(format!(
-                                "linear variable {} is not consumed in let body",
-                                x
-                            )));
+                            let error = err(
+                                ErrorCode::LinearNotConsumed,
+                                format!("linear variable {} is not consumed in let body", x),
+                            );
+                            let suggestion = consume_suggestion(type_ctx, x, t2);
+                            return Err(error.with_suggestion(suggestion));
                         }
                     }
-                    type_ctx.remove(x1);
-                    type_ctx.remove(x2);
+                    type_ctx.unbind(x2, shadowed2);
+                    type_ctx.unbind(x1, shadowed1);
                     t2_type
                 }
-                _ => return Err(err(format!("expect Compound, given {:?}", t1_type))),
+                _ => {
+                    let expected = Type(t1_type.0, Pretype::Compound(any_type(), any_type()));
+                    return Err(mismatch(ErrorCode::NotAPair, "", &expected, &t1_type));
+                }
             }
         }
-        Term::Arith1(q, ArithOp::IsZero, t) => match type_check_aux(t, type_ctx, type_map)? {
-            Type(_, Pretype::Integer) => Type(*q, Pretype::Boolean),
-            ty => return Err(err(format!("expect Integer, given {:?}", ty))),
+        Term::Arith1(q, ArithOp::IsZero, t) => match type_check_aux(
+            t,
+            type_ctx,
+            record,
+            Some(&Type(Qualifier::Nop, Pretype::Integer)),
+        )? {
+            Type(_, Pretype::Integer | Pretype::Unknown) => Type(*q, Pretype::Boolean),
+            ty => {
+                let expected = Type(ty.0, Pretype::Integer);
+                return Err(mismatch(ErrorCode::NotAnInteger, "", &expected, &ty));
+            }
         },
         Term::Arith2(q, ArithOp::Diff, t1, t2) => {
-            let t1_type = type_check_aux(t1, type_ctx, type_map)?;
-            let t2_type = type_check_aux(t2, type_ctx, type_map)?;
+            let t1_type = type_check_aux(
+                t1,
+                type_ctx,
+                record,
+                Some(&Type(Qualifier::Nop, Pretype::Integer)),
+            )?;
+            let t2_type = type_check_aux(
+                t2,
+                type_ctx,
+                record,
+                Some(&Type(Qualifier::Nop, Pretype::Integer)),
+            )?;
             match (t1_type, t2_type) {
-                (Type(_, Pretype::Integer), Type(_, Pretype::Integer)) => {
-                    Type(*q, Pretype::Integer)
-                }
-                (ty1, ty2) => {
-                    return Err(err(format!(
-                        "expect Integers, given {:?} and {:?}",
-                        ty1, ty2
-                    )))
+                (
+                    Type(_, Pretype::Integer | Pretype::Unknown),
+                    Type(_, Pretype::Integer | Pretype::Unknown),
+                ) => Type(*q, Pretype::Integer),
+                (Type(_, Pretype::Integer | Pretype::Unknown), ty) | (ty, _) => {
+                    let expected = Type(ty.0, Pretype::Integer);
+                    return Err(mismatch(
+                        ErrorCode::NotAnInteger,
+                        " as operand",
+                        &expected,
+                        &ty,
+                    ));
                 }
             }
         }
-        _ => return Err(err(format!("unknown term {:?}", term))),
+        Term::Error(text) => {
+            if !record.recovering {
+                let message = format!("malformed term {:?} has no type", text);
+                return Err(err(ErrorCode::SyntaxError, message));
+            }
+            Type(Qualifier::Nop, Pretype::Unknown)
+        }
+        Term::Hole(name) => {
+            let may_use = type_ctx
+                .sorted()
+                .into_iter()
+                .filter(|(_, b)| b.ty.0 == Qualifier::Nop)
+                .map(|(x, b)| Assumption {
+                    name: x.clone(),
+                    ty: format_type(&b.ty),
+                })
+                .collect();
+            let consumed = type_ctx.consume_by_hole(*node, *span);
+            record.holes.push(HoleRecord {
+                name: name.clone(),
+                id: *node,
+                span: *span,
+                expected: expected.cloned(),
+                consumed,
+                may_use,
+            });
+            expected
+                .cloned()
+                .unwrap_or(Type(Qualifier::Nop, Pretype::Unknown))
+        }
+        Term::Ascription(t, ty) => check(t, type_ctx, record, ty)?,
+        _ => {
+            let message = format!("unknown term {}", formatter::format_termctx(term_ctx));
+            return Err(err(ErrorCode::Internal, message));
+        }
     };
     if need_type_tip {
-        type_map.insert(*span, type_.clone());
+        record.types.insert(*node, type_.clone());
+        if let (Some(live), Some(before)) = (record.live.as_mut(), before) {
+            let after = type_ctx.live_linear();
+            live.insert(*node, LiveLinear { before, after });
+        }
+    }
+    if let (Some(siblings), Some(input)) = (siblings, input) {
+        let premises = record.derivations.replace(siblings).unwrap_or_default();
+        record.derivations.as_mut().unwrap().push(Derivation {
+            rule: rule_name(term).to_owned(),
+            id: *node,
+            span: term_ctx.0,
+            term: formatter::format_termctx(term_ctx),
+            input,
+            output: type_ctx.assumptions(),
+            ty: format_type(&type_),
+            premises,
+        });
     }
     Ok(type_)
 }
 
-pub fn type_check(term_ctx: &TermCtx) -> Result<HashMap<Context, Type>, Error> {
-    let mut type_map = HashMap::<Context, Type>::new();
-    let mut type_ctx = HashMap::<String, Type>::new();
-    let res_type = type_check_aux(term_ctx, &mut type_ctx, &mut type_map)?;
+/// The types of the terms of a program, by node. Syntax errors are typed as `_` and left out.
+pub fn type_check(term_ctx: &TermCtx) -> Result<HashMap<NodeId, Type>, Error> {
+    let mut record = Record::default();
+    type_check_top(term_ctx, &mut record)?;
+    Ok(record.types)
+}
+
+/// Like `type_check`, but the types found before the first error are returned as well.
+/// `Term::Error` nodes are typed as unknown, so that the rest of a recovered program is typed.
+pub fn type_check_partial(term_ctx: &TermCtx) -> (HashMap<NodeId, Type>, Result<(), Error>) {
+    let mut record = Record {
+        recovering: true,
+        ..Default::default()
+    };
+    let result = type_check_top(term_ctx, &mut record);
+    (record.types, result.map(|_| ()))
+}
+
+/// Like `type_check`, but the live linear variables around each term are returned as well.
+pub fn type_check_live(term_ctx: &TermCtx) -> Result<(HashMap<NodeId, Type>, LiveMap), Error> {
+    let mut record = Record {
+        live: Some(HashMap::new()),
+        ..Default::default()
+    };
+    type_check_top(term_ctx, &mut record)?;
+    Ok((record.types, record.live.unwrap_or_default()))
+}
+
+/// Like `type_check_live`, for a program from `syntax::parse_program_recovering`: its
+/// `Term::Error` nodes are typed as unknown instead of being errors.
+pub fn type_check_recovering(
+    term_ctx: &TermCtx,
+) -> Result<(HashMap<NodeId, Type>, LiveMap), Error> {
+    let mut record = Record {
+        live: Some(HashMap::new()),
+        recovering: true,
+        ..Default::default()
+    };
+    type_check_top(term_ctx, &mut record)?;
+    Ok((record.types, record.live.unwrap_or_default()))
+}
+
+/// The holes in a program, with what they can be filled with. Only the holes before the first
+/// type error, if there is one, are found.
+pub fn find_holes(term_ctx: &TermCtx) -> Vec<Hole> {
+    let mut record = Record {
+        recovering: true,
+        ..Default::default()
+    };
+    let _ = type_check_top(term_ctx, &mut record);
+    record.holes.into_iter().map(Hole::from).collect()
+}
+
+/// Type check a program and build its typing derivation.
+pub fn type_derivation(term_ctx: &TermCtx) -> Result<Derivation, Error> {
+    let mut record = Record {
+        derivations: Some(vec![]),
+        ..Default::default()
+    };
+    type_check_top(term_ctx, &mut record)?;
+    let derivation = record.derivations.and_then(|mut d| d.pop());
+    derivation.ok_or_else(|| Error::InternalError {
+        code: ErrorCode::Internal,
+        message: "no derivation was recorded".to_owned(),
+    })
+}
+
+fn type_check_top(term_ctx: &TermCtx, record: &mut Record) -> Result<Type, Error> {
+    let mut type_ctx = TypeCtx::default();
+    let res_type = infer(term_ctx, &mut type_ctx, record)?;
+    match linear_result(term_ctx, &res_type) {
+        Some(error) => Err(error),
+        None => Ok(res_type),
+    }
+}
+
+/// Nothing consumes the value of the whole program, so it must not be linear.
+fn linear_result(term_ctx: &TermCtx, res_type: &Type) -> Option<Error> {
     if res_type.0 == Qualifier::Linear {
-        return Err(Error::TypeError {
-            start: term_ctx.0.start,
-            end: term_ctx.0.end,
-            message: format!("The term is linear type, will not consumed after evaluation"),
-        });
+        let Type(_, pretype) = res_type;
+        let (expected, found) = (Type(Qualifier::Nop, pretype.clone()), res_type.clone());
+        let (expected, found) = (format_type(&expected), format_type(&found));
+        return Some(Error::from(TypeError {
+            code: ErrorCode::LinearResult,
+            start: term_ctx.key_span().start,
+            end: term_ctx.key_span().end,
+            location: None,
+            message: format!(
+                "The term is of linear type {}, will not be consumed after evaluation",
+                found
+            ),
+            expected: Some(expected),
+            found: Some(found),
+            labels: vec![],
+            suggestions: vec![],
+        }));
     }
-    Ok(type_map)
+    None
 }
 
 #[derive(Eq, PartialEq)]
@@ -236,40 +1002,65 @@ impl Ord for HeapState {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TypedTermStr<'a> {
     ty: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    live: Option<LiveLinear>,
     s: &'a str,
 }
 
 pub fn convert_hashmap_to_vec<'a>(
-    type_map: &HashMap<Context, Type>,
+    term_ctx: &TermCtx,
+    type_map: &HashMap<NodeId, Type>,
+    source: &'a str,
+) -> Vec<TypedTermStr<'a>> {
+    type_tips(term_ctx, type_map, None, source)
+}
+
+/// Split `source`, the source of `term_ctx`, into runs of text that belong to the same
+/// innermost typed term, each with the type of that term and, if `live` is given, its live
+/// linear variables.
+pub fn type_tips<'a>(
+    term_ctx: &TermCtx,
+    type_map: &HashMap<NodeId, Type>,
+    live: Option<&LiveMap>,
     source: &'a str,
 ) -> Vec<TypedTermStr<'a>> {
-    // (position, is_start, type)
-    let mut event: Vec<(usize, bool, &Type)> = Vec::new();
-    let mut max_pos = 0;
-    for (span, ty) in type_map.iter() {
-        event.push((span.start, true, ty));
+    // (position, is_start, node, type)
+    let mut event: Vec<(usize, bool, &NodeId, &Type)> = Vec::new();
+    let mut nodes = vec![term_ctx];
+    while let Some(TermCtx(span, term, id, _)) = nodes.pop() {
+        nodes.extend(term.children());
+        let Some(ty) = type_map.get(id) else {
+            continue;
+        };
+        event.push((span.start, true, id, ty));
         // the `end` is not inclusive
-        event.push((span.end, false, ty));
-        max_pos = max_pos.max(span.end);
+        event.push((span.end, false, id, ty));
     }
-    event.sort_by_key(|&(pos, t, _)| (pos, t));
+    // Of the terms that start at the same place, the inner ones come later in preorder and
+    // end up on top of the stack. An application thus gives way to its function.
+    event.sort_by_key(|&(pos, t, id, _)| (pos, t, *id));
     let mut formatter = TermFormatter::new(formatter::DEFAULT_LINE_WIDTH);
+    let mut tag = |top: Option<(&NodeId, &Type)>, s: &'a str| TypedTermStr {
+        ty: top.map(|(_, ty)| formatter.format_type(ty)),
+        live: top.and_then(|(id, _)| live?.get(id).cloned()),
+        s,
+    };
     let mut tags: Vec<TypedTermStr<'a>> = Vec::new();
-    let mut stack: Vec<&Type> = Vec::new();
+    let mut stack: Vec<(&NodeId, &Type)> = Vec::new();
     let mut event_i = event.iter().peekable();
 
     let mut start = 0;
     for (i, c) in source.char_indices() {
         let mut is_changed = false;
-        let mut is_changed_type: Option<Type> = None;
-        while let Some((pos, t, ty)) = event_i.peek() {
+        let mut is_changed_top: Option<(&NodeId, &Type)> = None;
+        while let Some((pos, t, id, ty)) = event_i.peek() {
             if *pos == i {
                 if !is_changed {
-                    is_changed_type = stack.last().map(|&t| t.to_owned());
+                    is_changed_top = stack.last().copied();
                 }
                 is_changed = true;
                 if *t {
-                    stack.push(ty);
+                    stack.push((id, ty));
                 } else {
                     stack.pop();
                 }
@@ -279,41 +1070,23 @@ pub fn convert_hashmap_to_vec<'a>(
             }
         }
         if is_changed && start < i {
-            tags.push(TypedTermStr {
-                ty: is_changed_type.map(|t| formatter.format_type(&t)),
-                s: &source[start..i],
-            });
+            tags.push(tag(is_changed_top, &source[start..i]));
             if c == '\n' {
-                tags.push(TypedTermStr {
-                    ty: None,
-                    s: &source[i..i + 1],
-                });
+                tags.push(tag(None, &source[i..i + 1]));
                 start = i + 1;
             } else {
                 start = i;
             }
         } else if c == '\n' {
             if start < i {
-                tags.push(TypedTermStr {
-                    ty: stack.last().map(|t| formatter.format_type(&t)),
-                    s: &source[start..i],
-                });
+                tags.push(tag(stack.last().copied(), &source[start..i]));
             }
-            tags.push(TypedTermStr {
-                ty: None,
-                s: &source[i..i + 1],
-            });
+            tags.push(tag(None, &source[i..i + 1]));
             start = i + 1;
         }
     }
     if start < source.len() {
-        tags.push(TypedTermStr {
-            ty: stack
-                .last()
-                .map(|&t| t.to_owned())
-                .map(|t| formatter.format_type(&t)),
-            s: &source[start..],
-        });
+        tags.push(tag(stack.last().copied(), &source[start..]));
     }
 
     tags
@@ -333,6 +1106,19 @@ mod tests {
         assert_eq!(type_map.len(), 1);
     }
 
+    #[test]
+    fn test_type_by_node() {
+        let input = "(|x: bool| 1) (false)";
+        let term = parse_program(input).unwrap();
+        let type_map = type_check(&term).unwrap();
+        println!("{:#?}", type_map);
+        let TermCtx(_, Term::Application(_, arg), id, _) = &term else {
+            panic!("not an application");
+        };
+        assert_eq!(type_map[id], Type(Qualifier::Nop, Pretype::Integer));
+        assert_eq!(type_map[&arg.2], Type(Qualifier::Nop, Pretype::Boolean));
+    }
+
     #[test]
     fn test_type_if() {
         let input = "if $true { 1 } else { 2 }";
@@ -354,11 +1140,174 @@ mod tests {
         let input = "(|x: $bool| if x { false } else { true }) ($false)";
         let term = parse_program(input).unwrap();
         let type_map = type_check(&term).unwrap();
-        let vec = convert_hashmap_to_vec(&type_map, &input);
+        let vec = convert_hashmap_to_vec(&term, &type_map, &input);
         println!("{:#?}", type_map);
         println!("{:#?}", vec);
     }
 
+    #[test]
+    fn test_type_recovered() {
+        let input = "let f = |x: int| iszero(x) in let y = f(1 + 2) in y";
+        let (term, errors) = crate::syntax::parse_program_recovering(input);
+        assert!(!errors.is_empty());
+        let (type_map, _) = type_check_recovering(&term).unwrap();
+        println!("{:#?}", type_map);
+        // only the recovering checker gives malformed terms a type
+        for input in [
+            "let x = \u{FFFD} in iszero(x)",
+            "\u{FFFD}(1)",
+            "let x = in iszero(x)",
+        ] {
+            let (term, errors) = crate::syntax::parse_program_recovering(input);
+            assert!(!errors.is_empty());
+            assert_eq!(
+                type_check(&term).unwrap_err().code(),
+                ErrorCode::SyntaxError
+            );
+            assert!(type_check_recovering(&term).is_ok());
+        }
+        let vec = convert_hashmap_to_vec(&term, &type_map, input);
+        println!("{:#?}", vec);
+        assert!(vec
+            .iter()
+            .any(|t| t.s.starts_with("|x: int|") && t.ty.is_some()));
+    }
+
+    #[test]
+    fn test_type_error_message() {
+        let input = "let f = |x: $int| iszero(x) in f(1)";
+        let term = parse_program(input).unwrap();
+        match type_check(&term) {
+            Err(Error::TypeError(error)) => {
+                let TypeError {
+                    message,
+                    expected,
+                    found,
+                    ..
+                } = *error;
+                assert_eq!(message, "expect $int as argument, given int");
+                assert_eq!(expected.as_deref(), Some("$int"));
+                assert_eq!(found.as_deref(), Some("int"));
+            }
+            result => panic!("expect a type error, given {:?}", result),
+        }
+
+        let input = "let f = |x: int| $iszero(x) in if true { f } else { |y: int| iszero(y) }";
+        let term = parse_program(input).unwrap();
+        match type_check(&term) {
+            Err(Error::TypeError(error)) => {
+                let TypeError {
+                    expected, found, ..
+                } = *error;
+                assert_eq!(expected.as_deref(), Some("int->$bool"));
+                assert_eq!(found.as_deref(), Some("int->bool"));
+            }
+            result => panic!("expect a type error, given {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_examples() {
+        let well_typed = [
+            include_str!("../examples/fact.txt"),
+            include_str!("../examples/http.txt"),
+            include_str!("../examples/io.txt"),
+        ];
+        for input in well_typed {
+            let term = parse_program(input).unwrap();
+            assert!(type_check(&term).is_ok());
+        }
+        let ill_typed = [
+            include_str!("../examples/http_error1.txt"),
+            include_str!("../examples/http_error2.txt"),
+            include_str!("../examples/io_error1.txt"),
+            include_str!("../examples/io_error2.txt"),
+            include_str!("../examples/io_error3.txt"),
+        ];
+        for input in ill_typed {
+            let term = parse_program(input).unwrap();
+            assert!(type_check(&term).is_err());
+        }
+    }
+
+    #[test]
+    fn test_consumed_twice() {
+        let input = include_str!("../examples/io_error3.txt");
+        let term = parse_program(input).unwrap();
+        match type_check(&term) {
+            Err(Error::TypeError(error)) => {
+                let TypeError {
+                    message,
+                    start,
+                    labels,
+                    ..
+                } = *error;
+                println!("{} {:#?}", message, labels);
+                assert!(message.starts_with("linear variable h was already consumed"));
+                assert_eq!(&input[start..start + 1], "h");
+                assert_eq!(labels.len(), 2);
+                let first_use = &labels[1];
+                assert!(first_use.start < start);
+                assert_eq!(&input[first_use.start..first_use.end], "h");
+            }
+            result => panic!("expect a type error, given {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_consumed_in_branches() {
+        let input = include_str!("../examples/http_error1.txt");
+        let term = parse_program(input).unwrap();
+        match type_check(&term) {
+            Err(Error::TypeError(error)) => {
+                let TypeError {
+                    message, labels, ..
+                } = *error;
+                println!("{} {:#?}", message, labels);
+                assert!(message.ends_with("request_handler only in the else branch"));
+                assert_eq!(labels.len(), 2);
+            }
+            result => panic!("expect a type error, given {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_shadowed_linear() {
+        // the outer `x` comes back into scope after the inner `let`, and is never consumed
+        let input = "let x = $1 in let y = (let x = 2 in x) in y";
+        let term = parse_program(input).unwrap();
+        assert!(type_check(&term).is_err());
+        let input = "let x = $1 in let y = (let x = 2 in x) in diff(x, y)";
+        let term = parse_program(input).unwrap();
+        assert!(type_check(&term).is_ok());
+        // a parameter that hides a linear variable does not capture it
+        let input = "let h = $1 in let f = |h: int| h in let _ = f(2) in iszero(h)";
+        let term = parse_program(input).unwrap();
+        assert!(type_check(&term).is_ok());
+    }
+
+    #[test]
+    fn test_suggestions() {
+        let inputs = [
+            include_str!("../examples/io_error1.txt"),
+            "let h = $0 in let f = |x: int| diff(h, x) in f(1)",
+            "let f = |x: $int| iszero(x) in f(1)",
+            "let f = |x: int| iszero(x) in f($1)",
+            "let f = |x: $int| 1 in f($1)",
+            "let <a, b> = $<$1, 2> in b",
+        ];
+        for input in inputs {
+            let term = parse_program(input).unwrap();
+            let suggestions = match type_check(&term) {
+                Err(Error::TypeError(error)) => error.suggestions,
+                result => panic!("expect a type error, given {:?}", result),
+            };
+            let fixed = suggestions[0].apply(input);
+            println!("{} => {}", suggestions[0].message, fixed);
+            type_check(&parse_program(&fixed).unwrap()).unwrap();
+        }
+    }
+
     #[test]
     fn test_top_term_linearity() {
         let input = "
@@ -379,4 +1328,153 @@ mod tests {
         let term = parse_program(input).unwrap();
         assert_eq!(type_check(&term).is_err(), true);
     }
+
+    #[test]
+    fn test_derivation() {
+        let term = parse_program("let f = |x: $int| iszero(x) in f($1)").unwrap();
+        let derivation = type_derivation(&term).unwrap();
+        println!("{}", serde_json::to_string_pretty(&derivation).unwrap());
+        assert_eq!(derivation.rule, "T-Let");
+        assert_eq!(derivation.ty, "bool");
+        let rules: Vec<&str> = derivation
+            .premises
+            .iter()
+            .map(|d| d.rule.as_str())
+            .collect();
+        assert_eq!(rules, ["T-Abs", "T-App"]);
+        // the parameter is in the input context of the body and consumed in its output
+        let body = &derivation.premises[0].premises[0];
+        assert_eq!(body.rule, "T-IsZero");
+        assert_eq!(body.input[0].name, "x");
+        assert_eq!(body.input[0].ty, "$int");
+        assert!(body.output.is_empty());
+        let app = &derivation.premises[1];
+        assert_eq!(app.input.len(), 1);
+        assert_eq!(app.input[0].name, "f");
+        // a failing program has no derivation
+        let term = parse_program("let h = $1 in 2").unwrap();
+        assert_eq!(
+            type_derivation(&term).unwrap_err().code(),
+            ErrorCode::LinearNotConsumed
+        );
+    }
+
+    #[test]
+    fn test_live_linear() {
+        let input = "let close = |h: $int| iszero(h) in let h = $0 in close(h)";
+        let term = parse_program(input).unwrap();
+        let (type_map, live) = type_check_live(&term).unwrap();
+        let tips = type_tips(&term, &type_map, Some(&live), input);
+        for tip in &tips {
+            println!("{:?}", tip);
+        }
+        // `h` disappears from the context at the argument of `close`
+        let arg = tips.iter().rev().find(|t| t.s.contains('h')).unwrap();
+        assert_eq!(arg.s, "h");
+        let arg = arg.live.as_ref().unwrap();
+        assert_eq!(arg.before, ["h"]);
+        assert!(arg.after.is_empty());
+        // without `live`, the tips are the same as before
+        let plain = convert_hashmap_to_vec(&term, &type_map, input);
+        assert_eq!(plain.len(), tips.len());
+        assert!(plain.iter().all(|t| t.live.is_none()));
+    }
+
+    #[test]
+    fn test_holes() {
+        let holes = |input: &str| {
+            let term = parse_program(input).unwrap();
+            type_check(&term).unwrap();
+            let holes = find_holes(&term);
+            for hole in &holes {
+                println!("{}", hole.to_error());
+            }
+            holes
+        };
+        let names = |assumptions: &[Assumption]| -> Vec<String> {
+            assumptions.iter().map(|a| a.name.clone()).collect()
+        };
+        let found = holes("let h = $0 in let f = |x: int| x in iszero(?goal)");
+        assert_eq!(found[0].name.as_deref(), Some("goal"));
+        assert_eq!(found[0].expected.as_deref(), Some("int"));
+        assert_eq!(names(&found[0].must_consume), ["h"]);
+        assert_eq!(names(&found[0].may_use), ["f"]);
+        // `h` is consumed later on, so the hole only has to consume `k`
+        let found = holes(
+            "let close = |x: $int| iszero(x) in let h = $0 in let k = $1 in \
+             let _ = ? in close(h)",
+        );
+        assert_eq!(found[0].expected, None);
+        assert_eq!(names(&found[0].must_consume), ["k"]);
+        // the branches agree: the hole takes `h`, like the else branch, and leaves `k`
+        let found = holes(
+            "let close = |x: $int| iszero(x) in let h = $0 in let k = $1 in \
+             let r = if true { ? } else { close(h) } in close(k)",
+        );
+        assert_eq!(names(&found[0].must_consume), ["h"]);
+        let found = holes(
+            "let close = |x: $int| iszero(x) in let h = $0 in let k = $1 in \
+             let r = if true { close(h) } else { ? } in close(k)",
+        );
+        assert_eq!(names(&found[0].must_consume), ["h"]);
+        // an unrestricted function cannot consume `h`
+        let found = holes("let h = $0 in let f = |x: int| ? in diff(h, f(1))");
+        assert!(found[0].must_consume.is_empty());
+        assert_eq!(names(&found[0].may_use), ["x"]);
+        // the argument of a known function
+        let found = holes("let f = |x: <int, $bool>| 1 in f(?)");
+        assert_eq!(found[0].expected.as_deref(), Some("<int, $bool>"));
+        let error = found[0].to_error();
+        assert_eq!(error.code(), ErrorCode::TypedHole);
+        assert!(error.to_string().contains("hole ? of type <int, $bool>"));
+    }
+
+    #[test]
+    fn test_bidirectional() {
+        let ok = [
+            // the parameter type comes from the function the lambda is passed to
+            "let apply = |f: int->int| f(1) in apply(|x| diff(x, 1))",
+            "let f: int->bool = |x| iszero(x) in f(1)",
+            "(|x| x : int->int) (2)",
+            "let g: $($int->int) = $|x| diff(x, 1) in g($1)",
+            "let id = |x: int| x in id (1 : int)",
+        ];
+        for input in ok {
+            println!("{}", input);
+            let term = parse_program(input).unwrap();
+            type_check(&term).unwrap();
+            // annotations survive formatting
+            let formatted = formatter::format_termctx(&term);
+            println!("{}", formatted);
+            type_check(&parse_program(&formatted).unwrap()).unwrap();
+        }
+        let failing = [
+            ("let x: bool = 1 in x", ErrorCode::AnnotationMismatch),
+            (
+                "let f: int->int = |x| iszero(x) in f(1)",
+                ErrorCode::AnnotationMismatch,
+            ),
+            ("(|x| x)(1)", ErrorCode::MissingAnnotation),
+        ];
+        for (input, code) in failing {
+            let error = type_check(&parse_program(input).unwrap()).unwrap_err();
+            println!("{}", error);
+            assert_eq!(error.code(), code);
+        }
+        // only the qualifier is off
+        let input = "let x: $int = 1 in diff(x, 1)";
+        let suggestions = match type_check(&parse_program(input).unwrap()) {
+            Err(Error::TypeError(error)) => error.suggestions,
+            _ => unreachable!(),
+        };
+        let fixed = suggestions[0].apply(input);
+        assert_eq!(fixed, "let x: $int = $1 in diff(x, 1)");
+        let input = "let g: $int->int = $|x| diff(x, 1) in g($1)";
+        let suggestions = match type_check(&parse_program(input).unwrap()) {
+            Err(Error::TypeError(error)) => error.suggestions,
+            _ => unreachable!(),
+        };
+        let fixed = suggestions[0].apply(input);
+        assert_eq!(fixed, "let g: $int->int = |x| diff(x, 1) in g($1)");
+    }
 }
@@ -0,0 +1,627 @@
+use crate::error::{Error, ErrorCode, EvaluateError};
+use crate::eval::{self, Answer, Limits, MachineOutcome};
+use crate::syntax::{ArithOp, Context, NodeId, Qualifier, Term, TermCtx};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// An instruction of the stack machine.
+///
+/// Linear values live in cells. Variables, arguments and pairs share the cell, and `Take`
+/// empties it where the value is consumed, as `eval::Store` does, so that a second use is
+/// caught at run time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Instr {
+    /// Push a literal, in a cell if it is linear.
+    Boolean(Qualifier, bool),
+    Integer(Qualifier, i64),
+    /// Push a local variable of the current function. The parameter is local 0.
+    Local(usize),
+    /// Push a variable captured by the current closure.
+    Captured(usize),
+    /// Pop into a local variable.
+    Store(usize),
+    /// Pop `n` captured values and push a closure of the function `code`: `Closure(q, code, n)`.
+    Closure(Qualifier, usize, usize),
+    /// Pop two components and push a pair.
+    Pair(Qualifier),
+    /// Swap the two values on top of the stack.
+    Swap,
+    /// Consume the value on top of the stack: take a linear value out of its cell, and unroll
+    /// a recursive function.
+    Take,
+    /// Pop a function, then its argument, and call it.
+    Call,
+    /// Pop a function and call it with its own fixed point.
+    Fix,
+    /// Return the value on top of the stack to the caller.
+    Return,
+    /// Pop a boolean and jump if it is false.
+    JumpIfFalse(usize),
+    Jump(usize),
+    /// Pop a pair and push its components.
+    Split,
+    IsZero(Qualifier),
+    /// Pop the subtrahend, then the minuend.
+    Diff(Qualifier),
+    /// Stop with an error, e.g. at a hole.
+    Fail(ErrorCode, String),
+}
+
+/// The code of a function, or of the whole program.
+#[derive(Debug, Clone, Default)]
+pub struct Code {
+    pub instrs: Vec<Instr>,
+    /// The span and node that each instruction was compiled from, for errors.
+    pub nodes: Vec<(Context, NodeId)>,
+    /// The number of local variables, the parameter included.
+    pub locals: usize,
+}
+
+/// A compiled program. `codes[0]` is the program itself, the others are its functions.
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    pub codes: Vec<Code>,
+}
+
+/// The function being compiled.
+#[derive(Default)]
+struct Builder<'a> {
+    code: Code,
+    /// The local variables in scope and their slots, innermost last.
+    scope: Vec<(&'a str, usize)>,
+    captured: Vec<&'a str>,
+}
+
+impl<'a> Builder<'a> {
+    fn emit(&mut self, at: &TermCtx, instr: Instr) -> usize {
+        self.code.instrs.push(instr);
+        self.code.nodes.push((at.0, at.2));
+        self.code.instrs.len() - 1
+    }
+
+    fn bind(&mut self, x: &'a str) -> usize {
+        let slot = self.code.locals;
+        self.code.locals += 1;
+        self.scope.push((x, slot));
+        slot
+    }
+
+    fn lookup(&self, x: &str) -> Option<Instr> {
+        if let Some((_, slot)) = self.scope.iter().rev().find(|(y, _)| *y == x) {
+            return Some(Instr::Local(*slot));
+        }
+        let i = self.captured.iter().position(|y| *y == x)?;
+        Some(Instr::Captured(i))
+    }
+}
+
+/// Compile a program to bytecode.
+pub fn compile(term_ctx: &TermCtx) -> Program {
+    let mut program = Program::default();
+    program.codes.push(Code::default());
+    let mut builder = Builder::default();
+    program.compile(&mut builder, term_ctx);
+    // the value of the program is consumed at the end
+    builder.emit(term_ctx, Instr::Take);
+    builder.emit(term_ctx, Instr::Return);
+    program.codes[0] = builder.code;
+    program
+}
+
+impl Program {
+    /// Compile the function `|x| body` that captures `captured`, and return its index.
+    fn compile_function<'a>(
+        &mut self,
+        x: &'a str,
+        captured: Vec<&'a str>,
+        body: &'a TermCtx,
+    ) -> usize {
+        let index = self.codes.len();
+        self.codes.push(Code::default());
+        let mut builder = Builder {
+            captured,
+            ..Default::default()
+        };
+        builder.bind(x);
+        self.compile(&mut builder, body);
+        builder.emit(body, Instr::Return);
+        self.codes[index] = builder.code;
+        index
+    }
+
+    fn compile<'a>(&mut self, b: &mut Builder<'a>, term_ctx: &'a TermCtx) {
+        let at = term_ctx;
+        match &term_ctx.1 {
+            Term::Variable(x) => {
+                let instr = b.lookup(x).unwrap_or_else(|| {
+                    let message = format!("Variable {} not found", x);
+                    Instr::Fail(ErrorCode::UnboundStoreVariable, message)
+                });
+                b.emit(at, instr);
+            }
+            Term::Boolean(q, v) => {
+                b.emit(at, Instr::Boolean(*q, *v));
+            }
+            Term::Integer(q, v) => {
+                b.emit(at, Instr::Integer(*q, *v));
+            }
+            Term::Abstraction(q, x, _, body) => {
+                // capture the free variables that are in scope; the others fail when reached
+                let free = term_ctx.free_variables();
+                let mut captured = vec![];
+                for y in &free {
+                    if let Some(load) = b.lookup(y) {
+                        b.emit(at, load);
+                        captured.push(y.as_str());
+                    }
+                }
+                let n = captured.len();
+                let code = self.compile_function(x, captured, body);
+                b.emit(at, Instr::Closure(*q, code, n));
+            }
+            Term::Compound(q, t1, t2) => {
+                self.compile(b, t1);
+                self.compile(b, t2);
+                b.emit(at, Instr::Pair(*q));
+            }
+            Term::Application(t1, t2) => {
+                // the function is consumed once the argument is evaluated
+                self.compile(b, t1);
+                self.compile(b, t2);
+                b.emit(at, Instr::Swap);
+                b.emit(at, Instr::Take);
+                b.emit(at, Instr::Call);
+            }
+            Term::Conditional(t1, t2, t3) => {
+                self.compile(b, t1);
+                b.emit(at, Instr::Take);
+                let jump_else = b.emit(at, Instr::JumpIfFalse(0));
+                self.compile(b, t2);
+                let jump_end = b.emit(at, Instr::Jump(0));
+                b.code.instrs[jump_else] = Instr::JumpIfFalse(b.code.instrs.len());
+                self.compile(b, t3);
+                b.code.instrs[jump_end] = Instr::Jump(b.code.instrs.len());
+            }
+            Term::Let(x, t1, t2) => {
+                self.compile(b, t1);
+                let slot = b.bind(x);
+                b.emit(at, Instr::Store(slot));
+                self.compile(b, t2);
+                b.scope.pop();
+            }
+            Term::Letc(x1, x2, t1, t2) => {
+                self.compile(b, t1);
+                b.emit(at, Instr::Take);
+                b.emit(at, Instr::Split);
+                let slot1 = b.bind(x1);
+                let slot2 = b.bind(x2);
+                b.emit(at, Instr::Store(slot2));
+                b.emit(at, Instr::Store(slot1));
+                self.compile(b, t2);
+                b.scope.pop();
+                b.scope.pop();
+            }
+            Term::Fix(t) => {
+                self.compile(b, t);
+                b.emit(at, Instr::Take);
+                b.emit(at, Instr::Fix);
+            }
+            Term::Arith1(q, ArithOp::IsZero, t) => {
+                self.compile(b, t);
+                b.emit(at, Instr::Take);
+                b.emit(at, Instr::IsZero(*q));
+            }
+            Term::Arith2(q, ArithOp::Diff, t1, t2) => {
+                // consume the operands from left to right
+                self.compile(b, t1);
+                self.compile(b, t2);
+                b.emit(at, Instr::Swap);
+                b.emit(at, Instr::Take);
+                b.emit(at, Instr::Swap);
+                b.emit(at, Instr::Take);
+                b.emit(at, Instr::Diff(*q));
+            }
+            Term::Arith1(_, op, _) | Term::Arith2(_, op, ..) => {
+                let message = format!("Unknown op {:?}", op);
+                b.emit(at, Instr::Fail(ErrorCode::StuckTerm, message));
+            }
+            // annotations do not matter at run time
+            Term::Ascription(t, _) => self.compile(b, t),
+            Term::Error(_) => {
+                let message = "Cannot evaluate a malformed term".to_string();
+                b.emit(at, Instr::Fail(ErrorCode::MalformedTerm, message));
+            }
+            Term::Hole(name) => {
+                let name = name.as_deref().unwrap_or_default();
+                let message = format!("Cannot evaluate the hole ?{}", name);
+                b.emit(at, Instr::Fail(ErrorCode::UnfilledHole, message));
+            }
+        }
+    }
+
+    /// Run the program until it returns, or until one of the `limits` is reached. The store
+    /// size is the number of linear values not consumed yet. With `check_linearity` off, `Take`
+    /// leaves linear values in their cells, and using one twice goes unnoticed.
+    pub fn run(&self, limits: &Limits, check_linearity: bool) -> MachineOutcome {
+        let mut vm = Vm {
+            program: self,
+            stack: vec![],
+            frames: vec![],
+            cells: 0,
+            check_linearity,
+            at: (Context { start: 0, end: 0 }, NodeId::default()),
+        };
+        vm.enter(0, None, None);
+        let start = eval::now_millis();
+        let mut steps = 0;
+        loop {
+            if let Some(limit) = limits.reached(steps, vm.cells, start) {
+                return MachineOutcome::OutOfFuel { limit, steps };
+            }
+            match vm.step() {
+                Ok(Some(value)) => {
+                    return match answer(&value) {
+                        Some(answer) => MachineOutcome::Value { answer, steps },
+                        None => {
+                            let message = "Consumed value in the result".to_string();
+                            let error = vm.error(ErrorCode::UnboundStoreVariable, message);
+                            let error = Box::new(error);
+                            MachineOutcome::Stuck { error, steps }
+                        }
+                    }
+                }
+                Ok(None) => {}
+                Err(error) => {
+                    let error = Box::new(error);
+                    return MachineOutcome::Stuck { error, steps };
+                }
+            }
+            steps += 1;
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Value {
+    Boolean(Qualifier, bool),
+    Integer(Qualifier, i64),
+    Closure(Qualifier, Rc<Closure>),
+    Pair(Qualifier, Rc<(Value, Value)>),
+    /// The fixed point of a function, which is unrestricted.
+    Fix(Rc<Closure>),
+    /// A linear value, until it is taken.
+    Cell(Rc<RefCell<Option<Value>>>),
+}
+
+struct Closure {
+    code: usize,
+    captured: Vec<Value>,
+}
+
+struct Frame {
+    code: usize,
+    pc: usize,
+    locals: Vec<Option<Value>>,
+    closure: Option<Rc<Closure>>,
+}
+
+struct Vm<'p> {
+    program: &'p Program,
+    stack: Vec<Value>,
+    frames: Vec<Frame>,
+    /// The number of linear values not taken yet.
+    cells: usize,
+    check_linearity: bool,
+    /// The span and node of the instruction being run, for errors. The `pc` of the frame is
+    /// already past it.
+    at: (Context, NodeId),
+}
+
+fn answer(value: &Value) -> Option<Answer> {
+    let answer = match value {
+        Value::Boolean(q, v) => Answer::Boolean(*q, *v),
+        Value::Integer(q, v) => Answer::Integer(*q, *v),
+        Value::Closure(q, _) => Answer::Function(*q),
+        Value::Fix(_) => Answer::Function(Qualifier::Nop),
+        Value::Pair(q, pair) => {
+            let (a1, a2) = (answer(&pair.0)?, answer(&pair.1)?);
+            Answer::Pair(*q, Box::new(a1), Box::new(a2))
+        }
+        Value::Cell(cell) => answer(cell.borrow().as_ref()?)?,
+    };
+    Some(answer)
+}
+
+impl<'p> Vm<'p> {
+    fn enter(&mut self, code: usize, closure: Option<Rc<Closure>>, arg: Option<Value>) {
+        let mut locals = vec![None; self.program.codes[code].locals];
+        if let Some(arg) = arg {
+            locals[0] = Some(arg);
+        }
+        self.frames.push(Frame {
+            code,
+            pc: 0,
+            locals,
+            closure,
+        });
+    }
+
+    /// An error at the current instruction.
+    fn error(&self, code: ErrorCode, message: String) -> Error {
+        let (span, node) = self.at;
+        Error::from(EvaluateError {
+            code,
+            message,
+            start: span.start,
+            end: span.end,
+            location: None,
+            node,
+            labels: vec![],
+        })
+    }
+
+    fn stuck<T>(&self, message: &str) -> Result<T, Error> {
+        Err(self.error(ErrorCode::StuckTerm, message.to_string()))
+    }
+
+    /// Push a new value, in a cell if it is linear.
+    fn push(&mut self, q: Qualifier, value: Value) {
+        let value = match q {
+            Qualifier::Linear => {
+                self.cells += 1;
+                Value::Cell(Rc::new(RefCell::new(Some(value))))
+            }
+            Qualifier::Nop => value,
+        };
+        self.stack.push(value);
+    }
+
+    fn pop(&mut self) -> Result<Value, Error> {
+        match self.stack.pop() {
+            Some(value) => Ok(value),
+            None => Err(self.error(ErrorCode::Internal, "stack underflow".to_string())),
+        }
+    }
+
+    fn integer(&mut self) -> Result<i64, Error> {
+        match self.pop()? {
+            Value::Integer(_, v) => Ok(v),
+            _ => self.stuck("Expect an Integer"),
+        }
+    }
+
+    /// Run one instruction, and return the value of the program once it returns.
+    fn step(&mut self) -> Result<Option<Value>, Error> {
+        let frame = self.frames.last_mut().unwrap();
+        let code = &self.program.codes[frame.code];
+        let instr = &code.instrs[frame.pc];
+        self.at = code.nodes[frame.pc];
+        // `Take` runs again after unrolling a recursive function
+        if *instr != Instr::Take {
+            frame.pc += 1;
+        }
+        match instr {
+            Instr::Boolean(q, v) => self.push(*q, Value::Boolean(*q, *v)),
+            Instr::Integer(q, v) => self.push(*q, Value::Integer(*q, *v)),
+            Instr::Local(slot) => {
+                let value = self.frames.last().unwrap().locals[*slot].clone();
+                let value = value.ok_or_else(|| {
+                    self.error(ErrorCode::Internal, "uninitialized local".to_string())
+                })?;
+                self.stack.push(value);
+            }
+            Instr::Captured(i) => {
+                let closure = self.frames.last().unwrap().closure.as_ref().unwrap();
+                self.stack.push(closure.captured[*i].clone());
+            }
+            Instr::Store(slot) => {
+                let value = self.pop()?;
+                self.frames.last_mut().unwrap().locals[*slot] = Some(value);
+            }
+            Instr::Closure(q, code, n) => {
+                let captured = self.stack.split_off(self.stack.len() - n);
+                let closure = Closure {
+                    code: *code,
+                    captured,
+                };
+                self.push(*q, Value::Closure(*q, Rc::new(closure)));
+            }
+            Instr::Pair(q) => {
+                let second = self.pop()?;
+                let first = self.pop()?;
+                self.push(*q, Value::Pair(*q, Rc::new((first, second))));
+            }
+            Instr::Swap => {
+                let (b, a) = (self.pop()?, self.pop()?);
+                self.stack.push(b);
+                self.stack.push(a);
+            }
+            Instr::Take => match self.pop()? {
+                Value::Cell(cell) => {
+                    let value = match self.check_linearity {
+                        true => cell.borrow_mut().take(),
+                        false => cell.borrow().clone(),
+                    };
+                    let Some(value) = value else {
+                        let message = "linear value used after it was consumed".to_string();
+                        return Err(self.error(ErrorCode::UnboundStoreVariable, message));
+                    };
+                    if self.check_linearity {
+                        self.cells -= 1;
+                    }
+                    self.stack.push(value);
+                    self.frames.last_mut().unwrap().pc += 1;
+                }
+                Value::Fix(closure) => {
+                    let arg = Value::Fix(closure.clone());
+                    self.enter(closure.code, Some(closure), Some(arg));
+                }
+                value => {
+                    self.stack.push(value);
+                    self.frames.last_mut().unwrap().pc += 1;
+                }
+            },
+            Instr::Call => match self.pop()? {
+                Value::Closure(_, closure) => {
+                    let arg = self.pop()?;
+                    self.enter(closure.code, Some(closure), Some(arg));
+                }
+                _ => return self.stuck("Expect abstraction"),
+            },
+            Instr::Fix => match self.pop()? {
+                Value::Closure(_, closure) => {
+                    let arg = Value::Fix(closure.clone());
+                    self.enter(closure.code, Some(closure), Some(arg));
+                }
+                _ => return self.stuck("Expect abstraction"),
+            },
+            Instr::Return => {
+                let value = self.pop()?;
+                self.frames.pop();
+                if self.frames.is_empty() {
+                    return Ok(Some(value));
+                }
+                self.stack.push(value);
+            }
+            Instr::JumpIfFalse(target) => match self.pop()? {
+                Value::Boolean(_, v) => {
+                    if !v {
+                        self.frames.last_mut().unwrap().pc = *target;
+                    }
+                }
+                _ => return self.stuck("Conditional term must be boolean"),
+            },
+            Instr::Jump(target) => self.frames.last_mut().unwrap().pc = *target,
+            Instr::Split => match self.pop()? {
+                Value::Pair(_, pair) => {
+                    self.stack.push(pair.0.clone());
+                    self.stack.push(pair.1.clone());
+                }
+                _ => return self.stuck("Expect compound"),
+            },
+            Instr::IsZero(q) => {
+                let v = self.integer()?;
+                self.push(*q, Value::Boolean(*q, v == 0));
+            }
+            Instr::Diff(q) => {
+                let v2 = self.integer()?;
+                let v1 = self.integer()?;
+                self.push(*q, Value::Integer(*q, v1 - v2));
+            }
+            Instr::Fail(code, message) => return Err(self.error(*code, message.clone())),
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::syntax::parse_program;
+
+    /// Run a program on the small-step evaluator and the VM, and check that they agree.
+    fn cross_check(input: &str) -> MachineOutcome {
+        let term = parse_program(input).unwrap();
+        let limits = Limits {
+            max_millis: None,
+            ..Default::default()
+        };
+        let expected = eval::run(term.clone(), &limits);
+        let program = compile(&term);
+        println!("{:?}", program);
+        let outcome = program.run(&limits, true);
+        println!("{:?}\n{:?}", expected, outcome);
+        match (&expected, &outcome) {
+            (eval::Outcome::Value { result, .. }, MachineOutcome::Value { answer, .. }) => {
+                assert_eq!(result.answer().as_ref(), Some(answer))
+            }
+            (eval::Outcome::Stuck { error: e1, .. }, MachineOutcome::Stuck { error: e2, .. }) => {
+                assert_eq!(e1.code(), e2.code());
+                let span = |error: &Error| match error {
+                    Error::EvaluateError(error) => &input[error.start..error.end],
+                    _ => "",
+                };
+                assert_eq!(
+                    span(e1),
+                    span(e2),
+                    "the evaluators disagree on where it gets stuck"
+                );
+            }
+            (eval::Outcome::OutOfFuel { .. }, MachineOutcome::OutOfFuel { .. }) => {}
+            _ => panic!("the evaluators disagree on {}", input),
+        }
+        outcome
+    }
+
+    #[test]
+    fn test_examples() {
+        let mut paths: Vec<_> = std::fs::read_dir("examples")
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|e| e == "txt"))
+            .collect();
+        paths.sort();
+        for path in paths {
+            println!("{}", path.display());
+            cross_check(&std::fs::read_to_string(path).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_cross_check() {
+        let inputs = [
+            "(|x| |y| x) (true) (false)",
+            "let f = fix(|ff: bool -> bool| |x: bool| if x {ff(false)} else {ff(true)}) in f(true)",
+            "let negate = |x| $diff($0, x) in negate($5)",
+            "let <a, b> = $<$1, <true, |x: int| x>> in <b, a>",
+            "let x: int = 5 in let f = |y: int| (diff(x, y) : int) in f(2)",
+            "let h = $1 in let _ = iszero(h) in iszero(h)",
+            "let f = $|x: int| x in let _ = f(1) in f(2)",
+            "iszero(?n)",
+            "let x = diff(true, 1) in x",
+            "if 1 { 2 } else { 3 }",
+            "(|x: int| y) (1)",
+        ];
+        for input in inputs {
+            cross_check(input);
+        }
+    }
+
+    #[test]
+    fn test_fact() {
+        let input = std::fs::read_to_string("examples/fact.txt").unwrap();
+        let input = input.replace("fact(3)", "fact(8)");
+        // multiplication is repeated subtraction, so this takes a while
+        let limits = Limits {
+            max_steps: None,
+            max_millis: None,
+            ..Default::default()
+        };
+        let outcome = compile(&parse_program(&input).unwrap()).run(&limits, true);
+        match outcome {
+            MachineOutcome::Value { answer, .. } => {
+                assert_eq!(answer, Answer::Integer(Qualifier::Nop, 40320))
+            }
+            outcome => panic!("expect a value, given {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn test_check_linearity() {
+        let program =
+            compile(&parse_program("let h = $1 in let _ = iszero(h) in iszero(h)").unwrap());
+        match program.run(&Limits::default(), true) {
+            MachineOutcome::Stuck { error, .. } => {
+                assert_eq!(error.code(), ErrorCode::UnboundStoreVariable)
+            }
+            outcome => panic!("expect stuck, given {:?}", outcome),
+        }
+        // without the checks, the second use goes through
+        match program.run(&Limits::default(), false) {
+            MachineOutcome::Value { answer, .. } => {
+                assert_eq!(answer, Answer::Boolean(Qualifier::Nop, false))
+            }
+            outcome => panic!("expect a value, given {:?}", outcome),
+        }
+    }
+}